@@ -6,21 +6,21 @@
 use std::fmt::Write as _;
 use std::mem;
 
-use heck::*;
 use wit_bindgen_core::abi::{Bindgen, Instruction, LiftLower, WasmType};
 use wit_bindgen_core::{dealias, uwrite, uwriteln, wit_parser::*, Source};
 
-use crate::{int_repr, interface, to_mbt_ident, Direction, MbtFlagsRepr};
+use crate::{interface, to_mbt_ident, to_upper_camel_case, MbtFlagsRepr, TypeMode};
 
 pub(super) struct FunctionBindgen<'a, 'b> {
     pub gen: &'b mut interface::InterfaceGenerator<'a>,
     params: Vec<String>,
     pub src: Source,
     blocks: Vec<String>,
-    block_storage: Vec<(Source, Vec<(String, String)>)>,
+    block_storage: Vec<(Source, Vec<String>)>,
     tmp: usize,
     pub needs_cleanup_list: bool,
-    cleanup: Vec<(String, String)>,
+    /// The blocks allocated for the lowered arguments, freed after the call.
+    cleanup: Vec<String>,
     pub import_return_pointer_area_size: usize,
     pub import_return_pointer_area_align: usize,
     pub handle_decls: Vec<String>,
@@ -52,23 +52,19 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
     }
 
     fn emit_cleanup(&mut self) {
-        for (ptr, layout) in mem::take(&mut self.cleanup) {
-            self.push_str(&format!(
-                "if {layout}.size() != 0 {{\nalloc::dealloc({ptr}, {layout});\n}}\n"
-            ));
-        }
-        if self.needs_cleanup_list {
-            self.push_str(
-                "for (ptr, layout) in cleanup_list {\n
-                    if layout.size() != 0 {\n
-                        alloc::dealloc(ptr, layout);\n
-                    }\n
-                }\n",
-            );
+        let cleanup = mem::take(&mut self.cleanup);
+        if !cleanup.is_empty() || self.needs_cleanup_list {
+            let free = self.gen.gen.runtime_func("free");
+            for ptr in cleanup {
+                uwriteln!(self.src, "{free}({ptr})");
+            }
+            if self.needs_cleanup_list {
+                uwriteln!(self.src, "cleanup_list.each(fn(ptr) {{ {free}(ptr) }})");
+            }
         }
         if self.import_return_pointer_area_size > 0 {
-            self.gen.gen.imported_builtins.insert("_rael_free");
-            self.push_str("_rael_free(ret_area)\n");
+            let free = self.gen.gen.runtime_func("free");
+            self.push_str(&format!("{free}(ret_area)\n"));
         }
     }
 
@@ -88,8 +84,10 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
                 self.push_str("let (");
                 for i in 0..n {
                     let arg = format!("result{}_{}", tmp, i);
+                    if i > 0 {
+                        self.push_str(", ");
+                    }
                     self.push_str(&arg);
-                    self.push_str(",");
                     results.push(arg);
                 }
                 self.push_str(") = ");
@@ -97,30 +95,23 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
         }
     }
 
-    fn record_lower(
-        &mut self,
-        id: TypeId,
-        record: &Record,
-        operand: &str,
-        results: &mut Vec<String>,
-    ) {
+    fn record_lower(&mut self, record: &Record, operand: &str, results: &mut Vec<String>) {
         let tmp = self.tmp();
-        self.push_str("let ");
-        let name = self.typename_lower(id);
-        self.push_str(&name);
-        self.push_str("{ ");
-        for field in record.fields.iter() {
+        self.push_str("let { ");
+        for (i, field) in record.fields.iter().enumerate() {
             let name = to_mbt_ident(&field.name);
             let arg = format!("{}{}", name, tmp);
+            if i > 0 {
+                self.push_str(", ");
+            }
             self.push_str(&name);
-            self.push_str(":");
+            self.push_str(": ");
             self.push_str(&arg);
-            self.push_str(", ");
             results.push(arg);
         }
-        self.push_str("} = ");
+        self.push_str(" } = ");
         self.push_str(operand);
-        self.push_str(";\n");
+        self.push_str("\n");
     }
 
     fn record_lift(
@@ -131,7 +122,7 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
         results: &mut Vec<String>,
     ) {
         let mut result = self.typename_lift(id);
-        result.push_str("{\n");
+        result.push_str("::{\n");
         for (field, val) in ty.fields.iter().zip(operands) {
             result.push_str(&to_mbt_ident(&field.name));
             result.push_str(": ");
@@ -147,13 +138,15 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
         self.push_str("let (");
         for i in 0..tuple.types.len() {
             let arg = format!("t{}_{}", tmp, i);
+            if i > 0 {
+                self.push_str(", ");
+            }
             self.push_str(&arg);
-            self.push_str(", ");
             results.push(arg);
         }
         self.push_str(") = ");
         self.push_str(operand);
-        self.push_str(";\n");
+        self.push_str("\n");
     }
 
     fn tuple_lift(&mut self, operands: &[String], results: &mut Vec<String>) {
@@ -186,6 +179,41 @@ impl<'a, 'b> FunctionBindgen<'a, 'b> {
         ret
     }
 
+    fn load(
+        &mut self,
+        builtin: &'static str,
+        offset: i32,
+        operands: &[String],
+        results: &mut Vec<String>,
+    ) {
+        let tmp = self.tmp();
+        self.gen.gen.imported_builtins.insert(builtin);
+        uwrite!(self.src, "let l{tmp} = {builtin}({}", operands[0]);
+        if offset > 0 {
+            uwrite!(self.src, " + {offset}");
+        }
+        uwriteln!(self.src, ")");
+        results.push(format!("l{tmp}"));
+    }
+
+    fn store(&mut self, builtin: &'static str, offset: i32, operands: &[String]) {
+        self.gen.gen.imported_builtins.insert(builtin);
+        uwrite!(self.src, "{builtin}({}", operands[1]);
+        if offset > 0 {
+            uwrite!(self.src, " + {offset}");
+        }
+        uwriteln!(self.src, ", {})", operands[0]);
+    }
+
+    /// Frees the elements of a lifted list, which belong to the callee, unless
+    /// it's empty and wasn't allocated.
+    fn free_list(&mut self, ptr: &str, len: &str) {
+        let free = self.gen.gen.runtime_func("free");
+        uwriteln!(self.src, "if {len} > 0 {{");
+        uwriteln!(self.src, "{free}({ptr})");
+        uwriteln!(self.src, "}}");
+    }
+
     fn lift_lower(&self) -> LiftLower {
         if self.gen.in_import {
             LiftLower::LowerArgsLiftResults
@@ -205,17 +233,11 @@ impl Bindgen for FunctionBindgen<'_, '_> {
     }
 
     fn finish_block(&mut self, operands: &mut Vec<String>) {
-        if !self.cleanup.is_empty() {
+        // The blocks allocated in a block, like for the elements of a list,
+        // are freed with the others after the call.
+        for ptr in mem::take(&mut self.cleanup) {
             self.needs_cleanup_list = true;
-            self.push_str("cleanup_list.extend_from_slice(&[");
-            for (ptr, layout) in mem::take(&mut self.cleanup) {
-                self.push_str("(");
-                self.push_str(&ptr);
-                self.push_str(", ");
-                self.push_str(&layout);
-                self.push_str("),");
-            }
-            self.push_str("]);\n");
+            uwriteln!(self.src, "cleanup_list.push({ptr})");
         }
         let (prev_src, prev_cleanup) = self.block_storage.pop().unwrap();
         let src = mem::replace(&mut self.src, prev_src);
@@ -255,14 +277,10 @@ impl Bindgen for FunctionBindgen<'_, '_> {
         &self.gen.sizes
     }
 
-    fn is_list_canonical(&self, resolve: &Resolve, ty: &Type) -> bool {
-        if !resolve.all_bits_valid(ty) {
-            return false;
-        }
-        match ty {
-            Type::Id(id) => !self.gen.gen.types.get(*id).has_resource,
-            _ => true,
-        }
+    fn is_list_canonical(&self, _resolve: &Resolve, ty: &Type) -> bool {
+        // Only `Bytes` has the layout of the canonical ABI, the elements of
+        // an `Array` are lowered and lifted one by one.
+        matches!(ty, Type::U8)
     }
 
     fn emit(
@@ -274,14 +292,14 @@ impl Bindgen for FunctionBindgen<'_, '_> {
     ) {
         match inst {
             Instruction::GetArg { nth } => results.push(self.params[*nth].clone()),
-            Instruction::I32Const { val } => results.push(format!("{}i32", val)),
+            Instruction::I32Const { val } => results.push(val.to_string()),
             Instruction::ConstZero { tys } => {
                 for ty in tys.iter() {
                     match ty {
-                        WasmType::I32 => results.push("0i32".to_string()),
-                        WasmType::I64 => results.push("0i64".to_string()),
-                        WasmType::F32 => results.push("0.0f32".to_string()),
-                        WasmType::F64 => results.push("0.0f64".to_string()),
+                        WasmType::I32 => results.push("0".to_string()),
+                        WasmType::I64 => results.push("0L".to_string()),
+                        WasmType::F32 => results.push("(0).reinterpret_as_float()".to_string()),
+                        WasmType::F64 => results.push("0.0".to_string()),
                     }
                 }
             }
@@ -300,21 +318,10 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 results.push(s);
             }
 
-            Instruction::F32FromFloat32 => {
-                let s = operands.pop().unwrap();
-                results.push(format!(
-                    "{rt}::as_f32({s})",
-                    rt = self.gen.gen.runtime_path()
-                ));
-            }
-            Instruction::F64FromFloat64 => {
-                let s = operands.pop().unwrap();
-                results.push(format!(
-                    "{rt}::as_f64({s})",
-                    rt = self.gen.gen.runtime_path()
-                ));
-            }
-            Instruction::Float32FromF32
+            Instruction::F32FromFloat32
+            | Instruction::F64FromFloat64
+            | Instruction::CharFromI32
+            | Instruction::Float32FromF32
             | Instruction::Float64FromF64
             | Instruction::S32FromI32
             | Instruction::S64FromI64
@@ -326,114 +333,57 @@ impl Bindgen for FunctionBindgen<'_, '_> {
             | Instruction::U64FromI64 => {
                 results.push(operands.pop().unwrap());
             }
-            Instruction::CharFromI32 => {
-                results.push(format!(
-                    "{}::char_lift({} as u32)",
-                    self.gen.gen.runtime_path(),
-                    operands[0]
-                ));
-            }
 
             Instruction::Bitcasts { casts } => crate::bitcast(casts, operands, results),
 
             Instruction::I32FromBool => {
-                results.push(format!("match {} {{ true => 1, false => 0 }}", operands[0]));
+                results.push(format!("if {} {{ 1 }} else {{ 0 }}", operands[0]));
             }
             Instruction::BoolFromI32 => {
-                results.push(format!(
-                    "{}::bool_lift({} as u8)",
-                    self.gen.gen.runtime_path(),
-                    operands[0]
-                ));
+                results.push(format!("({} != 0)", operands[0]));
             }
 
             Instruction::FlagsLower { flags, .. } => {
-                let tmp = self.tmp();
-                self.push_str(&format!("let flags{} = {};\n", tmp, operands[0]));
-                for i in 0..flags.repr().count() {
-                    results.push(format!("(flags{}.bits() >> {}) as i32", tmp, i * 32));
+                let op = &operands[0];
+                match MbtFlagsRepr::new(flags) {
+                    MbtFlagsRepr::Int => results.push(format!("{op}.bits")),
+                    MbtFlagsRepr::Int64 => {
+                        results.push(format!("{op}.bits.to_int()"));
+                        results.push(format!("({op}.bits >> 32).to_int()"));
+                    }
                 }
             }
             Instruction::FlagsLift { flags, ty, .. } => {
-                let repr = MbtFlagsRepr::new(flags);
                 let name = self.gen.type_path(*ty, true);
-                let mut result = format!("{name}::empty()");
-                for (i, op) in operands.iter().enumerate() {
-                    result.push_str(&format!(
-                        " | {name}::from_bits_retain((({op} as {repr}) << {}) as _)",
-                        i * 32
-                    ));
-                }
-                results.push(result);
-            }
-
-            Instruction::HandleLower {
-                handle: Handle::Own(resource),
-                ..
-            } => {
-                let op = &operands[0];
-                let rt = self.gen.gen.runtime_path();
-                let resource = dealias(self.gen.resolve, *resource);
-                results.push(match self.gen.gen.resources[&resource].direction {
-                    Direction::Import => format!("({op}).into_handle() as i32"),
-                    Direction::Export => format!("{rt}::Resource::into_handle({op}) as i32"),
-                });
+                let bits = match MbtFlagsRepr::new(flags) {
+                    MbtFlagsRepr::Int => operands[0].clone(),
+                    MbtFlagsRepr::Int64 => format!(
+                        "({}.to_int64() & 0xFFFFFFFFL) | ({}.to_int64() << 32)",
+                        operands[0], operands[1]
+                    ),
+                };
+                results.push(format!("{name}::{{ bits: {bits} }}"));
             }
 
-            Instruction::HandleLower {
-                handle: Handle::Borrow(_),
-                ..
-            } => {
+            Instruction::HandleLower { .. } => {
                 let op = &operands[0];
-                results.push(format!("({op}).handle() as i32"))
+                results.push(format!("{op}.handle"));
             }
 
             Instruction::HandleLift { handle, .. } => {
                 let op = &operands[0];
-                let (prefix, resource) = match handle {
-                    Handle::Borrow(resource) => ("&", resource),
-                    Handle::Own(resource) => ("", resource),
+                let resource = match handle {
+                    Handle::Borrow(resource) | Handle::Own(resource) => resource,
                 };
                 let resource = dealias(resolve, *resource);
-
-                results.push(
-                    if let Direction::Export = self.gen.gen.resources[&resource].direction {
-                        match handle {
-                            Handle::Borrow(_) => {
-                                let name = resolve.types[resource]
-                                    .name
-                                    .as_deref()
-                                    .unwrap()
-                                    .to_upper_camel_case();
-                                let rt = self.gen.gen.runtime_path();
-                                format!(
-                                    "{rt}::Resource::<{name}>::lift_borrow({op} as u32 as usize)"
-                                )
-                            }
-                            Handle::Own(_) => {
-                                let name = self.gen.type_path(resource, true);
-                                format!("{name}::from_handle({op} as u32)")
-                            }
-                        }
-                    } else if prefix.is_empty() {
-                        let name = self.gen.type_path(resource, true);
-                        format!("{name}::from_handle({op} as u32)")
-                    } else {
-                        let tmp = format!("handle{}", self.tmp());
-                        self.handle_decls.push(format!("let {tmp};"));
-                        let name = self.gen.type_path(resource, true);
-                        format!(
-                            "{{\n
-                                {tmp} = {name}::from_handle({op} as u32);
-                                {prefix}{tmp}
-                            }}"
-                        )
-                    },
-                );
+                let name = self
+                    .gen
+                    .type_path_with_name(resource, self.gen.type_name(resource));
+                results.push(format!("{name}::{{ handle: {op} }}"));
             }
 
-            Instruction::RecordLower { ty, record, .. } => {
-                self.record_lower(*ty, record, &operands[0], results);
+            Instruction::RecordLower { record, .. } => {
+                self.record_lower(record, &operands[0], results);
             }
             Instruction::RecordLift { ty, record, .. } => {
                 self.record_lift(*ty, record, operands, results);
@@ -459,30 +409,15 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                     .drain(self.blocks.len() - variant.cases.len()..)
                     .collect::<Vec<_>>();
                 let name = self.typename_lower(*ty);
-                let name = if name.contains("::") {
-                    let tmp = self.tmp();
-                    uwriteln!(self.src, "use {name} as V{tmp};");
-                    format!("V{tmp}")
-                } else {
-                    name
-                };
                 self.let_results(result_types.len(), results);
                 let op0 = &operands[0];
-                self.push_str(&format!("match {op0} {{\n"));
+                uwriteln!(self.src, "match {op0} {{");
                 for (case, block) in variant.cases.iter().zip(blocks) {
-                    let case_name = case.name.to_upper_camel_case();
-                    self.push_str(&format!("{name}::{case_name}"));
-                    if case.ty.is_some() {
-                        self.push_str(&format!("(e) => {block},\n"));
-                    } else {
-                        self.push_str(&format!(" => {{\n{block}\n}}\n"));
-                    }
-                }
-                if results.is_empty() {
-                    self.push_str("}\n");
-                } else {
-                    self.push_str("};\n");
+                    let case_name = to_upper_camel_case(&case.name);
+                    let payload = if case.ty.is_some() { "(e)" } else { "" };
+                    uwriteln!(self.src, "{name}::{case_name}{payload} => {block}");
                 }
+                uwriteln!(self.src, "}}");
             }
 
             Instruction::VariantLift { variant, ty, .. } => {
@@ -493,34 +428,20 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let op0 = &operands[0];
                 let tmp = self.tmp();
                 let name = self.typename_lift(*ty);
-                let name = if name.contains("::") {
-                    uwriteln!(self.src, "use {name} as V{tmp};");
-                    format!("V{tmp}")
-                } else {
-                    name
-                };
                 uwriteln!(self.src, "let v{tmp} = match {op0} {{");
                 for (i, (case, block)) in variant.cases.iter().zip(blocks).enumerate() {
-                    if i == variant.cases.len() - 1 {
-                        uwriteln!(
-                            self.src,
-                            "n => {{
-                                debug_assert_eq!(n, {i}, \"invalid enum discriminant\");\
-                            "
-                        );
-                    } else {
-                        uwriteln!(self.src, "{i} => {{");
-                    }
-                    let case_name = case.name.to_upper_camel_case();
+                    uwriteln!(self.src, "{i} => {{");
+                    let case_name = to_upper_camel_case(&case.name);
                     if case.ty.is_none() {
                         uwriteln!(self.src, "{name}::{case_name}");
                     } else {
-                        uwriteln!(self.src, "let e{tmp} = {block};");
+                        uwriteln!(self.src, "let e{tmp} = {block}");
                         uwriteln!(self.src, "{name}::{case_name}(e{tmp})");
                     }
                     uwriteln!(self.src, "}}");
                 }
-                uwriteln!(self.src, "}};");
+                uwriteln!(self.src, "_ => abort(\"invalid enum discriminant\")");
+                uwriteln!(self.src, "}}");
                 results.push(format!("v{tmp}"));
             }
 
@@ -532,12 +453,10 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let none = self.blocks.pop().unwrap();
                 self.let_results(result_types.len(), results);
                 let operand = &operands[0];
-                self.push_str(&format!(
-                    "match {operand} {{
-                        Some(e) => {some},
-                        None => {{\n{none}\n}},
-                    }};"
-                ));
+                uwriteln!(self.src, "match {operand} {{");
+                uwriteln!(self.src, "Some(e) => {some}");
+                uwriteln!(self.src, "None => {none}");
+                uwriteln!(self.src, "}}");
             }
 
             Instruction::OptionLift { .. } => {
@@ -547,14 +466,13 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let operand = &operands[0];
                 results.push(format!(
                     "match {operand} {{
-                        0 => None,
+                        0 => None
                         1 => {{
-                            let e = {some};
+                            let e = {some}
                             Some(e)
                         }}
-                        _ => {rt}::invalid_enum_discriminant(),
+                        _ => abort(\"invalid enum discriminant\")
                     }}",
-                    rt = self.gen.gen.runtime_path(),
                 ));
             }
 
@@ -569,12 +487,10 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let operand = &operands[0];
                 let ok_binding = if result.ok.is_some() { "e" } else { "_" };
                 let err_binding = if result.err.is_some() { "e" } else { "_" };
-                self.push_str(&format!(
-                    "match {operand} {{
-                        Ok({ok_binding}) => {{ {ok} }},
-                        Err({err_binding}) => {{ {err} }},
-                    }};"
-                ));
+                uwriteln!(self.src, "match {operand} {{");
+                uwriteln!(self.src, "Ok({ok_binding}) => {ok}");
+                uwriteln!(self.src, "Err({err_binding}) => {err}");
+                uwriteln!(self.src, "}}");
             }
 
             Instruction::ResultLift { .. } => {
@@ -584,79 +500,70 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 results.push(format!(
                     "match {operand} {{
                         0 => {{
-                            let e = {ok};
+                            let e = {ok}
                             Ok(e)
                         }}
                         1 => {{
-                            let e = {err};
+                            let e = {err}
                             Err(e)
                         }}
-                        _ => {rt}::invalid_enum_discriminant(),
+                        _ => abort(\"invalid enum discriminant\")
                     }}",
-                    rt = self.gen.gen.runtime_path(),
                 ));
             }
 
             Instruction::EnumLower { .. } => {
-                results.push(format!("{}.clone() as i32", operands[0]));
+                results.push(format!("{}._lower()", operands[0]));
             }
 
-            Instruction::EnumLift { enum_, ty, .. } => {
+            Instruction::EnumLift { ty, .. } => {
                 let name = self.gen.type_path(*ty, true);
-                let repr = int_repr(enum_.tag());
                 let op = &operands[0];
-                let result = format!("{name}::_lift({op} as {repr})");
-                results.push(result);
+                results.push(format!("{name}::_lift({op})"));
             }
 
             Instruction::ListCanonLower { realloc, .. } => {
                 let tmp = self.tmp();
-                let val = format!("vec{}", tmp);
-                let ptr = format!("ptr{}", tmp);
-                let len = format!("len{}", tmp);
+                let vec = format!("vec{tmp}");
+                let ptr = format!("ptr{tmp}");
+                let len = format!("len{tmp}");
+                self.gen.gen.imported_builtins.insert("_rael_bytes_data");
+                uwriteln!(self.src, "let {vec} = {}", operands[0]);
+                uwriteln!(self.src, "let {len} = {vec}.length()");
                 if realloc.is_none() {
-                    self.push_str(&format!("let {} = {};\n", val, operands[0]));
+                    // The bytes outlive the call that borrows them.
+                    uwriteln!(self.src, "let {ptr} = _rael_bytes_data({vec})");
                 } else {
-                    let op0 = operands.pop().unwrap();
-                    self.push_str(&format!("let {} = ({}).into_boxed_slice();\n", val, op0));
-                }
-                self.push_str(&format!("let {} = {}.as_ptr() as i32;\n", ptr, val));
-                self.push_str(&format!("let {} = {}.len() as i32;\n", len, val));
-                if realloc.is_some() {
-                    self.push_str(&format!("::core::mem::forget({});\n", val));
-                }
-                results.push(ptr);
-                results.push(len);
-            }
-
-            Instruction::ListCanonLift { element, .. } => match element {
-                Type::Bool => todo!(),
-                Type::U8 => {
-                    let bytes = format!("bytes{}", self.tmp());
-                    uwriteln!(self.src, "let {bytes} = Bytes::make({}, 0)", operands[1],);
-                    self.gen.gen.imported_builtins.insert("_rael_bytes_data");
+                    // The caller takes a copy to free it.
+                    let malloc = self.gen.gen.runtime_func("malloc_aligned");
                     self.gen.gen.imported_builtins.insert("_rael_memory_copy");
+                    uwriteln!(self.src, "let {ptr} = {malloc}({len}, 1)");
                     uwriteln!(
                         self.src,
-                        "_rael_memory_copy(_rael_bytes_data({bytes}), {}, {})",
-                        operands[0],
-                        operands[1],
+                        "_rael_memory_copy({ptr}, _rael_bytes_data({vec}), {len})"
                     );
-                    results.push(bytes);
                 }
-                Type::U16 => todo!(),
-                Type::U32 => todo!(),
-                Type::U64 => todo!(),
-                Type::S8 => todo!(),
-                Type::S16 => todo!(),
-                Type::S32 => todo!(),
-                Type::S64 => todo!(),
-                Type::Float32 => todo!(),
-                Type::Float64 => todo!(),
-                Type::Char => todo!(),
-                Type::String => todo!(),
-                Type::Id(_) => todo!(),
-            },
+                results.push(ptr);
+                results.push(len);
+            }
+
+            Instruction::ListCanonLift { .. } => {
+                let tmp = self.tmp();
+                let ptr = format!("ptr{tmp}");
+                let len = format!("len{tmp}");
+                let bytes = format!("bytes{tmp}");
+                uwriteln!(self.src, "let {ptr} = {}", operands[0]);
+                uwriteln!(self.src, "let {len} = {}", operands[1]);
+                uwriteln!(self.src, "let {bytes} = Bytes::make({len}, 0)");
+                self.gen.gen.imported_builtins.insert("_rael_bytes_data");
+                self.gen.gen.imported_builtins.insert("_rael_memory_copy");
+                uwriteln!(
+                    self.src,
+                    "_rael_memory_copy(_rael_bytes_data({bytes}), {ptr}, {len})"
+                );
+                self.free_list(&ptr, &len);
+                results.push(bytes);
+            }
 
             Instruction::StringLower { realloc } => {
                 if realloc.is_none() {
@@ -695,39 +602,26 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let tmp = self.tmp();
                 let vec = format!("vec{tmp}");
                 let result = format!("result{tmp}");
-                let layout = format!("layout{tmp}");
                 let len = format!("len{tmp}");
-                self.push_str(&format!(
-                    "let {vec} = {operand0};\n",
-                    operand0 = operands[0]
-                ));
-                self.push_str(&format!("let {len} = {vec}.len() as i32;\n"));
                 let size = self.gen.sizes.size(element);
                 let align = self.gen.sizes.align(element);
-                self.push_str(&format!(
-                    "let {layout} = alloc::Layout::from_size_align_unchecked({vec}.len() * {size}, {align});\n",
-                ));
-                self.push_str(&format!(
-                    "let {result} = if {layout}.size() != 0\n{{\nlet ptr = alloc::alloc({layout});\n",
-                ));
-                self.push_str(&format!(
-                    "if ptr.is_null()\n{{\nalloc::handle_alloc_error({layout});\n}}\nptr\n}}",
-                ));
-                self.push_str("else {{\n::core::ptr::null_mut()\n}};\n");
-                self.push_str(&format!("for (i, e) in {vec}.into_iter().enumerate() {{\n",));
-                self.push_str(&format!(
-                    "let base = {result} as i32 + (i as i32) * {size};\n",
-                ));
-                self.push_str(&body);
-                self.push_str("\n}\n");
-                results.push(format!("{result} as i32"));
+                let malloc = self.gen.gen.runtime_func("malloc_aligned");
+                uwriteln!(self.src, "let {vec} = {}", operands[0]);
+                uwriteln!(self.src, "let {len} = {vec}.length()");
+                uwriteln!(self.src, "let {result} = {malloc}({len} * {size}, {align})");
+                uwriteln!(self.src, "for i = 0; i < {len}; i = i + 1 {{");
+                uwriteln!(self.src, "let e = {vec}[i]");
+                uwriteln!(self.src, "let base = {result} + i * {size}");
+                uwriteln!(self.src, "{}", unwrap_block(&body));
+                uwriteln!(self.src, "}}");
+                results.push(result.clone());
                 results.push(len);
 
                 if realloc.is_none() {
                     // If an allocator isn't requested then we must clean up the
                     // allocation ourselves since our callee isn't taking
                     // ownership.
-                    self.cleanup.push((result, layout));
+                    self.cleanup.push(result);
                 }
             }
 
@@ -735,32 +629,20 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let body = self.blocks.pop().unwrap();
                 let tmp = self.tmp();
                 let size = self.gen.sizes.size(element);
-                let align = self.gen.sizes.align(element);
                 let len = format!("len{tmp}");
                 let base = format!("base{tmp}");
                 let result = format!("result{tmp}");
-                self.push_str(&format!(
-                    "let {base} = {operand0};\n",
-                    operand0 = operands[0]
-                ));
-                self.push_str(&format!(
-                    "let {len} = {operand1};\n",
-                    operand1 = operands[1]
-                ));
-                self.push_str(&format!(
-                    "let mut {result} = Vec::with_capacity({len} as usize);\n",
-                ));
-
-                uwriteln!(self.src, "for i in 0..{len} {{");
-                uwriteln!(self.src, "let base = {base} + i * {size};");
-                uwriteln!(self.src, "let e{tmp} = {body};");
-                uwriteln!(self.src, "{result}.push(e{tmp});");
+                let ty = self.gen.type_string(element, TypeMode::Owned);
+                uwriteln!(self.src, "let {base} = {}", operands[0]);
+                uwriteln!(self.src, "let {len} = {}", operands[1]);
+                uwriteln!(self.src, "let {result}: Array[{ty}] = []");
+                uwriteln!(self.src, "for i = 0; i < {len}; i = i + 1 {{");
+                uwriteln!(self.src, "let base = {base} + i * {size}");
+                uwriteln!(self.src, "let e{tmp} = {body}");
+                uwriteln!(self.src, "{result}.push(e{tmp})");
                 uwriteln!(self.src, "}}");
+                self.free_list(&base, &len);
                 results.push(result);
-                self.push_str(&format!(
-                    "{rt}::dealloc({base}, ({len} as usize) * {size}, {align});\n",
-                    rt = self.gen.gen.runtime_path(),
-                ));
             }
 
             Instruction::IterElem { .. } => results.push("e".to_string()),
@@ -791,26 +673,22 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                             to_mbt_ident(&func.name)
                         ));
                     }
-                    FunctionKind::Method(ty) | FunctionKind::Static(ty) => {
+                    // The functions of resources are closures of `init_guest()`.
+                    FunctionKind::Method(_) | FunctionKind::Static(_) => {
                         self.push_str(&format!(
-                            "<_{0}Impl as Guest{0}>::{1}",
-                            resolve.types[*ty]
-                                .name
-                                .as_deref()
-                                .unwrap()
-                                .to_upper_camel_case(),
+                            "(guest_impl.{}.unwrap().{})",
+                            self.export_trait_field.as_deref().unwrap(),
                             to_mbt_ident(func.item_name())
                         ));
                     }
+                    // The constructor returns the rep of the new value, which
+                    // gets a handle.
                     FunctionKind::Constructor(ty) => {
                         self.gen.mark_resource_owned(*ty);
                         self.push_str(&format!(
-                            "Own{0}::new(<_{0}Impl as Guest{0}>::new",
-                            resolve.types[*ty]
-                                .name
-                                .as_deref()
-                                .unwrap()
-                                .to_upper_camel_case()
+                            "{}::new((guest_impl.{}.unwrap().new)",
+                            self.gen.type_name(*ty),
+                            self.export_trait_field.as_deref().unwrap(),
                         ));
                     }
                 }
@@ -820,10 +698,19 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 if let FunctionKind::Constructor(_) = &func.kind {
                     self.push_str(")");
                 }
-                self.push_str(";\n");
+                self.push_str("\n");
             }
 
             Instruction::Return { amt, .. } => {
+                // The results may still be read from the return area, so they
+                // are lifted before it's freed.
+                if self.import_return_pointer_area_size > 0 {
+                    for operand in operands.iter_mut() {
+                        let tmp = self.tmp();
+                        uwriteln!(self.src, "let ret{tmp} = {operand}");
+                        *operand = format!("ret{tmp}");
+                    }
+                }
                 self.emit_cleanup();
                 match amt {
                     0 => {}
@@ -840,133 +727,46 @@ impl Bindgen for FunctionBindgen<'_, '_> {
             }
 
             Instruction::I32Load { offset } => {
-                let tmp = self.tmp();
-                self.gen.gen.imported_builtins.insert("_rael_load_i32");
-                uwrite!(self.src, "let l{tmp} = _rael_load_i32({}", operands[0]);
-                if *offset > 0 {
-                    uwrite!(self.src, " + {offset}");
-                }
-                uwriteln!(self.src, ")");
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_i32", *offset, operands, results)
             }
             Instruction::I32Load8U { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = i32::from(*(({} + {offset}) as *const u8));",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_u8", *offset, operands, results)
             }
             Instruction::I32Load8S { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = i32::from(*(({} + {offset}) as *const i8));",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_i8", *offset, operands, results)
             }
             Instruction::I32Load16U { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = i32::from(*(({} + {offset}) as *const u16));",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_u16", *offset, operands, results)
             }
             Instruction::I32Load16S { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = i32::from(*(({} + {offset}) as *const i16));",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_i16", *offset, operands, results)
             }
             Instruction::I64Load { offset } => {
-                let tmp = self.tmp();
-                self.gen.gen.imported_builtins.insert("_rael_load_i64");
-                uwrite!(self.src, "let l{tmp} = _rael_load_i64({}", operands[0]);
-                if *offset > 0 {
-                    uwrite!(self.src, " + {offset}");
-                }
-                uwriteln!(self.src, ")");
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_i64", *offset, operands, results)
             }
             Instruction::F32Load { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = *(({} + {offset}) as *const f32);",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
+                self.load("_rael_load_f32", *offset, operands, results)
             }
             Instruction::F64Load { offset } => {
-                let tmp = self.tmp();
-                uwriteln!(
-                    self.src,
-                    "let l{tmp} = *(({} + {offset}) as *const f64);",
-                    operands[0]
-                );
-                results.push(format!("l{tmp}"));
-            }
-            Instruction::I32Store { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut i32) = {};\n",
-                    operands[1], offset, operands[0]
-                ));
-            }
-            Instruction::I32Store8 { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut u8) = ({}) as u8;\n",
-                    operands[1], offset, operands[0]
-                ));
-            }
-            Instruction::I32Store16 { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut u16) = ({}) as u16;\n",
-                    operands[1], offset, operands[0]
-                ));
-            }
-            Instruction::I64Store { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut i64) = {};\n",
-                    operands[1], offset, operands[0]
-                ));
-            }
-            Instruction::F32Store { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut f32) = {};\n",
-                    operands[1], offset, operands[0]
-                ));
-            }
-            Instruction::F64Store { offset } => {
-                self.push_str(&format!(
-                    "*(({} + {}) as *mut f64) = {};\n",
-                    operands[1], offset, operands[0]
-                ));
+                self.load("_rael_load_f64", *offset, operands, results)
             }
+            Instruction::I32Store { offset } => self.store("_rael_store_i32", *offset, operands),
+            Instruction::I32Store8 { offset } => self.store("_rael_store_i8", *offset, operands),
+            Instruction::I32Store16 { offset } => self.store("_rael_store_i16", *offset, operands),
+            Instruction::I64Store { offset } => self.store("_rael_store_i64", *offset, operands),
+            Instruction::F32Store { offset } => self.store("_rael_store_f32", *offset, operands),
+            Instruction::F64Store { offset } => self.store("_rael_store_f64", *offset, operands),
 
             Instruction::Malloc { .. } => unimplemented!(),
 
-            Instruction::GuestDeallocate { size, align } => {
-                self.push_str(&format!(
-                    "{rt}::dealloc({op}, {size}, {align});\n",
-                    rt = self.gen.gen.runtime_path(),
-                    op = operands[0]
-                ));
+            Instruction::GuestDeallocate { .. } => {
+                let free = self.gen.gen.runtime_func("free");
+                self.push_str(&format!("{free}({})\n", operands[0]));
             }
 
             Instruction::GuestDeallocateString => {
-                self.push_str(&format!(
-                    "{rt}::dealloc({op0}, ({op1}) as usize, 1);\n",
-                    rt = self.gen.gen.runtime_path(),
-                    op0 = operands[0],
-                    op1 = operands[1],
-                ));
+                let free = self.gen.gen.runtime_func("free");
+                self.push_str(&format!("{free}({})\n", operands[0]));
             }
 
             Instruction::GuestDeallocateVariant { blocks } => {
@@ -992,35 +792,29 @@ impl Bindgen for FunctionBindgen<'_, '_> {
                 let body = self.blocks.pop().unwrap();
                 let tmp = self.tmp();
                 let size = self.gen.sizes.size(element);
-                let align = self.gen.sizes.align(element);
                 let len = format!("len{tmp}");
                 let base = format!("base{tmp}");
-                self.push_str(&format!(
-                    "let {base} = {operand0};\n",
-                    operand0 = operands[0]
-                ));
-                self.push_str(&format!(
-                    "let {len} = {operand1};\n",
-                    operand1 = operands[1]
-                ));
-
+                uwriteln!(self.src, "let {base} = {}", operands[0]);
+                uwriteln!(self.src, "let {len} = {}", operands[1]);
                 if body != "()" {
-                    self.push_str("for i in 0..");
-                    self.push_str(&len);
-                    self.push_str(" {\n");
-                    self.push_str("let base = ");
-                    self.push_str(&base);
-                    self.push_str(" + i *");
-                    self.push_str(&size.to_string());
-                    self.push_str(";\n");
-                    self.push_str(&body);
-                    self.push_str("\n}\n");
+                    uwriteln!(self.src, "for i = 0; i < {len}; i = i + 1 {{");
+                    uwriteln!(self.src, "let base = {base} + i * {size}");
+                    uwriteln!(self.src, "{}", unwrap_block(&body));
+                    uwriteln!(self.src, "}}");
                 }
-                self.push_str(&format!(
-                    "{rt}::dealloc({base}, ({len} as usize) * {size}, {align});\n",
-                    rt = self.gen.gen.runtime_path(),
-                ));
+                let free = self.gen.gen.runtime_func("free");
+                uwriteln!(self.src, "{free}({base})");
             }
         }
     }
 }
+
+/// Returns the statements of a block emitted as a statement, like the body of
+/// a loop, without its braces.
+fn unwrap_block(block: &str) -> &str {
+    block
+        .strip_prefix('{')
+        .and_then(|block| block.strip_suffix('}'))
+        .unwrap_or(block)
+        .trim()
+}
@@ -0,0 +1,198 @@
+//! A formatter for the generated MoonBit code, following the style of
+//! `moon fmt` for the constructs the generators emit.
+//!
+//! The generators indent with `Source`, so this only has to clean up the
+//! blank lines and trailing spaces left behind by templates and by items
+//! that turned out empty, and to space the type annotations and drop the
+//! semicolons the way `moon fmt` does.
+
+/// Returns `src` formatted:
+///
+/// - trailing whitespace removed, runs of blank lines collapsed into one,
+///   blank lines dropped at the start and end of the file and of every
+///   block, and a single newline at the end;
+/// - type annotations of parameters, type parameters, fields and `let`s
+///   spaced as `name : Type`, leaving the fields of struct literals as is;
+/// - semicolons at the end of lines dropped.
+pub fn format(src: &str) -> String {
+    let mut out = String::with_capacity(src.len());
+    let mut pending_blank = false;
+    // The depth of the braces, with the depth of the innermost `struct`
+    // whose fields are being declared.
+    let mut depth = 0usize;
+    let mut fields = None;
+    for line in src.lines() {
+        let line = line.trim_end();
+        if line.is_empty() {
+            pending_blank = !out.is_empty() && !opens_block(&out);
+            continue;
+        }
+        if pending_blank && !closes_block(line) {
+            out.push('\n');
+        }
+        pending_blank = false;
+
+        let line = line.strip_suffix(';').unwrap_or(line).trim_end();
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let code = line.trim_start();
+        let item = strip_visibility(code);
+        let formatted = if item.starts_with("fn ") {
+            space_annotations(code, usize::MAX)
+        } else if item.starts_with("let ") {
+            // Only the pattern, not the value, can have an annotation.
+            match code.split_once(" = ") {
+                Some((pattern, value)) => format!("{} = {value}", space_annotations(pattern, 1)),
+                None => space_annotations(code, 1),
+            }
+        } else if fields == Some(depth) {
+            space_annotations(code, 1)
+        } else {
+            code.to_string()
+        };
+        out.push_str(indent);
+        out.push_str(&formatted);
+        out.push('\n');
+
+        let (opened, closed) = braces(code);
+        depth = (depth + opened).saturating_sub(closed);
+        if fields.is_some_and(|fields| depth < fields) {
+            fields = None;
+        }
+        if opened > closed && (item.starts_with("struct ") || item.starts_with("enum ")) {
+            fields = Some(depth);
+        }
+    }
+    out
+}
+
+fn opens_block(out: &str) -> bool {
+    out.trim_end().ends_with(['{', '(', '['])
+}
+
+fn closes_block(line: &str) -> bool {
+    line.trim_start().starts_with(['}', ')', ']'])
+}
+
+/// Returns `code` without its `pub`, `pub(readonly)` or `priv` modifier.
+fn strip_visibility(code: &str) -> &str {
+    for modifier in ["pub(readonly) ", "pub(all) ", "pub ", "priv "] {
+        if let Some(code) = code.strip_prefix(modifier) {
+            return code;
+        }
+    }
+    code
+}
+
+/// Returns `code` with at most `limit` of its annotations `name: Type`
+/// spaced as `name : Type`, outside of string literals and paths.
+fn space_annotations(code: &str, limit: usize) -> String {
+    let mut out = String::with_capacity(code.len() + 8);
+    let mut chars = code.chars().peekable();
+    let mut in_string = false;
+    let mut spaced = 0;
+    let mut prev = ' ';
+    while let Some(c) = chars.next() {
+        if in_string {
+            out.push(c);
+            match c {
+                '\\' => out.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+        } else if c == '"' {
+            in_string = true;
+            out.push(c);
+        } else if c == ':'
+            && spaced < limit
+            && (prev.is_alphanumeric() || prev == '_')
+            && chars.peek() == Some(&' ')
+        {
+            spaced += 1;
+            out.push_str(" :");
+        } else {
+            out.push(c);
+        }
+        prev = c;
+    }
+    out
+}
+
+/// Counts the opening and closing braces of `code`, outside of strings.
+fn braces(code: &str) -> (usize, usize) {
+    let (mut opened, mut closed) = (0, 0);
+    let mut chars = code.chars();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if in_string => {
+                chars.next();
+            }
+            '"' => in_string = !in_string,
+            '{' if !in_string => opened += 1,
+            '}' if !in_string => closed += 1,
+            _ => {}
+        }
+    }
+    (opened, closed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn collapses_blank_lines() {
+        let src = "\n\nfn a() -> Unit {  \n\n  b()\n\n\n  c()\n\n}\n\n\n\nfn d() -> Unit {\n}\n\n";
+        let expected = "fn a() -> Unit {\n  b()\n\n  c()\n}\n\nfn d() -> Unit {\n}\n";
+        assert_eq!(format(src), expected);
+    }
+
+    #[test]
+    fn spaces_signatures() {
+        let src = "pub fn init_guest[T: Guest](guest: T) -> Unit {\n\
+                   fn Guest::hello(self: Impl, name: String) -> Unit {\n\
+                   fn ffi_log(p0: Int, p1: Int) = \"$root\" \"log: x\"\n";
+        let expected = "pub fn init_guest[T : Guest](guest : T) -> Unit {\n\
+                        fn Guest::hello(self : Impl, name : String) -> Unit {\n\
+                        fn ffi_log(p0 : Int, p1 : Int) = \"$root\" \"log: x\"\n";
+        assert_eq!(format(src), expected);
+    }
+
+    #[test]
+    fn spaces_fields_but_not_literals() {
+        let src = "pub(readonly) struct Fantix {\n  examples: FantixExamples\n  mut t0: Option[Guest]\n}\n\
+                   \n\
+                   pub let fantix: Fantix = { examples: { stdio: Stdio(()) } }\n\
+                   \n\
+                   fn f() -> Point {\n  let p: Point = { x: 1, y: 2 }\n  let q = { x: 1, y: 2 }\n  Point::{ x: p.x, y: 0 }\n}\n";
+        let expected = "pub(readonly) struct Fantix {\n  examples : FantixExamples\n  mut t0 : Option[Guest]\n}\n\
+                        \n\
+                        pub let fantix : Fantix = { examples: { stdio: Stdio(()) } }\n\
+                        \n\
+                        fn f() -> Point {\n  let p : Point = { x: 1, y: 2 }\n  let q = { x: 1, y: 2 }\n  Point::{ x: p.x, y: 0 }\n}\n";
+        assert_eq!(format(src), expected);
+    }
+
+    #[test]
+    fn keeps_enum_cases_and_labelled_arguments() {
+        let src = "enum E {\n  A(Int)\n  B\n}\n\nfn g() -> Unit {\n  h(x: 1)\n}\n";
+        let expected = "enum E {\n  A(Int)\n  B\n}\n\nfn g() -> Unit {\n  h(x: 1)\n}\n";
+        assert_eq!(format(src), expected);
+    }
+
+    #[test]
+    fn drops_semicolons() {
+        let src = "pub trait Guest {\n  hello(Self, String) -> Unit;\n}\n\
+                   fn a() -> Unit {\n  b(\"x;\");\n  match c {\n    _ => ()\n  };\n}\n";
+        let expected = "pub trait Guest {\n  hello(Self, String) -> Unit\n}\n\
+                        fn a() -> Unit {\n  b(\"x;\")\n  match c {\n    _ => ()\n  }\n}\n";
+        assert_eq!(format(src), expected);
+    }
+
+    #[test]
+    fn keeps_strings_and_paths() {
+        let src = "fn a() -> Unit {\n  let s = \"a: \\\"b: c\\\"\"\n  T::f(x)\n}\n";
+        let expected = "fn a() -> Unit {\n  let s = \"a: \\\"b: c\\\"\"\n  T::f(x)\n}\n";
+        assert_eq!(format(src), expected);
+    }
+}
@@ -14,8 +14,8 @@ use wit_bindgen_core::{dealias, uwrite, uwriteln, wit_parser::*, Source, TypeInf
 
 use crate::bindgen::FunctionBindgen;
 use crate::{
-    int_repr, to_mbt_ident, to_upper_camel_case, wasm_type, Direction, ExportKey, FnSig,
-    Identifier, InterfaceName, MbtFlagsRepr, ModuleName, MoonBit, Ownership, TypeMode,
+    to_mbt_ident, to_upper_camel_case, wasm_type, Direction, ExportKey, ExportedFunction, FnSig,
+    Identifier, MbtFlagsRepr, ModuleName, MoonBit, Ownership, TypeMode,
 };
 
 pub struct InterfaceGenerator<'a> {
@@ -75,7 +75,7 @@ impl InterfaceGenerator<'_> {
         };
         match item {
             Some(item) => match base {
-                ExportKey::World => unimplemented!("item projected from world interface"),
+                ExportKey::World => ExportKey::Name(item.to_string()),
                 ExportKey::Name(name) => ExportKey::Name(format!("{name}/{item}")),
             },
             None => base,
@@ -94,39 +94,51 @@ impl InterfaceGenerator<'_> {
                 continue;
             }
 
-            let (trait_name, export_key) = match func.kind {
+            let (trait_name, export_key, docs) = match func.kind {
                 FunctionKind::Freestanding => (
                     module_name
                         .map(|n| n.qual.as_str())
                         .unwrap_or("Guest")
                         .into(),
                     self.export_key(None),
+                    match self.identifier {
+                        Identifier::Interface(id, _) => &self.resolve.interfaces[id].docs,
+                        Identifier::World(id) => &self.resolve.worlds[id].docs,
+                    },
                 ),
                 FunctionKind::Method(id)
                 | FunctionKind::Constructor(id)
                 | FunctionKind::Static(id) => {
                     let resource_name = self.resolve.types[id].name.as_deref().unwrap();
-                    let camel = resource_name.to_upper_camel_case();
-                    let trait_name = format!("Guest{camel}");
+                    let trait_name = format!("Guest{}", self.type_name(id));
                     let export_key = self.export_key(Some(resource_name));
-                    (trait_name, export_key)
+                    (trait_name, export_key, &self.resolve.types[id].docs)
                 }
             };
 
             // First generate the exported function which performs lift/lower
             // operations and delegates to a trait (that doesn't exist just yet).
-            let num_traits = self.gen.export_traits.len();
-            let trait_field = self
-                .gen
-                .export_traits
-                .entry(trait_name.clone())
-                .or_insert_with(|| format!("t{num_traits}"))
-                .clone();
-            self.generate_guest_export(func, trait_field);
+            let export_traits = &mut self.gen.export_traits;
+            let trait_field = match export_traits.iter().position(|(t, _)| *t == trait_name) {
+                Some(i) => export_traits[i].1.clone(),
+                None => {
+                    let field = format!("t{}", export_traits.len());
+                    export_traits.push((trait_name.clone(), field.clone()));
+                    field
+                }
+            };
+            self.generate_guest_export(func, trait_field.clone());
 
             // Next generate a trait signature for this method and insert it
             // into `traits`. Note that `traits` will have a trait-per-resource.
-            let (_, methods) = traits.entry(export_key).or_insert((trait_name, Vec::new()));
+            // Constructors and static functions don't take `self`, they are
+            // called on the type of the implementation.
+            let (_, _, methods, funcs) = traits.entry(export_key).or_insert((
+                trait_name.clone(),
+                docs,
+                Vec::new(),
+                Vec::new(),
+            ));
             let prev = mem::take(&mut self.src);
             let mut sig = FnSig {
                 use_item_name: true,
@@ -134,21 +146,48 @@ impl InterfaceGenerator<'_> {
                 is_trait: true,
                 ..Default::default()
             };
-            match &func.kind {
-                FunctionKind::Freestanding => {
-                    sig.self_arg = Some("Self".into());
-                }
-                FunctionKind::Method(_) => {
-                    sig.self_arg = Some("&self".into());
-                    sig.self_is_first_param = true;
-                }
-                FunctionKind::Static(_) => {}
-                FunctionKind::Constructor(_) => {}
+            if let FunctionKind::Method(_) = &func.kind {
+                sig.self_arg = Some("Self".into());
+                sig.self_is_first_param = true;
+            }
+            if let FunctionKind::Freestanding = &func.kind {
+                sig.self_arg = Some("Self".into());
             }
             self.print_signature(func, TypeMode::Owned, &sig);
             self.src.push_str(";\n");
             let trait_method = mem::replace(&mut self.src, prev);
             methods.push(trait_method);
+
+            // The implementation of a resource is kept in a table by its rep,
+            // so its functions are bound to it by closures in `init_guest()`.
+            if let FunctionKind::Freestanding = &func.kind {
+                continue;
+            }
+            let prev = mem::take(&mut self.src);
+            let name = match &func.kind {
+                FunctionKind::Constructor(_) => "new",
+                _ => func.item_name(),
+            };
+            uwrite!(self.src, "{}: (", to_mbt_ident(name));
+            for (i, (_, param)) in func.params.iter().enumerate() {
+                if i > 0 {
+                    self.push_str(", ");
+                }
+                self.print_ty(param, TypeMode::Owned);
+            }
+            self.push_str(")");
+            if let FunctionKind::Constructor(_) = &func.kind {
+                self.push_str(" -> Int");
+            } else {
+                self.print_results(&func.results, TypeMode::Owned);
+            }
+            let field = mem::replace(&mut self.src, prev);
+            funcs.push(String::from(field));
+            self.gen
+                .export_resources
+                .entry(trait_name)
+                .or_default()
+                .push(resource_closure(func, &trait_field));
         }
 
         // Once all the traits have been assembled then they can be emitted.
@@ -156,13 +195,22 @@ impl InterfaceGenerator<'_> {
         // Additionally alias the user-configured item for each trait here as
         // there's only one implementation of this trait and it must be
         // pre-configured.
-        for (trait_name, methods) in traits.values() {
+        for (trait_name, docs, methods, funcs) in traits.values() {
+            self.rustdoc(docs);
             uwriteln!(self.src, "pub trait {trait_name} {{");
             for method in methods {
                 self.src.push_str(method);
             }
             uwriteln!(self.src, "}}");
             uwriteln!(self.src, "");
+            if !funcs.is_empty() {
+                uwriteln!(self.src, "priv struct {trait_name}Funcs {{");
+                for func in funcs {
+                    uwriteln!(self.src, "{func}");
+                }
+                uwriteln!(self.src, "}}");
+                uwriteln!(self.src, "");
+            }
         }
 
         Ok(())
@@ -180,10 +228,10 @@ impl InterfaceGenerator<'_> {
 
     pub fn finish(&mut self) -> String {
         if self.return_pointer_area_align > 0 {
-            self.gen.imported_builtins.insert("_rael_malloc");
+            let malloc = self.gen.runtime_func("malloc_aligned");
             uwrite!(
                 self.src,
-                "let _RET_AREA: Int = _rael_malloc({size}) // align: {align}\n",
+                "let _RET_AREA: Int = {malloc}({size}, {align})\n",
                 align = self.return_pointer_area_align,
                 size = self.return_pointer_area_size,
             );
@@ -192,29 +240,6 @@ impl InterfaceGenerator<'_> {
         mem::take(&mut self.src).into()
     }
 
-    fn path_to_root(&self) -> String {
-        let mut path_to_root = String::new();
-
-        if let Identifier::Interface(_, key) = self.identifier {
-            // Escape the submodule for this interface
-            path_to_root.push_str("super::");
-
-            // Escape the `exports` top-level submodule
-            if !self.in_import {
-                path_to_root.push_str("super::");
-            }
-
-            // Escape the namespace/package submodules for interface-based ids
-            match key {
-                WorldKey::Name(_) => {}
-                WorldKey::Interface(_) => {
-                    path_to_root.push_str("super::super::");
-                }
-            }
-        }
-        path_to_root
-    }
-
     pub fn start_append_submodule(&mut self, name: &WorldKey) -> Vec<ModuleName> {
         crate::compute_module_path(name, self.resolve, !self.in_import)
     }
@@ -238,19 +263,24 @@ impl InterfaceGenerator<'_> {
         let param_mode = TypeMode::AllBorrowed("'_");
         match func.kind {
             FunctionKind::Freestanding => {
+                // Keep the names of exported functions apart from the imported
+                // ones of the world, the only ones that are top-level functions
+                // too, rather than methods or in another package.
+                if let Identifier::World(_) = self.identifier {
+                    self.gen.used_value_names.insert(to_mbt_ident(&func.name));
+                }
                 if let Some(module_name) = module_name {
                     sig.self_arg = Some(format!("self: {}", module_name.qual));
                 }
             }
             FunctionKind::Method(id) | FunctionKind::Static(id) | FunctionKind::Constructor(id) => {
-                let name = self.resolve.types[id].name.as_ref().unwrap();
-                let name = to_upper_camel_case(name);
-                uwriteln!(self.src, "impl {name} {{");
+                let name = self.type_name(id);
                 sig.use_item_name = true;
                 if let FunctionKind::Method(_) = &func.kind {
-                    sig.self_arg = Some("&self".into());
+                    sig.self_arg = Some(format!("self: {name}"));
                     sig.self_is_first_param = true;
                 }
+                sig.qualifier = Some(name);
             }
         }
         let params = self.print_signature(func, param_mode, &sig);
@@ -259,9 +289,9 @@ impl InterfaceGenerator<'_> {
         let ffi_name = format!(
             "ffi_{}{}",
             module_name
-                .map(|n| format!("{}_", n.qual.to_snake_case()))
+                .map(|n| format!("{}_", n.ffi))
                 .unwrap_or("".into()),
-            func.name.to_snake_case(),
+            func.name.to_snake_case().replace('.', "_"),
         );
         let mut f = FunctionBindgen::new(self, params, Some(ffi_name.clone()));
         abi::call(
@@ -281,27 +311,19 @@ impl InterfaceGenerator<'_> {
         } = f;
 
         if needs_cleanup_list {
-            self.src.push_str("let mut cleanup_list = Vec::new();\n");
+            self.src.push_str("let cleanup_list: Array[Int] = []\n");
         }
         assert!(handle_decls.is_empty());
         if import_return_pointer_area_size > 0 {
-            self.gen.imported_builtins.insert("_rael_malloc");
-            uwrite!(
+            let malloc = self.gen.runtime_func("malloc_aligned");
+            uwriteln!(
                 self.src,
-                "let ret_area = _rael_malloc({import_return_pointer_area_size})"
+                "let ret_area = {malloc}({import_return_pointer_area_size}, {import_return_pointer_area_align})"
             );
-            uwriteln!(self.src, " // align: {import_return_pointer_area_align}");
         }
         self.src.push_str(&String::from(src));
 
         self.src.push_str("}\n");
-
-        match func.kind {
-            FunctionKind::Freestanding => {}
-            FunctionKind::Method(_) | FunctionKind::Static(_) | FunctionKind::Constructor(_) => {
-                self.src.push_str("}\n");
-            }
-        }
         self.src.push_str("\n");
 
         let wasm_sig = self.resolve.wasm_signature(AbiVariant::GuestImport, func);
@@ -335,12 +357,17 @@ impl InterfaceGenerator<'_> {
             return;
         }
 
-        let name_snake = func.name.to_snake_case().replace('.', "_");
-        let wasm_module_export_name = match self.identifier {
-            Identifier::Interface(_, key) => Some(self.resolve.name_world_key(key)),
-            Identifier::World(_) => None,
+        let (wasm_module_export_name, qualifier) = match self.identifier {
+            Identifier::Interface(id, key) => (
+                Some(self.resolve.name_world_key(key)),
+                self.resolve.interfaces[id].name.as_deref(),
+            ),
+            Identifier::World(_) => (None, None),
         };
-        let export_prefix = self.gen.opts.export_prefix.as_deref().unwrap_or("");
+        let name_snake = self
+            .gen
+            .unique_value_name(&func.name.replace('.', "-"), qualifier);
+        let export_prefix = self.gen.opts.export_prefix.clone().unwrap_or_default();
         let export_name = func.core_export_name(wasm_module_export_name.as_deref());
         uwrite!(
             self.src,
@@ -354,7 +381,10 @@ impl InterfaceGenerator<'_> {
         let mut params = Vec::new();
         for (i, param) in sig.params.iter().enumerate() {
             let name = format!("arg{}", i);
-            uwrite!(self.src, "{name}: {},", wasm_type(*param));
+            if i > 0 {
+                self.src.push_str(", ");
+            }
+            uwrite!(self.src, "{name}: {}", wasm_type(*param));
             params.push(name);
         }
         self.src.push_str(") -> ");
@@ -370,10 +400,18 @@ impl InterfaceGenerator<'_> {
             }
             _ => unimplemented!(),
         };
-        self.gen.exported_symbols.insert(
-            format!("__export_{name_snake}"),
-            (format!("{export_prefix}{export_name}"), has_rv),
-        );
+        let post_return = abi::guest_export_needs_post_return(self.resolve, func).then(|| {
+            (
+                format!("__post_return_{name_snake}"),
+                format!("{export_prefix}cabi_post_{export_name}"),
+            )
+        });
+        self.gen.manifest.exports.push(ExportedFunction {
+            function: name_snake.clone(),
+            export: format!("{export_prefix}{export_name}"),
+            has_rv,
+            post_return,
+        });
 
         self.push_str(" {\n");
 
@@ -402,11 +440,6 @@ impl InterfaceGenerator<'_> {
         self.src.push_str("\n");
 
         if abi::guest_export_needs_post_return(self.resolve, func) {
-            let export_prefix = self.gen.opts.export_prefix.as_deref().unwrap_or("");
-            self.gen.exported_symbols.insert(
-                format!("__post_return_{name_snake}"),
-                (format!("{export_prefix}cabi_post_{export_name}"), false),
-            );
             uwrite!(
                 self.src,
                 "
@@ -417,7 +450,10 @@ impl InterfaceGenerator<'_> {
             let mut params = Vec::new();
             for (i, result) in sig.results.iter().enumerate() {
                 let name = format!("arg{}", i);
-                uwrite!(self.src, "{name}: {},", wasm_type(*result));
+                if i > 0 {
+                    self.src.push_str(", ");
+                }
+                uwrite!(self.src, "{name}: {}", wasm_type(*result));
                 params.push(name);
             }
             self.src.push_str(") {\n");
@@ -440,61 +476,47 @@ impl InterfaceGenerator<'_> {
     pub fn generate_stub(
         &mut self,
         resource: Option<TypeId>,
-        pkg: Option<(String, String)>,
-        name: &str,
-        in_interface: bool,
+        module_name: Option<&ModuleName>,
         funcs: &[&Function],
     ) {
-        let path = if let Some((namespace, pkg_name)) = pkg {
-            format!(
-                "{}::{}::{}",
-                to_mbt_ident(&namespace),
-                to_mbt_ident(&pkg_name),
-                to_mbt_ident(name),
-            )
-        } else {
-            to_mbt_ident(name)
-        };
-
-        let name = resource
-            .map(|ty| {
-                format!(
-                    "Guest{}",
-                    self.resolve.types[ty]
-                        .name
-                        .as_deref()
-                        .unwrap()
-                        .to_upper_camel_case()
-                )
-            })
-            .unwrap_or_else(|| "Guest".to_string());
-
-        let qualified_name = if in_interface {
-            format!("exports::{path}::{name}")
-        } else {
-            name
+        let trait_name = match resource {
+            Some(ty) => format!("Guest{}", self.type_name(ty)),
+            None => module_name
+                .map(|n| n.qual.clone())
+                .unwrap_or_else(|| "Guest".to_string()),
         };
 
-        uwriteln!(self.src, "impl {qualified_name} for Stub {{");
-
         for &func in funcs {
             if self.gen.skip.contains(&func.name) {
                 continue;
             }
             let mut sig = FnSig {
-                use_item_name: true,
                 private: true,
+                use_item_name: true,
+                qualifier: Some(trait_name.clone()),
+                self_arg: Some("self: Stub".into()),
                 ..Default::default()
             };
-            if let FunctionKind::Method(_) = &func.kind {
-                sig.self_arg = Some("&self".into());
-                sig.self_is_first_param = true;
+            match &func.kind {
+                FunctionKind::Method(_) => sig.self_is_first_param = true,
+                // Without `self`, these are methods of `Stub` implementing
+                // the trait.
+                FunctionKind::Constructor(_) | FunctionKind::Static(_) => {
+                    sig.qualifier = Some("Stub".into());
+                    sig.self_arg = None;
+                }
+                FunctionKind::Freestanding => {}
             }
-            self.print_signature(func, TypeMode::Owned, &sig);
-            self.src.push_str("{ unreachable!() }\n");
+            self.print_docs_and_params(func, TypeMode::Owned, &sig);
+            if let FunctionKind::Constructor(_) = &func.kind {
+                self.push_str(" -> Stub");
+            } else {
+                self.print_results(&func.results, TypeMode::Owned);
+            }
+            self.push_str(" {\n");
+            self.push_str("abort(\"unimplemented\")\n");
+            self.push_str("}\n\n");
         }
-
-        self.src.push_str("}\n");
     }
 
     fn rustdoc(&mut self, docs: &Docs) {
@@ -509,40 +531,46 @@ impl InterfaceGenerator<'_> {
         }
     }
 
-    fn rustdoc_params(&mut self, docs: &[(String, Type)], header: &str) {
-        let _ = (docs, header);
-        // let docs = docs
-        //     .iter()
-        //     .filter(|param| param.docs.trim().len() > 0)
-        //     .collect::<Vec<_>>();
-        // if docs.len() == 0 {
-        //     return;
-        // }
-
-        // self.push_str("///\n");
-        // self.push_str("/// ## ");
-        // self.push_str(header);
-        // self.push_str("\n");
-        // self.push_str("///\n");
-
-        // for param in docs {
-        //     for (i, line) in param.docs.lines().enumerate() {
-        //         self.push_str("/// ");
-        //         // Currently wasi only has at most one return value, so there's no
-        //         // need to indent it or name it.
-        //         if header != "Return" {
-        //             if i == 0 {
-        //                 self.push_str("* `");
-        //                 self.push_str(to_rust_ident(param.name.as_str()));
-        //                 self.push_str("` - ");
-        //             } else {
-        //                 self.push_str("  ");
-        //             }
-        //         }
-        //         self.push_str(line);
-        //         self.push_str("\n");
-        //     }
-        // }
+    fn rustdoc_params(&mut self, func: &Function) {
+        // WIT has no per-parameter doc comments, so when a function is
+        // documented describe its parameters and results by name and type.
+        if func.docs.contents.is_none() {
+            return;
+        }
+        let params = match &func.kind {
+            FunctionKind::Method(_) => &func.params[1..],
+            _ => &func.params[..],
+        };
+        if !params.is_empty() {
+            self.push_str("///\n/// ## Parameters\n///\n");
+            for (name, ty) in params {
+                let ty = self.type_string(ty, TypeMode::Owned);
+                uwriteln!(self.src, "/// * `{}` : `{ty}`", to_mbt_ident(name));
+            }
+        }
+        if let FunctionKind::Constructor(_) = &func.kind {
+            return;
+        }
+        match &func.results {
+            Results::Named(results) if !results.is_empty() => {
+                self.push_str("///\n/// ## Return\n///\n");
+                for (name, ty) in results {
+                    let ty = self.type_string(ty, TypeMode::Owned);
+                    uwriteln!(self.src, "/// * `{}` : `{ty}`", to_mbt_ident(name));
+                }
+            }
+            Results::Anon(ty) => {
+                let ty = self.type_string(ty, TypeMode::Owned);
+                uwriteln!(self.src, "///\n/// ## Return\n///\n/// `{ty}`");
+            }
+            Results::Named(_) => {}
+        }
+    }
+
+    pub(super) fn type_string(&mut self, ty: &Type, mode: TypeMode) -> String {
+        let prev = mem::take(&mut self.src);
+        self.print_ty(ty, mode);
+        mem::replace(&mut self.src, prev).into()
     }
 
     fn print_signature(
@@ -552,8 +580,12 @@ impl InterfaceGenerator<'_> {
         sig: &FnSig,
     ) -> Vec<String> {
         let params = self.print_docs_and_params(func, param_mode, sig);
-        if let FunctionKind::Constructor(_) = &func.kind {
-            self.push_str(" -> Self")
+        if let FunctionKind::Constructor(id) = &func.kind {
+            if sig.is_trait {
+                self.push_str(" -> Self")
+            } else {
+                uwrite!(self.src, " -> {}", self.type_name(*id));
+            }
         } else {
             self.print_results(&func.results, TypeMode::Owned);
         }
@@ -567,9 +599,7 @@ impl InterfaceGenerator<'_> {
         sig: &FnSig,
     ) -> Vec<String> {
         self.rustdoc(&func.docs);
-        self.rustdoc_params(&func.params, "Parameters");
-        // TODO: re-add this when docs are back
-        // self.rustdoc_params(&func.results, "Return");
+        self.rustdoc_params(func);
 
         if !sig.private {
             self.push_str("pub ");
@@ -592,6 +622,10 @@ impl InterfaceGenerator<'_> {
         } else {
             &func.name
         };
+        if let Some(qualifier) = &sig.qualifier {
+            self.push_str(qualifier);
+            self.push_str("::");
+        }
         self.push_str(&to_mbt_ident(func_name));
         if let Some(generics) = &sig.generics {
             self.push_str(generics);
@@ -632,9 +666,11 @@ impl InterfaceGenerator<'_> {
             }
             _ => {
                 self.push_str(" -> (");
-                for ty in results.iter_types() {
+                for (i, ty) in results.iter_types().enumerate() {
+                    if i > 0 {
+                        self.push_str(", ");
+                    }
                     self.print_ty(ty, mode);
-                    self.push_str(", ")
                 }
                 self.push_str(")")
             }
@@ -656,20 +692,14 @@ impl InterfaceGenerator<'_> {
             Type::Float32 => self.push_str("Float"),
             Type::Float64 => self.push_str("Float64"),
             Type::Char => self.push_str("Int"),
-            Type::String => {
-                if self.gen.opts.raw_strings {
-                    self.push_str("Bytes");
-                } else {
-                    self.push_str("String");
-                }
-            }
+            Type::String => self.push_str("String"),
         }
     }
 
     fn print_optional_ty(&mut self, ty: Option<&Type>, mode: TypeMode) {
         match ty {
             Some(ty) => self.print_ty(ty, mode),
-            None => self.push_str("()"),
+            None => self.push_str("Unit"),
         }
     }
 
@@ -684,10 +714,14 @@ impl InterfaceGenerator<'_> {
         )
     }
 
-    fn type_path_with_name(&self, id: TypeId, name: String) -> String {
+    pub(super) fn type_name(&self, id: TypeId) -> String {
+        self.gen.type_name(self.resolve, id)
+    }
+
+    pub(super) fn type_path_with_name(&self, id: TypeId, name: String) -> String {
         if let TypeOwner::Interface(id) = self.resolve.types[id].owner {
             if let Some(path) = self.path_to_interface(id) {
-                return format!("{path}::{name}");
+                return format!("{path}{name}");
             }
         }
         name
@@ -698,100 +732,33 @@ impl InterfaceGenerator<'_> {
         let lt = self.lifetime_for(&info, mode);
         let ty = &self.resolve.types[id];
         if ty.name.is_some() {
-            // If `mode` is borrowed then that means literal ownership of the
-            // input type is not necessarily required. In this situation we
-            // ideally want to put a `&` in front to statically indicate this.
-            // That's not required in all situations however and is only really
-            // critical for lists which otherwise would transfer ownership of
-            // the allocation to this function.
-            //
-            // Note, though, that if the type has an `own<T>` inside of it then
-            // it is actually required that we take ownership since Rust is
-            // losing access to those handles.
-            //
-            // We also skip borrowing if the type has a lifetime associated with
-            // in which case we treated it as already borrowed.
-            //
-            // Check here if the type has the right shape and if we're in the
-            // right mode, and if those conditions are met a lifetime is
-            // printed.
-            if info.has_list && !info.has_own_handle && lt.is_none() {
-                if let TypeMode::AllBorrowed(lt) | TypeMode::HandlesBorrowed(lt) = mode {
-                    self.push_str("&");
-                    if lt != "'_" {
-                        self.push_str(lt);
-                        self.push_str(" ");
-                    }
-                }
-            }
             let name = self.type_path(id, lt.is_none());
             self.push_str(&name);
-
-            // If the type recursively owns data and it's a
-            // variant/record/list, then we need to place the
-            // lifetime parameter on the type as well.
-            if (info.has_list || info.has_borrow_handle)
-                && !info.has_own_handle
-                && needs_generics(self.resolve, &ty.kind)
-            {
-                self.print_generics(lt);
-            }
-
             return;
-
-            fn needs_generics(resolve: &Resolve, ty: &TypeDefKind) -> bool {
-                match ty {
-                    TypeDefKind::Variant(_)
-                    | TypeDefKind::Record(_)
-                    | TypeDefKind::Option(_)
-                    | TypeDefKind::Result(_)
-                    | TypeDefKind::Future(_)
-                    | TypeDefKind::Stream(_)
-                    | TypeDefKind::List(_)
-                    | TypeDefKind::Flags(_)
-                    | TypeDefKind::Enum(_)
-                    | TypeDefKind::Tuple(_) => true,
-                    TypeDefKind::Type(Type::Id(t)) => {
-                        needs_generics(resolve, &resolve.types[*t].kind)
-                    }
-                    TypeDefKind::Type(Type::String) => true,
-                    TypeDefKind::Handle(Handle::Borrow(_)) => true,
-                    TypeDefKind::Resource | TypeDefKind::Handle(_) | TypeDefKind::Type(_) => false,
-                    TypeDefKind::Unknown => unreachable!(),
-                }
-            }
         }
 
         match &ty.kind {
             TypeDefKind::List(t) => self.print_list(t, mode),
 
             TypeDefKind::Option(t) => {
-                self.push_str("Option<");
+                self.push_str("Option[");
                 self.print_ty(t, mode);
-                self.push_str(">");
+                self.push_str("]");
             }
 
             TypeDefKind::Result(r) => {
-                self.push_str("Result<");
+                self.push_str("Result[");
                 self.print_optional_ty(r.ok.as_ref(), mode);
-                self.push_str(",");
+                self.push_str(", ");
                 self.print_optional_ty(r.err.as_ref(), mode);
-                self.push_str(">");
+                self.push_str("]");
             }
 
             TypeDefKind::Variant(_) => panic!("unsupported anonymous variant"),
 
-            // Tuple-like records are mapped directly to Rust tuples of
-            // types. Note the trailing comma after each member to
-            // appropriately handle 1-tuples.
-            TypeDefKind::Tuple(t) => {
-                self.push_str("(");
-                for ty in t.types.iter() {
-                    self.print_ty(ty, mode);
-                    self.push_str(",");
-                }
-                self.push_str(")");
-            }
+            // Tuple-like records are mapped directly to MoonBit tuples of
+            // types.
+            TypeDefKind::Tuple(t) => self.print_tuple(t, mode),
             TypeDefKind::Resource => {
                 panic!("unsupported anonymous type reference: resource")
             }
@@ -805,16 +772,16 @@ impl InterfaceGenerator<'_> {
                 panic!("unsupported anonymous type reference: enum")
             }
             TypeDefKind::Future(ty) => {
-                self.push_str("Future<");
+                self.push_str("Future[");
                 self.print_optional_ty(ty.as_ref(), mode);
-                self.push_str(">");
+                self.push_str("]");
             }
             TypeDefKind::Stream(stream) => {
-                self.push_str("Stream<");
+                self.push_str("Stream[");
                 self.print_optional_ty(stream.element.as_ref(), mode);
-                self.push_str(",");
+                self.push_str(", ");
                 self.print_optional_ty(stream.end.as_ref(), mode);
-                self.push_str(">");
+                self.push_str("]");
             }
 
             TypeDefKind::Handle(Handle::Own(ty)) => {
@@ -823,24 +790,8 @@ impl InterfaceGenerator<'_> {
             }
 
             TypeDefKind::Handle(Handle::Borrow(ty)) => {
-                self.push_str("&");
-                if let TypeMode::AllBorrowed(lt) | TypeMode::HandlesBorrowed(lt) = mode {
-                    if lt != "'_" {
-                        self.push_str(lt);
-                        self.push_str(" ");
-                    }
-                }
                 if self.is_exported_resource(*ty) {
-                    self.push_str(
-                        &self.type_path_with_name(
-                            *ty,
-                            self.resolve.types[*ty]
-                                .name
-                                .as_deref()
-                                .unwrap()
-                                .to_upper_camel_case(),
-                        ),
-                    );
+                    self.push_str(&self.type_path_with_name(*ty, self.type_name(*ty)));
                 } else {
                     self.print_ty(&Type::Id(*ty), mode);
                 }
@@ -884,20 +835,15 @@ impl InterfaceGenerator<'_> {
         }
     }
 
-    fn print_generics(&mut self, lifetime: Option<&str>) {
-        if lifetime.is_none() {
-            return;
-        }
-        self.push_str("<");
-        if let Some(lt) = lifetime {
-            self.push_str(lt);
-            self.push_str(",");
+    fn print_tuple(&mut self, tuple: &Tuple, mode: TypeMode) {
+        self.push_str("(");
+        for (i, ty) in tuple.types.iter().enumerate() {
+            if i > 0 {
+                self.push_str(", ");
+            }
+            self.print_ty(ty, mode);
         }
-        self.push_str(">");
-    }
-
-    fn int_repr(&mut self, repr: Int) {
-        self.push_str(int_repr(repr));
+        self.push_str(")");
     }
 
     fn modes_of(&self, ty: TypeId) -> Vec<(String, TypeMode)> {
@@ -932,449 +878,134 @@ impl InterfaceGenerator<'_> {
         result
     }
 
-    fn print_typedef_record(
-        &mut self,
-        id: TypeId,
-        record: &Record,
-        docs: &Docs,
-        derive_component: bool,
-    ) {
-        let info = self.info(id);
-        // We use a BTree set to make sure we don't have any duplicates and we have a stable order
-        let additional_derives: BTreeSet<String> = self
-            .gen
-            .opts
-            .additional_derive_attributes
-            .iter()
-            .cloned()
-            .collect();
+    fn print_typedef_record(&mut self, id: TypeId, record: &Record, docs: &Docs) {
         for (name, mode) in self.modes_of(id) {
-            let lt = self.lifetime_for(&info, mode);
             self.rustdoc(docs);
-
-            if derive_component {
-                self.push_str("#[derive(wasmtime::component::ComponentType)]\n");
-                if lt.is_none() {
-                    self.push_str("#[derive(wasmtime::component::Lift)]\n");
-                }
-                self.push_str("#[derive(wasmtime::component::Lower)]\n");
-                self.push_str("#[component(record)]\n");
-            }
-            let mut derives = additional_derives.clone();
-            if info.is_copy() {
-                self.push_str("#[repr(C)]\n");
-                derives.extend(["Copy", "Clone"].into_iter().map(|s| s.to_string()));
-            } else if info.is_clone() {
-                derives.insert("Clone".to_string());
-            }
-            if !derives.is_empty() {
-                self.push_str("#[derive(");
-                self.push_str(&derives.into_iter().collect::<Vec<_>>().join(", "));
-                self.push_str(")]\n")
-            }
-            self.push_str(&format!("pub struct {}", name));
-            self.print_generics(lt);
-            self.push_str(" {\n");
+            uwriteln!(self.src, "pub struct {name} {{");
             for field in record.fields.iter() {
                 self.rustdoc(&field.docs);
-                if derive_component {
-                    self.push_str(&format!("#[component(name = \"{}\")]\n", field.name));
-                }
-                self.push_str("pub ");
                 self.push_str(&to_mbt_ident(&field.name));
                 self.push_str(": ");
                 self.print_ty(&field.ty, mode);
-                self.push_str(",\n");
+                self.push_str("\n");
             }
-            self.push_str("}\n");
+            self.push_str("}");
+            self.print_derives(&[]);
+            self.push_str("\n\n");
+        }
+    }
 
-            self.push_str("impl");
-            self.print_generics(lt);
-            self.push_str(" ::core::fmt::Debug for ");
-            self.push_str(&name);
-            self.print_generics(lt);
-            self.push_str(" {\n");
-            self.push_str(
-                "fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-            );
-            self.push_str(&format!("f.debug_struct(\"{}\")", name));
-            for field in record.fields.iter() {
-                self.push_str(&format!(
-                    ".field(\"{}\", &self.{})",
-                    field.name,
-                    to_mbt_ident(&field.name)
-                ));
-            }
-            self.push_str(".finish()\n");
-            self.push_str("}\n");
-            self.push_str("}\n");
-
-            if info.error {
-                self.push_str("impl");
-                self.print_generics(lt);
-                self.push_str(" ::core::fmt::Display for ");
-                self.push_str(&name);
-                self.print_generics(lt);
-                self.push_str(" {\n");
-                self.push_str(
-                    "fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-                );
-                self.push_str("write!(f, \"{:?}\", self)\n");
-                self.push_str("}\n");
-                self.push_str("}\n");
-                if self.gen.opts.std_feature {
-                    self.push_str("#[cfg(feature = \"std\")]");
-                }
-                self.push_str("impl std::error::Error for ");
-                self.push_str(&name);
-                self.push_str("{}\n");
-            }
+    fn print_derives(&mut self, defaults: &[&str]) {
+        // We use a BTree set to make sure we don't have any duplicates and have a stable order
+        let derives = defaults
+            .iter()
+            .map(|s| s.to_string())
+            .chain(self.gen.opts.derive.iter().cloned())
+            .collect::<BTreeSet<_>>();
+        if !derives.is_empty() {
+            self.push_str(" derive(");
+            self.push_str(&derives.into_iter().collect::<Vec<_>>().join(", "));
+            self.push_str(")");
         }
     }
 
     fn print_typedef_tuple(&mut self, id: TypeId, tuple: &Tuple, docs: &Docs) {
-        let info = self.info(id);
         for (name, mode) in self.modes_of(id) {
-            let lt = self.lifetime_for(&info, mode);
             self.rustdoc(docs);
-            self.push_str(&format!("pub type {}", name));
-            self.print_generics(lt);
-            self.push_str(" = (");
-            for ty in tuple.types.iter() {
-                self.print_ty(ty, mode);
-                self.push_str(",");
-            }
-            self.push_str(");\n");
+            uwrite!(self.src, "pub typealias {name} = ");
+            self.print_tuple(tuple, mode);
+            self.push_str("\n\n");
         }
     }
 
-    fn print_typedef_variant(
-        &mut self,
-        id: TypeId,
-        variant: &Variant,
-        docs: &Docs,
-        derive_component: bool,
-    ) where
+    fn print_typedef_variant(&mut self, id: TypeId, variant: &Variant, docs: &Docs)
+    where
         Self: Sized,
     {
-        self.print_rust_enum(
+        self.print_mbt_enum(
             id,
-            variant.cases.iter().map(|c| {
-                (
-                    c.name.to_upper_camel_case(),
-                    Some(c.name.clone()),
-                    &c.docs,
-                    c.ty.as_ref(),
-                )
-            }),
+            variant
+                .cases
+                .iter()
+                .map(|c| (to_upper_camel_case(&c.name), &c.docs, c.ty.as_ref())),
             docs,
-            if derive_component {
-                Some("variant")
-            } else {
-                None
-            },
         );
     }
 
-    fn print_rust_enum<'b>(
+    fn print_mbt_enum<'b>(
         &mut self,
         id: TypeId,
-        cases: impl IntoIterator<Item = (String, Option<String>, &'b Docs, Option<&'b Type>)> + Clone,
+        cases: impl IntoIterator<Item = (String, &'b Docs, Option<&'b Type>)> + Clone,
         docs: &Docs,
-        derive_component: Option<&str>,
     ) where
         Self: Sized,
     {
-        let info = self.info(id);
-        // We use a BTree set to make sure we don't have any duplicates and have a stable order
-        let additional_derives: BTreeSet<String> = self
-            .gen
-            .opts
-            .additional_derive_attributes
-            .iter()
-            .cloned()
-            .collect();
         for (name, mode) in self.modes_of(id) {
             self.rustdoc(docs);
-            let lt = self.lifetime_for(&info, mode);
-            if let Some(derive_component) = derive_component {
-                self.push_str("#[derive(wasmtime::component::ComponentType)]\n");
-                if lt.is_none() {
-                    self.push_str("#[derive(wasmtime::component::Lift)]\n");
-                }
-                self.push_str("#[derive(wasmtime::component::Lower)]\n");
-                self.push_str(&format!("#[component({})]\n", derive_component));
-            }
-            let mut derives = additional_derives.clone();
-            if info.is_copy() {
-                derives.extend(["Copy", "Clone"].into_iter().map(|s| s.to_string()));
-            } else if info.is_clone() {
-                derives.insert("Clone".to_string());
-            }
-            if !derives.is_empty() {
-                self.push_str("#[derive(");
-                self.push_str(&derives.into_iter().collect::<Vec<_>>().join(", "));
-                self.push_str(")]\n")
-            }
-            self.push_str(&format!("pub enum {name}"));
-            self.print_generics(lt);
-            self.push_str("{\n");
-            for (case_name, component_name, docs, payload) in cases.clone() {
+            uwriteln!(self.src, "pub enum {name} {{");
+            for (case_name, docs, payload) in cases.clone() {
                 self.rustdoc(docs);
-                if derive_component.is_some() {
-                    if let Some(n) = component_name {
-                        self.push_str(&format!("#[component(name = \"{}\")] ", n));
-                    }
-                }
                 self.push_str(&case_name);
                 if let Some(ty) = payload {
                     self.push_str("(");
                     self.print_ty(ty, mode);
                     self.push_str(")")
                 }
-                self.push_str(",\n");
-            }
-            self.push_str("}\n");
-
-            self.print_rust_enum_debug(
-                id,
-                mode,
-                &name,
-                cases
-                    .clone()
-                    .into_iter()
-                    .map(|(name, _attr, _docs, ty)| (name, ty)),
-            );
-
-            if info.error {
-                self.push_str("impl");
-                self.print_generics(lt);
-                self.push_str(" ::core::fmt::Display for ");
-                self.push_str(&name);
-                self.print_generics(lt);
-                self.push_str(" {\n");
-                self.push_str(
-                    "fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-                );
-                self.push_str("write!(f, \"{:?}\", self)\n");
-                self.push_str("}\n");
-                self.push_str("}\n");
                 self.push_str("\n");
-
-                if self.gen.opts.std_feature {
-                    self.push_str("#[cfg(feature = \"std\")]");
-                }
-                self.push_str("impl");
-                self.print_generics(lt);
-                self.push_str(" std::error::Error for ");
-                self.push_str(&name);
-                self.print_generics(lt);
-                self.push_str(" {}\n");
-            }
-        }
-    }
-
-    fn print_rust_enum_debug<'b>(
-        &mut self,
-        id: TypeId,
-        mode: TypeMode,
-        name: &str,
-        cases: impl IntoIterator<Item = (String, Option<&'b Type>)>,
-    ) where
-        Self: Sized,
-    {
-        let info = self.info(id);
-        let lt = self.lifetime_for(&info, mode);
-        self.push_str("impl");
-        self.print_generics(lt);
-        self.push_str(" ::core::fmt::Debug for ");
-        self.push_str(name);
-        self.print_generics(lt);
-        self.push_str(" {\n");
-        self.push_str(
-            "fn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-        );
-        self.push_str("match self {\n");
-        for (case_name, payload) in cases {
-            self.push_str(name);
-            self.push_str("::");
-            self.push_str(&case_name);
-            if payload.is_some() {
-                self.push_str("(e)");
-            }
-            self.push_str(" => {\n");
-            self.push_str(&format!("f.debug_tuple(\"{}::{}\")", name, case_name));
-            if payload.is_some() {
-                self.push_str(".field(e)");
             }
-            self.push_str(".finish()\n");
-            self.push_str("}\n");
+            self.push_str("}");
+            self.print_derives(&[]);
+            self.push_str("\n\n");
         }
-        self.push_str("}\n");
-        self.push_str("}\n");
-        self.push_str("}\n");
     }
 
     fn print_typedef_option(&mut self, id: TypeId, payload: &Type, docs: &Docs) {
-        let info = self.info(id);
-
         for (name, mode) in self.modes_of(id) {
             self.rustdoc(docs);
-            let lt = self.lifetime_for(&info, mode);
-            self.push_str(&format!("pub type {}", name));
-            self.print_generics(lt);
-            self.push_str("= Option<");
+            uwrite!(self.src, "pub typealias {name} = Option[");
             self.print_ty(payload, mode);
-            self.push_str(">;\n");
+            self.push_str("]\n\n");
         }
     }
 
     fn print_typedef_result(&mut self, id: TypeId, result: &Result_, docs: &Docs) {
-        let info = self.info(id);
-
         for (name, mode) in self.modes_of(id) {
             self.rustdoc(docs);
-            let lt = self.lifetime_for(&info, mode);
-            self.push_str(&format!("pub type {}", name));
-            self.print_generics(lt);
-            self.push_str("= Result<");
+            uwrite!(self.src, "pub typealias {name} = Result[");
             self.print_optional_ty(result.ok.as_ref(), mode);
-            self.push_str(",");
+            self.push_str(", ");
             self.print_optional_ty(result.err.as_ref(), mode);
-            self.push_str(">;\n");
+            self.push_str("]\n\n");
         }
     }
 
-    fn print_typedef_enum(
-        &mut self,
-        id: TypeId,
-        name: &str,
-        enum_: &Enum,
-        docs: &Docs,
-        attrs: &[String],
-        case_attr: Box<dyn Fn(&EnumCase) -> String>,
-    ) where
+    fn print_typedef_enum(&mut self, id: TypeId, enum_: &Enum, docs: &Docs)
+    where
         Self: Sized,
     {
-        let info = self.info(id);
-
-        let name = to_upper_camel_case(name);
+        let name = self.type_name(id);
         self.rustdoc(docs);
-        for attr in attrs {
-            self.push_str(&format!("{}\n", attr));
-        }
-        self.push_str("#[repr(");
-        self.int_repr(enum_.tag());
-        self.push_str(")]\n");
-        // We use a BTree set to make sure we don't have any duplicates and a stable order
-        let mut derives: BTreeSet<String> = self
-            .gen
-            .opts
-            .additional_derive_attributes
-            .iter()
-            .cloned()
-            .collect();
-        derives.extend(
-            ["Clone", "Copy", "PartialEq", "Eq"]
-                .into_iter()
-                .map(|s| s.to_string()),
-        );
-        self.push_str("#[derive(");
-        self.push_str(&derives.into_iter().collect::<Vec<_>>().join(", "));
-        self.push_str(")]\n");
-        self.push_str(&format!("pub enum {name} {{\n"));
+        uwriteln!(self.src, "pub enum {name} {{");
         for case in enum_.cases.iter() {
             self.rustdoc(&case.docs);
-            self.push_str(&case_attr(case));
-            self.push_str(&case.name.to_upper_camel_case());
-            self.push_str(",\n");
-        }
-        self.push_str("}\n");
-
-        // Auto-synthesize an implementation of the standard `Error` trait for
-        // error-looking types based on their name.
-        if info.error {
-            self.push_str("impl ");
-            self.push_str(&name);
-            self.push_str("{\n");
-
-            self.push_str("pub fn name(&self) -> &'static str {\n");
-            self.push_str("match self {\n");
-            for case in enum_.cases.iter() {
-                self.push_str(&name);
-                self.push_str("::");
-                self.push_str(&case.name.to_upper_camel_case());
-                self.push_str(" => \"");
-                self.push_str(case.name.as_str());
-                self.push_str("\",\n");
-            }
-            self.push_str("}\n");
-            self.push_str("}\n");
-
-            self.push_str("pub fn message(&self) -> &'static str {\n");
-            self.push_str("match self {\n");
-            for case in enum_.cases.iter() {
-                self.push_str(&name);
-                self.push_str("::");
-                self.push_str(&case.name.to_upper_camel_case());
-                self.push_str(" => \"");
-                if let Some(contents) = &case.docs.contents {
-                    self.push_str(contents.trim());
-                }
-                self.push_str("\",\n");
-            }
-            self.push_str("}\n");
-            self.push_str("}\n");
-
-            self.push_str("}\n");
-
-            self.push_str("impl ::core::fmt::Debug for ");
-            self.push_str(&name);
-            self.push_str(
-                "{\nfn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-            );
-            self.push_str("f.debug_struct(\"");
-            self.push_str(&name);
-            self.push_str("\")\n");
-            self.push_str(".field(\"code\", &(*self as i32))\n");
-            self.push_str(".field(\"name\", &self.name())\n");
-            self.push_str(".field(\"message\", &self.message())\n");
-            self.push_str(".finish()\n");
-            self.push_str("}\n");
-            self.push_str("}\n");
-
-            self.push_str("impl ::core::fmt::Display for ");
-            self.push_str(&name);
-            self.push_str(
-                "{\nfn fmt(&self, f: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {\n",
-            );
-            self.push_str("write!(f, \"{} (error {})\", self.name(), *self as i32)\n");
-            self.push_str("}\n");
-            self.push_str("}\n");
+            self.push_str(&to_upper_camel_case(&case.name));
             self.push_str("\n");
-            if self.gen.opts.std_feature {
-                self.push_str("#[cfg(feature = \"std\")]");
-            }
-            self.push_str("impl std::error::Error for ");
-            self.push_str(&name);
-            self.push_str("{}\n");
-        } else {
-            self.print_rust_enum_debug(
-                id,
-                TypeMode::Owned,
-                &name,
-                enum_
-                    .cases
-                    .iter()
-                    .map(|c| (c.name.to_upper_camel_case(), None)),
-            )
         }
+        self.push_str("}");
+        self.print_derives(&["Eq"]);
+        self.push_str("\n\n");
     }
 
     fn print_typedef_alias(&mut self, id: TypeId, ty: &Type, docs: &Docs) {
+        // A type `use`d from an interface in the same MoonBit package is
+        // referred to by its original name, see `MoonBit::name_types`.
+        if let Type::Id(target) = ty {
+            if self.type_path(id, true) == self.type_path(*target, true) {
+                return;
+            }
+        }
         if self.is_exported_resource(id) {
             let target = dealias(self.resolve, id);
-            let ty = &self.resolve.types[target];
             // TODO: We could wait until we know how a resource (and its
             // aliases) is used prior to generating declarations.  For example,
             // if only borrows are used, no need to generate the `Own{name}`
@@ -1382,52 +1013,35 @@ impl InterfaceGenerator<'_> {
             self.mark_resource_owned(target);
             for prefix in ["Own", ""] {
                 self.rustdoc(docs);
-                self.push_str(&format!(
-                    "pub type {prefix}{} = {};\n",
-                    self.resolve.types[id]
-                        .name
-                        .as_deref()
-                        .unwrap()
-                        .to_upper_camel_case(),
-                    self.type_path_with_name(
-                        target,
-                        format!(
-                            "{prefix}{}",
-                            ty.name.as_deref().unwrap().to_upper_camel_case()
-                        )
-                    )
-                ));
+                uwriteln!(
+                    self.src,
+                    "pub typealias {prefix}{} = {}\n",
+                    self.type_name(id),
+                    self.type_path_with_name(target, format!("{prefix}{}", self.type_name(target)))
+                );
             }
         } else {
-            let info = self.info(id);
             for (name, mode) in self.modes_of(id) {
                 self.rustdoc(docs);
-                self.push_str(&format!("pub type {name}"));
-                let lt = self.lifetime_for(&info, mode);
-                self.print_generics(lt);
-                self.push_str(" = ");
+                uwrite!(self.src, "pub typealias {name} = ");
                 self.print_ty(ty, mode);
-                self.push_str(";\n");
+                self.push_str("\n\n");
             }
         }
     }
 
     fn print_type_list(&mut self, id: TypeId, ty: &Type, docs: &Docs) {
-        let info = self.info(id);
         for (name, mode) in self.modes_of(id) {
-            let lt = self.lifetime_for(&info, mode);
             self.rustdoc(docs);
-            self.push_str(&format!("pub type {}", name));
-            self.print_generics(lt);
-            self.push_str(" = ");
+            uwrite!(self.src, "pub typealias {name} = ");
             self.print_list(ty, mode);
-            self.push_str(";\n");
+            self.push_str("\n\n");
         }
     }
 
     fn param_name(&self, ty: TypeId) -> String {
         let info = self.info(ty);
-        let name = to_upper_camel_case(self.resolve.types[ty].name.as_ref().unwrap());
+        let name = self.type_name(ty);
         if self.uses_two_names(&info) {
             format!("{}Param", name)
         } else {
@@ -1437,7 +1051,7 @@ impl InterfaceGenerator<'_> {
 
     fn result_name(&self, ty: TypeId) -> String {
         let info = self.info(ty);
-        let name = to_upper_camel_case(self.resolve.types[ty].name.as_ref().unwrap());
+        let name = self.type_name(ty);
         if self.uses_two_names(&info) {
             format!("{}Result", name)
         } else if self.is_exported_resource(ty) {
@@ -1496,33 +1110,22 @@ impl InterfaceGenerator<'_> {
     //     self.gen.opts.ownership
     // }
 
+    /// Returns the prefix of the types of `interface` when referred to from
+    /// the current interface, i.e. `@alias.` if they live in another MoonBit
+    /// package, or `None` if they're in the same package.
     fn path_to_interface(&self, interface: InterfaceId) -> Option<String> {
-        let InterfaceName { path, remapped } = &self.gen.interface_names[&interface];
-        if *remapped {
-            let mut path_to_root = self.path_to_root();
-            path_to_root.push_str(path);
-            Some(path_to_root)
-        } else {
-            let mut full_path = String::new();
-            if let Identifier::Interface(cur, name) = self.identifier {
-                if cur == interface {
-                    return None;
-                }
-                if !self.in_import {
-                    full_path.push_str("super::");
-                }
-                match name {
-                    WorldKey::Name(_) => {
-                        full_path.push_str("super::");
-                    }
-                    WorldKey::Interface(_) => {
-                        full_path.push_str("super::super::super::");
-                    }
-                }
+        let exported = match self.in_import {
+            true => None,
+            false => self.gen.export_interface_names.get(&interface),
+        };
+        let name = exported.unwrap_or_else(|| &self.gen.interface_names[&interface]);
+        let alias = name.alias.as_ref()?;
+        if let Identifier::Interface(cur, _) = self.identifier {
+            if cur == interface {
+                return None;
             }
-            full_path.push_str(path);
-            Some(full_path)
         }
+        Some(format!("@{alias}."))
     }
 
     fn is_exported_resource(&self, mut ty: TypeId) -> bool {
@@ -1552,6 +1155,18 @@ impl InterfaceGenerator<'_> {
             .owned = true;
     }
 
+    /// Returns the prefix of the FFI functions generated for the current
+    /// interface, keeping them apart from those of other interfaces.
+    fn ffi_prefix(&self) -> String {
+        match self.identifier {
+            Identifier::Interface(_, key) => {
+                let module_path = crate::compute_module_path(key, self.resolve, !self.in_import);
+                format!("{}_", module_path.last().unwrap().ffi)
+            }
+            Identifier::World(_) => String::new(),
+        }
+    }
+
     fn push_str(&mut self, s: &str) {
         self.src.push_str(s);
     }
@@ -1567,7 +1182,7 @@ impl<'a> wit_bindgen_core::InterfaceGenerator<'a> for InterfaceGenerator<'a> {
     }
 
     fn type_record(&mut self, id: TypeId, _name: &str, record: &Record, docs: &Docs) {
-        self.print_typedef_record(id, record, docs, false);
+        self.print_typedef_record(id, record, docs);
     }
 
     fn type_resource(&mut self, id: TypeId, name: &str, docs: &Docs) {
@@ -1580,166 +1195,103 @@ impl<'a> wit_bindgen_core::InterfaceGenerator<'a> for InterfaceGenerator<'a> {
             entry.direction = Direction::Export;
         }
         self.rustdoc(docs);
-        let camel = to_upper_camel_case(name);
-        let rt = self.gen.runtime_path();
+        let camel = self.type_name(id);
+        let ffi = format!("{}{}", self.ffi_prefix(), name.to_snake_case());
+        uwriteln!(self.src, "pub struct {camel} {{");
+        uwriteln!(self.src, "handle: Int");
+        uwriteln!(self.src, "}}");
+        uwriteln!(self.src, "");
 
         let wasm_import_module = if self.in_import {
-            // Imported resources are a simple wrapper around `Resource<T>` in
-            // the `wit-bindgen` crate.
-            uwriteln!(
-                self.src,
-                r#"
-                    #[derive(Debug)]
-                    #[repr(transparent)]
-                    pub struct {camel} {{
-                        handle: {rt}::Resource<{camel}>,
-                    }}
-
-                    impl {camel} {{
-                        #[doc(hidden)]
-                        pub unsafe fn from_handle(handle: u32) -> Self {{
-                            Self {{
-                                handle: {rt}::Resource::from_handle(handle),
-                            }}
-                        }}
-
-                        #[doc(hidden)]
-                        pub fn into_handle(self) -> u32 {{
-                            {rt}::Resource::into_handle(self.handle)
-                        }}
-
-                        #[doc(hidden)]
-                        pub fn handle(&self) -> u32 {{
-                            {rt}::Resource::handle(&self.handle)
-                        }}
-                    }}
-                "#
-            );
             self.wasm_import_module.unwrap().to_string()
         } else {
-            // Exported resources are represented as `Resource<T>` as opposed
-            // to being wrapped like imported resources.
-            //
-            // An `Own` typedef is available for the `Resource<T>` type though.
-            //
-            // Note that the actual name `{camel}` is defined here though as
-            // an alias of the type this is implemented by as configured by the
-            // `exports` configuration by the user.
-            let export_prefix = self.gen.opts.export_prefix.as_deref().unwrap_or("");
+            // Exported resources are created from the representation chosen
+            // by the guest, which can be retrieved back from the handle.
             let module = match self.identifier {
                 Identifier::Interface(_, key) => self.resolve.name_world_key(key),
-                Identifier::World(_) => unimplemented!("resource exports from worlds"),
+                Identifier::World(_) => "$root".to_string(),
             };
-            // NB: errors are ignored here since they'll generate an error
-            // through the `generate_exports` method above.
-            let impl_name = self
-                .gen
-                .lookup_export(&self.export_key(Some(name)))
-                .unwrap_or_else(|_| "ERROR".to_string());
-            let path_to_root = self.path_to_root();
-            uwriteln!(
-                self.src,
+            self.src.push_str(&format!(
                 r#"
-                    pub use {path_to_root}{impl_name} as {camel};
-                    const _: () = {{
-                        #[doc(hidden)]
-                        #[export_name = "{export_prefix}{module}#[dtor]{name}"]
-                        #[allow(non_snake_case)]
-                        uuuuunsafe extern "C" fn dtor(rep: usize) {{
-                            {rt}::Resource::<{camel}>::dtor(rep)
-                        }}
-                    }};
-                    unsafe impl {rt}::RustResource for {camel} {{
-                        unsafe fn new(_rep: usize) -> u32 {{
-                            #[cfg(not(target_arch = "wasm32"))]
-                            unreachable!();
-
-                            #[cfg(target_arch = "wasm32")]
-                            {{
-                                #[link(wasm_import_module = "[export]{module}")]
-                                extern "C" {{
-                                    #[link_name = "[resource-new]{name}"]
-                                    fn new(_: usize) -> u32;
-                                }}
-                                new(_rep)
-                            }}
-                        }}
-
-                        unsafe fn rep(_handle: u32) -> usize {{
-                            #[cfg(not(target_arch = "wasm32"))]
-                            unreachable!();
-
-                            #[cfg(target_arch = "wasm32")]
-                            {{
-                                #[link(wasm_import_module = "[export]{module}")]
-                                extern "C" {{
-                                    #[link_name = "[resource-rep]{name}"]
-                                    fn rep(_: u32) -> usize;
-                                }}
-                                rep(_handle)
-                            }}
-                        }}
+                    pub typealias Own{camel} = {camel}
+
+                    pub fn {camel}::new(rep: Int) -> {camel} {{
+                        {{ handle: ffi_{ffi}_new(rep) }}
                     }}
-                    pub type Own{camel} = {rt}::Resource<{camel}>;
+
+                    pub fn {camel}::rep(self: {camel}) -> Int {{
+                        ffi_{ffi}_rep(self.handle)
+                    }}
+
+                    fn ffi_{ffi}_new(rep: Int) -> Int = "[export]{module}" "[resource-new]{name}"
+
+                    fn ffi_{ffi}_rep(handle: Int) -> Int = "[export]{module}" "[resource-rep]{name}"
+
                 "#
-            );
+            ));
             format!("[export]{module}")
         };
 
-        uwriteln!(
-            self.src,
+        self.src.push_str(&format!(
             r#"
-                unsafe impl {rt}::WasmResource for {camel} {{
-                     #[inline]
-                     unsafe fn drop(_handle: u32) {{
-                         #[cfg(not(target_arch = "wasm32"))]
-                         unreachable!();
-
-                         #[cfg(target_arch = "wasm32")]
-                         {{
-                             #[link(wasm_import_module = "{wasm_import_module}")]
-                             extern "C" {{
-                                 #[link_name = "[resource-drop]{name}"]
-                                 fn drop(_: u32);
-                             }}
-
-                             drop(_handle);
-                         }}
-                     }}
+                pub fn {camel}::drop(self: {camel}) -> Unit {{
+                    ffi_{ffi}_drop(self.handle)
                 }}
+
+                fn ffi_{ffi}_drop(handle: Int) = "{wasm_import_module}" "[resource-drop]{name}"
+
             "#
-        );
+        ));
     }
 
     fn type_tuple(&mut self, id: TypeId, _name: &str, tuple: &Tuple, docs: &Docs) {
         self.print_typedef_tuple(id, tuple, docs);
     }
 
-    fn type_flags(&mut self, _id: TypeId, name: &str, flags: &Flags, docs: &Docs) {
-        self.src.push_str(&format!(
-            "{bitflags}::bitflags! {{\n",
-            bitflags = self.gen.bitflags_path()
-        ));
-        self.rustdoc(docs);
+    fn type_flags(&mut self, id: TypeId, _name: &str, flags: &Flags, docs: &Docs) {
+        let name = self.type_name(id);
         let repr = MbtFlagsRepr::new(flags);
-        self.src.push_str(&format!(
-            "#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]\npub struct {}: {repr} {{\n",
-            name.to_upper_camel_case(),
-        ));
+        self.rustdoc(docs);
+        uwriteln!(self.src, "pub struct {name} {{");
+        uwriteln!(self.src, "bits: {repr}");
+        self.push_str("}");
+        self.print_derives(&["Eq"]);
+        self.push_str("\n\n");
+        let one = match repr {
+            MbtFlagsRepr::Int => "1",
+            MbtFlagsRepr::Int64 => "1L",
+        };
         for (i, flag) in flags.flags.iter().enumerate() {
             self.rustdoc(&flag.docs);
-            self.src.push_str(&format!(
-                "const {} = 1 << {};\n",
-                flag.name.to_shouty_snake_case(),
-                i,
-            ));
+            uwriteln!(
+                self.src,
+                "pub fn {name}::{}() -> {name} {{",
+                to_mbt_ident(&flag.name)
+            );
+            uwriteln!(self.src, "{{ bits: {one} << {i} }}");
+            uwriteln!(self.src, "}}");
+            uwriteln!(self.src, "");
         }
-        self.src.push_str("}\n");
-        self.src.push_str("}\n");
+        self.src.push_str(&format!(
+            r#"
+                pub fn {name}::empty() -> {name} {{
+                    {{ bits: 0 }}
+                }}
+
+                pub fn {name}::union(self: {name}, other: {name}) -> {name} {{
+                    {{ bits: self.bits | other.bits }}
+                }}
+
+                pub fn {name}::contains(self: {name}, other: {name}) -> Bool {{
+                    (self.bits & other.bits) == other.bits
+                }}
+
+            "#
+        ));
     }
 
     fn type_variant(&mut self, id: TypeId, _name: &str, variant: &Variant, docs: &Docs) {
-        self.print_typedef_variant(id, variant, docs, false);
+        self.print_typedef_variant(id, variant, docs);
     }
 
     fn type_option(&mut self, id: TypeId, _name: &str, payload: &Type, docs: &Docs) {
@@ -1750,33 +1302,34 @@ impl<'a> wit_bindgen_core::InterfaceGenerator<'a> for InterfaceGenerator<'a> {
         self.print_typedef_result(id, result, docs);
     }
 
-    fn type_enum(&mut self, id: TypeId, name: &str, enum_: &Enum, docs: &Docs) {
-        self.print_typedef_enum(id, name, enum_, docs, &[], Box::new(|_| String::new()));
+    fn type_enum(&mut self, id: TypeId, _name: &str, enum_: &Enum, docs: &Docs) {
+        self.print_typedef_enum(id, enum_, docs);
 
-        let name = to_upper_camel_case(name);
-        let mut cases = String::new();
-        let repr = int_repr(enum_.tag());
+        let name = self.type_name(id);
+        let mut lift = String::new();
+        let mut lower = String::new();
         for (i, case) in enum_.cases.iter().enumerate() {
-            let case = case.name.to_upper_camel_case();
-            cases.push_str(&format!("{i} => {name}::{case},\n"));
+            let case = to_upper_camel_case(&case.name);
+            lift.push_str(&format!("{i} => {case}\n"));
+            lower.push_str(&format!("{case} => {i}\n"));
         }
-        uwriteln!(
-            self.src,
+        self.src.push_str(&format!(
             r#"
-                impl {name} {{
-                    pub(crate) unsafe fn _lift(val: {repr}) -> {name} {{
-                        if !cfg!(debug_assertions) {{
-                            return ::core::mem::transmute(val);
-                        }}
-
-                        match val {{
-                            {cases}
-                            _ => panic!("invalid enum discriminant"),
-                        }}
+                pub fn {name}::_lift(val: Int) -> {name} {{
+                    match val {{
+                        {lift}
+                        _ => abort("invalid enum discriminant")
                     }}
                 }}
+
+                pub fn {name}::_lower(self: {name}) -> Int {{
+                    match self {{
+                        {lower}
+                    }}
+                }}
+
             "#
-        );
+        ));
     }
 
     fn type_alias(&mut self, id: TypeId, _name: &str, ty: &Type, docs: &Docs) {
@@ -1787,12 +1340,30 @@ impl<'a> wit_bindgen_core::InterfaceGenerator<'a> for InterfaceGenerator<'a> {
         self.print_type_list(id, ty, docs);
     }
 
-    fn type_builtin(&mut self, _id: TypeId, name: &str, ty: &Type, docs: &Docs) {
+    fn type_builtin(&mut self, id: TypeId, _name: &str, ty: &Type, docs: &Docs) {
         self.rustdoc(docs);
-        self.src
-            .push_str(&format!("pub type {}", name.to_upper_camel_case()));
-        self.src.push_str(" = ");
+        uwrite!(self.src, "pub typealias {} = ", self.type_name(id));
         self.print_ty(ty, TypeMode::Owned);
-        self.src.push_str(";\n");
+        self.src.push_str("\n\n");
+    }
+}
+
+/// The closure of `init_guest()` calling the implementation `T` of a
+/// function of an exported resource, whose values are kept in `table`.
+fn resource_closure(func: &Function, table: &str) -> String {
+    let params = (0..func.params.len())
+        .map(|i| format!("p{i}"))
+        .collect::<Vec<_>>();
+    let args = params.join(", ");
+    let name = to_mbt_ident(func.item_name());
+    match &func.kind {
+        FunctionKind::Constructor(_) => {
+            format!("new: fn({args}) {{\n{table}.push(T::new({args}))\n{table}.length() - 1\n}},\n")
+        }
+        FunctionKind::Method(_) => format!(
+            "{name}: fn({args}) {{ {table}[p0.handle].{name}({rest}) }},\n",
+            rest = params[1..].join(", "),
+        ),
+        _ => format!("{name}: fn({args}) {{ T::{name}({args}) }},\n"),
     }
 }
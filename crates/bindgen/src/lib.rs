@@ -4,7 +4,7 @@
 // Modification to work with MoonBit is done by Fantix King <fantix.king@gmail.com>.
 
 use std::cmp::Ordering;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Write as _};
 use std::mem;
 use std::str::FromStr;
@@ -13,12 +13,16 @@ use anyhow::{bail, Result};
 use heck::*;
 use wit_bindgen_core::abi::{Bitcast, WasmType};
 use wit_bindgen_core::{
-    uwriteln, wit_parser::*, Direction, Files, InterfaceGenerator as _, Source, Types,
+    uwrite, uwriteln, wit_parser::*, Direction, Files, InterfaceGenerator as _, Source, Types,
     WorldGenerator,
 };
 
 mod bindgen;
+mod format;
 mod interface;
+mod manifest;
+
+pub use manifest::{ExportedFunction, Manifest};
 
 #[derive(Default)]
 struct ResourceInfo {
@@ -32,12 +36,16 @@ struct ResourceInfo {
 }
 
 struct InterfaceName {
-    /// True when this interface name has been remapped through the use of `with` in the `bindgen!`
-    /// macro invocation.
+    /// True when this interface name has been remapped to an existing MoonBit
+    /// package through the use of `with`.
     remapped: bool,
 
     /// The string name for this interface.
     path: String,
+
+    /// The alias of the MoonBit package this interface lives in, if it is
+    /// generated as a separate package (see `Opts::multi_package`).
+    alias: Option<String>,
 }
 
 #[derive(Default)]
@@ -49,34 +57,53 @@ pub struct MoonBit {
     export_modules: Vec<(String, Vec<ModuleName>)>,
     skip: HashSet<String>,
     interface_names: HashMap<InterfaceId, InterfaceName>,
+    /// The names of exported interfaces, kept apart from `interface_names`
+    /// as an interface can be both imported and exported.
+    export_interface_names: HashMap<InterfaceId, InterfaceName>,
     resources: HashMap<TypeId, ResourceInfo>,
     import_funcs_called: bool,
-    with_name_counter: usize,
-    export_traits: HashMap<String, String>,
+    /// Traits of exported interfaces and resources with their `GuestImpl`
+    /// fields, in the order of the world's exports.
+    export_traits: Vec<(String, String)>,
+    /// The closures binding the functions of exported resources to their
+    /// implementation in `init_guest()`, by trait.
+    export_resources: HashMap<String, Vec<String>>,
+    package_aliases: HashMap<InterfaceId, String>,
     imported_builtins: HashSet<&'static str>,
-    pub exported_symbols: HashMap<String, (String, bool)>,
+    uses_runtime_package: bool,
+    module_docs: HashMap<String, String>,
+    /// The MoonBit names of types and the packages they're defined in.
+    type_names: HashMap<TypeId, (String, String)>,
+    used_type_names: HashMap<String, HashSet<String>>,
+    used_value_names: HashSet<String>,
+    /// The exported functions, filled in by `generate()`.
+    pub manifest: Manifest,
 }
 
 #[derive(Clone, Eq)]
 struct ModuleName {
     snake: String,
     qual: String,
+    /// The snake-case path of the module, used to prefix FFI functions.
+    ffi: String,
 }
 
 impl ModuleName {
     fn root(name: impl AsRef<str>) -> Self {
-        let name = to_mbt_ident(name.as_ref());
+        let name = name.as_ref();
         Self {
-            snake: name.to_snake_case(),
-            qual: name.to_upper_camel_case(),
+            snake: to_mbt_ident(name),
+            qual: to_qual_case(name),
+            ffi: name.to_snake_case(),
         }
     }
 
     fn child(&self, name: impl AsRef<str>) -> Self {
-        let name = to_mbt_ident(name.as_ref());
+        let name = name.as_ref();
         Self {
-            snake: name.to_snake_case(),
-            qual: format!("{}{}", self.qual, name.to_upper_camel_case()),
+            snake: to_mbt_ident(name),
+            qual: format!("{}{}", self.qual, to_qual_case(name)),
+            ffi: format!("{}_{}", self.ffi, name.to_snake_case()),
         }
     }
 }
@@ -151,31 +178,12 @@ fn parse_with(s: &str) -> Result<HashMap<String, String>, String> {
 #[derive(Default, Debug, Clone)]
 #[cfg_attr(feature = "clap", derive(clap::Args))]
 pub struct Opts {
-    /// If true, code generation should qualify any features that depend on
-    /// `std` with `cfg(feature = "std")`.
-    #[cfg_attr(feature = "clap", arg(long))]
-    pub std_feature: bool,
-
-    /// If true, code generation should pass borrowed string arguments as
-    /// `&[u8]` instead of `&str`. Strings are still required to be valid
-    /// UTF-8, but this avoids the need for Rust code to do its own UTF-8
-    /// validation if it doesn't already have a `&str`.
-    #[cfg_attr(feature = "clap", arg(long))]
-    pub raw_strings: bool,
-
     /// Names of functions to skip generating bindings for.
     #[cfg_attr(feature = "clap", arg(long))]
     pub skip: Vec<String>,
 
-    /// Names of the concrete types which implement the traits representing any
-    /// functions, interfaces, and/or resources exported by the world.
-    ///
-    /// Example: `--exports world=MyWorld,ns:pkg/iface1=MyIface1,ns:pkg/iface1/resource1=MyResource1`,
-    #[cfg_attr(feature = "clap", arg(long, value_parser = parse_exports, default_value = ""))]
-    pub exports: HashMap<ExportKey, String>,
-
-    /// If true, generate stub implementations for any exported functions,
-    /// interfaces, and/or resources.
+    /// If true, generate a `stub.mbt` file with a `Stub` implementation of
+    /// any exported functions, interfaces, and/or resources.
     #[cfg_attr(feature = "clap", arg(long))]
     pub stubs: bool,
 
@@ -185,52 +193,120 @@ pub struct Opts {
     #[cfg_attr(feature = "clap", arg(long))]
     pub export_prefix: Option<String>,
 
-    /// Whether to generate owning or borrowing type definitions.
+    /// The optional MoonBit package providing the `malloc_aligned(size, align)`
+    /// and `free` allocation helpers, e.g. `username/runtime`.
     ///
-    /// Valid values include:
-    ///
-    /// - `owning`: Generated types will be composed entirely of owning fields,
-    /// regardless of whether they are used as parameters to imports or not.
-    ///
-    /// - `borrowing`: Generated types used as parameters to imports will be
-    /// "deeply borrowing", i.e. contain references rather than owned values
-    /// when applicable.
+    /// This defaults to the `$rael.malloc_aligned` and `$rael.free` builtins
+    /// linked by `componentize`.
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub runtime_package: Option<String>,
+
+    /// Additional traits to derive for generated structs and enums. If using
+    /// in a CLI, this flag can be specified multiple times or with a list.
     ///
-    /// - `borrowing-duplicate-if-necessary`: As above, but generating distinct
-    /// types for borrowing and owning, if necessary.
-    #[cfg_attr(feature = "clap", arg(long, default_value_t = Ownership::Owning))]
-    pub ownership: Ownership,
+    /// Example: `--derive Show,Eq`
+    #[cfg_attr(feature = "clap", arg(long, value_delimiter = ','))]
+    pub derive: Vec<String>,
 
-    /// The optional path to the wit-bindgen runtime module to use.
+    /// If true, generate one MoonBit package per imported interface instead of
+    /// nesting all of them in the world's `.mbt` file.
     ///
-    /// This defaults to `wit_bindgen::rt`.
+    /// Each package is written to `<namespace>/<package>/<interface>` under
+    /// the output directory with its own `moon.pkg.json`, and the world's
+    /// package gets a `moon.pkg.json` importing all of them.
     #[cfg_attr(feature = "clap", arg(long))]
-    pub runtime_path: Option<String>,
+    pub multi_package: bool,
 
-    /// The optional path to the bitflags crate to use.
-    ///
-    /// This defaults to `wit_bindgen::bitflags`.
+    /// The MoonBit package path of the output directory, e.g.
+    /// `username/hello/main`, used to import the packages generated with
+    /// `--multi-package`.
     #[cfg_attr(feature = "clap", arg(long))]
-    pub bitflags_path: Option<String>,
+    pub package_path: Option<String>,
 
-    /// Additional derive attributes to add to generated types. If using in a CLI, this flag can be
-    /// specified multiple times to add multiple attributes.
+    /// Remapping of imported interface names to existing MoonBit packages.
     ///
-    /// These derive attributes will be added to any generated structs or enums
-    #[cfg_attr(feature = "clap", arg(long = "additional_derive_attribute", short = 'd', default_values_t = Vec::<String>::new()))]
-    pub additional_derive_attributes: Vec<String>,
-
-    /// Remapping of interface names to rust module names.
+    /// No bindings are generated for a remapped interface; its types and
+    /// functions are referenced from the given package instead, which is
+    /// added to the imports of the generated `moon.pkg.json`.
+    ///
+    /// Example: `--with wasi:io/streams=@wasi_io/streams`
     #[cfg_attr(feature = "clap", arg(long, value_parser = parse_with, default_value = ""))]
     pub with: HashMap<String, String>,
+
+    /// If true, format the generated MoonBit code in the style of `moon fmt`:
+    /// no trailing spaces, no leading or repeated blank lines, no blank lines
+    /// at the start or end of a block, annotations spaced as `name : Type`
+    /// and no semicolons at the end of lines.
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub format: bool,
+
+    // Options of the Rust generator without a MoonBit equivalent. They are
+    // only accepted to be rejected with a helpful error in `build()`. The
+    // implementation of the exports is chosen by calling `init_guest()`, and
+    // MoonBit strings and types are always owned.
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    raw_strings: bool,
+    #[cfg_attr(feature = "clap", arg(long, hide = true, default_value_t = Ownership::Owning))]
+    ownership: Ownership,
+    #[cfg_attr(feature = "clap", arg(long, hide = true, value_parser = parse_exports, default_value = ""))]
+    exports: HashMap<ExportKey, String>,
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    std_feature: bool,
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    runtime_path: Option<String>,
+    #[cfg_attr(feature = "clap", arg(long, hide = true))]
+    bitflags_path: Option<String>,
+    #[cfg_attr(
+        feature = "clap",
+        arg(long = "additional_derive_attribute", short = 'd', hide = true)
+    )]
+    additional_derive_attributes: Vec<String>,
 }
 
 impl Opts {
-    pub fn build(self) -> Box<dyn WorldGenerator> {
+    pub fn build(self) -> Result<Box<dyn WorldGenerator>> {
+        let rust_only = [
+            (
+                !self.exports.is_empty(),
+                "exports",
+                Some("call `init_guest()` with the implementation instead"),
+            ),
+            (self.std_feature, "std-feature", None),
+            (self.raw_strings, "raw-strings", None),
+            (
+                !matches!(self.ownership, Ownership::Owning),
+                "ownership",
+                None,
+            ),
+            (
+                self.runtime_path.is_some(),
+                "runtime-path",
+                Some("use `--runtime-package` instead"),
+            ),
+            (self.bitflags_path.is_some(), "bitflags-path", None),
+            (
+                !self.additional_derive_attributes.is_empty(),
+                "additional_derive_attribute",
+                Some("use `--derive` instead"),
+            ),
+        ];
+        for (used, name, hint) in rust_only {
+            match (used, hint) {
+                (false, _) => {}
+                (true, Some(hint)) => {
+                    bail!("`--{name}` is only supported by the Rust generator, {hint}")
+                }
+                (true, None) => bail!("`--{name}` is only supported by the Rust generator"),
+            }
+        }
+        if self.multi_package && self.package_path.is_none() {
+            bail!("`package_path` must be provided to generate multiple packages");
+        }
+
         let mut r = MoonBit::new();
         r.skip = self.skip.iter().cloned().collect();
         r.opts = self;
-        Box::new(r)
+        Ok(Box::new(r))
     }
 }
 
@@ -279,7 +355,7 @@ impl MoonBit {
             }
             cur.contents.push(module);
         }
-        let inits = emit(&mut self.src, map, in_import);
+        let inits = emit(&mut self.src, map, in_import, &self.module_docs);
         if in_import {
             for (snake, init) in inits {
                 uwriteln!(
@@ -290,10 +366,20 @@ impl MoonBit {
                 );
             }
         }
-        fn emit(me: &mut Source, module: Module, in_import: bool) -> Vec<(ModuleName, String)> {
+        fn emit(
+            me: &mut Source,
+            module: Module,
+            in_import: bool,
+            docs: &HashMap<String, String>,
+        ) -> Vec<(ModuleName, String)> {
             let mut rv = Vec::new();
             for (name, submodule) in module.submodules {
                 if in_import {
+                    if let Some(docs) = docs.get(&name.qual) {
+                        for line in docs.trim().lines() {
+                            uwriteln!(me, "/// {line}");
+                        }
+                    }
                     if submodule.submodules.is_empty() {
                         uwriteln!(me, "pub(readonly) type {} Unit", name.qual);
                     } else {
@@ -306,7 +392,7 @@ impl MoonBit {
                     uwriteln!(me, "");
                 }
 
-                let sub_inits = emit(me, submodule, in_import);
+                let sub_inits = emit(me, submodule, in_import, docs);
                 if in_import {
                     let init = if sub_inits.is_empty() {
                         format!("{}(())", name.qual)
@@ -328,35 +414,25 @@ impl MoonBit {
         }
     }
 
-    fn runtime_path(&self) -> &str {
-        self.opts
-            .runtime_path
-            .as_deref()
-            .unwrap_or("wit_bindgen::rt")
-    }
-
-    fn bitflags_path(&self) -> &str {
-        self.opts
-            .bitflags_path
-            .as_deref()
-            .unwrap_or("wit_bindgen::bitflags")
-    }
-
-    fn lookup_export(&self, key: &ExportKey) -> Result<String> {
-        if let Some(key) = self.opts.exports.get(key) {
-            return Ok(key.clone());
-        }
-        if self.opts.stubs {
-            return Ok("Stub".to_owned());
-        }
-        let key = match key {
-            ExportKey::World => "world".to_owned(),
-            ExportKey::Name(name) => format!("\"{name}\""),
-        };
-        if self.opts.exports.is_empty() {
-            bail!("no `exports` map provided in configuration - provide an `exports` map a key `{key}`")
+    /// Returns the function to call for the allocation helper `name` (either
+    /// `malloc` or `free`) from the runtime package, falling back to the
+    /// `$rael` builtins.
+    fn runtime_func(&mut self, name: &str) -> String {
+        match &self.opts.runtime_package {
+            Some(package) => {
+                self.uses_runtime_package = true;
+                format!("@{}.{name}", package.rsplit('/').next().unwrap())
+            }
+            None => {
+                let builtin = match name {
+                    "malloc_aligned" => "_rael_malloc_aligned",
+                    "free" => "_rael_free",
+                    _ => unreachable!(),
+                };
+                self.imported_builtins.insert(builtin);
+                builtin.to_string()
+            }
         }
-        bail!("expected `exports` map to contain key `{key}`")
     }
 
     fn name_interface(
@@ -367,13 +443,20 @@ impl MoonBit {
         is_export: bool,
     ) -> bool {
         let with_name = resolve.name_world_key(name);
-        let entry = if let Some(remapped_path) = self.opts.with.get(&with_name) {
-            let name = format!("__with_name{}", self.with_name_counter);
-            self.with_name_counter += 1;
-            uwriteln!(self.src, "use {remapped_path} as {name};");
+        let remapped_path = self.opts.with.get(&with_name).filter(|_| !is_export);
+        let entry = if let Some(remapped_path) = remapped_path {
+            let path = remapped_path.trim_start_matches('@');
             InterfaceName {
                 remapped: true,
-                path: name,
+                path: path.to_string(),
+                alias: Some(path.rsplit('/').next().unwrap().to_string()),
+            }
+        } else if let Some(alias) = self.package_aliases.get(&id).filter(|_| !is_export) {
+            let package_path = self.opts.package_path.as_deref().unwrap_or_default();
+            InterfaceName {
+                remapped: false,
+                path: format!("{package_path}/{}", package_dir(name, resolve)),
+                alias: Some(alias.clone()),
             }
         } else {
             let path = compute_module_path(name, resolve, is_export)
@@ -385,14 +468,331 @@ impl MoonBit {
             InterfaceName {
                 remapped: false,
                 path,
+                alias: None,
             }
         };
 
         let remapped = entry.remapped;
-        self.interface_names.insert(id, entry);
+        if !remapped {
+            let scope = match &entry.alias {
+                Some(_) => package_dir(name, resolve),
+                None => {
+                    let root = self.used_type_names.entry(String::new()).or_default();
+                    root.extend(
+                        compute_module_path(name, resolve, is_export)
+                            .into_iter()
+                            .map(|n| n.qual),
+                    );
+                    String::new()
+                }
+            };
+            let iface = &resolve.interfaces[id];
+            let mut qualifier = iface.name.clone().unwrap_or_default();
+            if let Some(pkg) = iface.package {
+                // Types of different versions of a package are told apart by
+                // the version rather than by the interface name they share.
+                let module = name_package_module(resolve, pkg);
+                if module != resolve.packages[pkg].name.name.to_snake_case() {
+                    qualifier = format!("{module}_{qualifier}");
+                }
+            }
+            self.name_types(resolve, iface.types.values().copied(), &scope, &qualifier);
+        }
+        if is_export {
+            self.export_interface_names.insert(id, entry);
+        } else {
+            self.interface_names.insert(id, entry);
+        }
 
         remapped
     }
+
+    /// Assigns the MoonBit names of `types`, unique within the package
+    /// `scope`. A name that is already taken is qualified with `qualifier`,
+    /// then suffixed with a number, so the result only depends on the order
+    /// of the world's items.
+    ///
+    /// A type `use`d from an interface in the same package shares the name
+    /// of the original type instead of getting a `typealias`.
+    fn name_types(
+        &mut self,
+        resolve: &Resolve,
+        types: impl Iterator<Item = TypeId>,
+        scope: &str,
+        qualifier: &str,
+    ) {
+        let used = self.used_type_names.entry(scope.to_string()).or_default();
+        for id in types {
+            let ty = &resolve.types[id];
+            if let TypeDefKind::Type(Type::Id(target)) = ty.kind {
+                match self.type_names.get(&target) {
+                    Some((name, target_scope))
+                        if target_scope == scope && ty.name == resolve.types[target].name =>
+                    {
+                        let entry = (name.clone(), scope.to_string());
+                        self.type_names.insert(id, entry);
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            let name = to_upper_camel_case(ty.name.as_deref().unwrap());
+            let qualified = format!("{}{name}", to_qual_case(&qualifier.to_snake_case()));
+            let name = unique_name(used, &[name, qualified]);
+            self.type_names.insert(id, (name, scope.to_string()));
+        }
+    }
+
+    /// Adds the MoonBit source file `path`, formatted if `Opts::format` is set.
+    fn push_mbt(&self, files: &mut Files, path: &str, src: &str) {
+        if self.opts.format {
+            files.push(path, format::format(src).as_bytes());
+        } else {
+            files.push(path, src.as_bytes());
+        }
+    }
+
+    /// Returns the MoonBit name of the type `id`.
+    fn type_name(&self, resolve: &Resolve, id: TypeId) -> String {
+        match self.type_names.get(&id) {
+            Some((name, _)) => name.clone(),
+            None => to_upper_camel_case(resolve.types[id].name.as_deref().unwrap()),
+        }
+    }
+
+    /// Returns a name for a top-level MoonBit function derived from `name`,
+    /// qualified with `qualifier` if it would collide with another one.
+    fn unique_value_name(&mut self, name: &str, qualifier: Option<&str>) -> String {
+        let name = to_mbt_ident(name);
+        let mut candidates = vec![name.clone()];
+        candidates.extend(qualifier.map(|q| format!("{}_{name}", q.to_snake_case())));
+        unique_name(&mut self.used_value_names, &candidates)
+    }
+
+    /// Assigns every imported interface a package alias that is unique within
+    /// the world, starting from the interface name and qualifying it with the
+    /// package and namespace names when they collide.
+    fn assign_package_aliases(&mut self, resolve: &Resolve, world: WorldId) {
+        let remapped = self
+            .opts
+            .with
+            .values()
+            .map(|path| path.rsplit('/').next().unwrap().to_string())
+            .collect::<Vec<_>>();
+        let candidates = resolve.worlds[world]
+            .imports
+            .iter()
+            .filter_map(|(name, item)| match item {
+                WorldItem::Interface(id)
+                    if !self.opts.with.contains_key(&resolve.name_world_key(name)) =>
+                {
+                    Some((*id, package_dir(name, resolve)))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        for depth in 1.. {
+            let aliases = candidates
+                .iter()
+                .filter(|(id, _)| !self.package_aliases.contains_key(id))
+                .map(|(id, dir)| {
+                    let parts = dir.split('/').collect::<Vec<_>>();
+                    let start = parts.len().saturating_sub(depth);
+                    (*id, parts[start..].join("_"), start == 0)
+                })
+                .collect::<Vec<_>>();
+            if aliases.is_empty() {
+                break;
+            }
+            for (id, alias, exhausted) in aliases.iter() {
+                let taken =
+                    self.package_aliases.values().any(|a| a == alias) || remapped.contains(alias);
+                let unique = aliases.iter().filter(|(_, a, _)| a == alias).count() == 1;
+                if *exhausted || (unique && !taken) {
+                    self.package_aliases.insert(*id, alias.clone());
+                }
+            }
+        }
+    }
+
+    fn import_interface_package(
+        &mut self,
+        resolve: &Resolve,
+        name: &WorldKey,
+        id: InterfaceId,
+        files: &mut Files,
+    ) {
+        if self.name_interface(resolve, id, name, false) {
+            return;
+        }
+        let prev_builtins = mem::take(&mut self.imported_builtins);
+        let prev_uses_runtime_package = mem::take(&mut self.uses_runtime_package);
+        let wasm_import_module = resolve.name_world_key(name);
+        let mut gen = self.interface(
+            Identifier::Interface(id, name),
+            Some(&wasm_import_module),
+            resolve,
+            true,
+        );
+        gen.types(id);
+        gen.generate_imports(resolve.interfaces[id].functions.values(), None);
+        let body = gen.finish();
+
+        let mut src = Source::default();
+        wit_bindgen_core::generated_preamble(&mut src, env!("CARGO_PKG_VERSION"));
+        uwriteln!(src, "");
+        src.push_str(&body);
+        let builtins = mem::replace(&mut self.imported_builtins, prev_builtins);
+        emit_builtins(&mut src, &builtins);
+
+        let dir = package_dir(name, resolve);
+        let file = dir.rsplit('/').next().unwrap();
+        self.push_mbt(files, &format!("{dir}/{file}.mbt"), &src);
+        let mut imports = interface_deps(resolve, id)
+            .into_iter()
+            .filter_map(|dep| self.interface_names.get(&dep))
+            .map(|name| (name.path.as_str(), name.alias.as_deref()))
+            .collect::<Vec<_>>();
+        if mem::replace(&mut self.uses_runtime_package, prev_uses_runtime_package) {
+            imports.extend(self.opts.runtime_package.as_deref().map(|p| (p, None)));
+        }
+        files.push(
+            &format!("{dir}/moon.pkg.json"),
+            moon_pkg_json(&imports).as_bytes(),
+        );
+    }
+}
+
+/// Returns the directory, relative to the output directory, of the MoonBit
+/// package generated for the interface `name` in `--multi-package` mode.
+fn package_dir(name: &WorldKey, resolve: &Resolve) -> String {
+    match name {
+        WorldKey::Name(name) => name.to_snake_case(),
+        WorldKey::Interface(id) => {
+            let iface = &resolve.interfaces[*id];
+            let pkg = iface.package.unwrap();
+            format!(
+                "{}/{}/{}",
+                resolve.packages[pkg].name.namespace.to_snake_case(),
+                name_package_module(resolve, pkg),
+                iface.name.as_ref().unwrap().to_snake_case(),
+            )
+        }
+    }
+}
+
+/// Returns the other interfaces whose types are `use`d by the interface `id`.
+fn interface_deps(resolve: &Resolve, id: InterfaceId) -> BTreeSet<InterfaceId> {
+    resolve.interfaces[id]
+        .types
+        .values()
+        .filter_map(|ty| match resolve.types[*ty].kind {
+            TypeDefKind::Type(Type::Id(other)) => match resolve.types[other].owner {
+                TypeOwner::Interface(dep) if dep != id => Some(dep),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+fn moon_pkg_json(imports: &[(&str, Option<&str>)]) -> String {
+    let mut json = String::from("{\n  \"import\": [");
+    for (i, (path, alias)) in imports.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        match alias {
+            Some(alias) => uwrite!(
+                json,
+                "\n    {{ \"path\": \"{path}\", \"alias\": \"{alias}\" }}"
+            ),
+            None => uwrite!(json, "\n    \"{path}\""),
+        }
+    }
+    if !imports.is_empty() {
+        json.push_str("\n  ");
+    }
+    json.push_str("]\n}\n");
+    json
+}
+
+/// The version of the componentize runtime that provides `BUILTINS`, also
+/// used by `componentize` for its `runtime.wat`: bump it when builtins are
+/// added there.
+pub const RUNTIME_VERSION: u32 = 2;
+
+/// FFI declarations of the builtins provided by `componentize`.
+const BUILTINS: &[(&str, &str)] = &[
+    (
+        "_mbt_string_data",
+        "(s: String) -> Int = \"$moonbit.string_data\"",
+    ),
+    (
+        "_mbt_unsafe_make_string",
+        "(len: Int, val: Int) -> String = \"$moonbit.unsafe_make_string\"",
+    ),
+    (
+        "_rael_bytes_data",
+        "(b: Bytes) -> Int = \"$rael.bytes_data\"",
+    ),
+    ("_rael_free", "(ptr: Int) = \"$rael.free\""),
+    ("_rael_load_f32", "(ptr: Int) -> Float = \"$rael.load_f32\""),
+    (
+        "_rael_load_f64",
+        "(ptr: Int) -> Float64 = \"$rael.load_f64\"",
+    ),
+    ("_rael_load_i16", "(ptr: Int) -> Int = \"$rael.load_i16\""),
+    ("_rael_load_i32", "(ptr: Int) -> Int = \"$rael.load_i32\""),
+    ("_rael_load_i64", "(ptr: Int) -> Int64 = \"$rael.load_i64\""),
+    ("_rael_load_i8", "(ptr: Int) -> Int = \"$rael.load_i8\""),
+    ("_rael_load_u16", "(ptr: Int) -> Int = \"$rael.load_u16\""),
+    ("_rael_load_u8", "(ptr: Int) -> Int = \"$rael.load_u8\""),
+    (
+        "_rael_malloc_aligned",
+        "(size: Int, align: Int) -> Int = \"$rael.malloc_aligned\"",
+    ),
+    (
+        "_rael_memory_copy",
+        "(dst: Int, src: Int, len: Int) = \"$rael.memory_copy\"",
+    ),
+    (
+        "_rael_store_f32",
+        "(ptr: Int, val: Float) = \"$rael.store_f32\"",
+    ),
+    (
+        "_rael_store_f64",
+        "(ptr: Int, val: Float64) = \"$rael.store_f64\"",
+    ),
+    (
+        "_rael_store_i16",
+        "(ptr: Int, val: Int) = \"$rael.store_i16\"",
+    ),
+    (
+        "_rael_store_i32",
+        "(ptr: Int, val: Int) = \"$rael.store_i32\"",
+    ),
+    (
+        "_rael_store_i64",
+        "(ptr: Int, val: Int64) = \"$rael.store_i64\"",
+    ),
+    (
+        "_rael_store_i8",
+        "(ptr: Int, val: Int) = \"$rael.store_i8\"",
+    ),
+];
+
+fn emit_builtins(src: &mut Source, builtins: &HashSet<&'static str>) {
+    let mut first = true;
+    for (builtin, def) in BUILTINS {
+        if !builtins.contains(builtin) {
+            continue;
+        }
+        if !mem::take(&mut first) {
+            uwriteln!(src, "");
+        }
+        uwriteln!(src, "fn {builtin}{def}");
+    }
 }
 
 /// If the package `id` is the only package with its namespace/name combo
@@ -426,22 +826,46 @@ fn name_package_module(resolve: &Resolve, id: PackageId) -> String {
     };
 
     // Here there's multiple packages with the same name that differ only in
-    // version, so the version needs to be mangled into the Rust module name
-    // that we're generating. This in theory could look at all of
-    // `versions_with_same_name` and produce a minimal diff, e.g. for 0.1.0
-    // and 0.2.0 this could generate "foo1" and "foo2", but for now
-    // a simpler path is chosen to generate "foo0_1_0" and "foo0_2_0".
+    // version, so the version needs to be mangled into the MoonBit names
+    // that we're generating. The full version is kept so that, e.g., 0.2.1
+    // and 0.21.0 can't collide: `io@0.2.1` becomes "io_v0_2_1", and its
+    // module type `IoV0_2_1` (see `to_qual_case`).
     let version = version
         .to_string()
         .replace(['.', '-', '+'], "_")
-        .to_snake_case();
-    format!("{base}{version}")
+        .to_lowercase();
+    format!("{base}_v{version}")
+}
+
+/// Converts a snake-case module name to the UpperCamelCase used for the
+/// module types, keeping an underscore between adjacent numbers so that
+/// versions stay readable, e.g. "io_v0_2_1" becomes "IoV0_2_1".
+fn to_qual_case(name: &str) -> String {
+    let mut qual = String::new();
+    for word in name.split('_').filter(|w| !w.is_empty()) {
+        if qual.ends_with(|c: char| c.is_ascii_digit())
+            && word.starts_with(|c: char| c.is_ascii_digit())
+        {
+            qual.push('_');
+        }
+        qual.push_str(&word.to_upper_camel_case());
+    }
+    qual
 }
 
 impl WorldGenerator for MoonBit {
-    fn preprocess(&mut self, resolve: &Resolve, _world: WorldId) {
+    fn preprocess(&mut self, resolve: &Resolve, world: WorldId) {
         wit_bindgen_core::generated_preamble(&mut self.src, env!("CARGO_PKG_VERSION"));
         self.types.analyze(resolve);
+        self.used_value_names.extend(
+            MBT_GENERATED_VALUES
+                .iter()
+                .chain(BUILTINS.iter().map(|(name, _)| name))
+                .map(|name| name.to_string()),
+        );
+        if self.opts.multi_package {
+            self.assign_package_aliases(resolve, world);
+        }
     }
 
     fn import_interface(
@@ -449,8 +873,12 @@ impl WorldGenerator for MoonBit {
         resolve: &Resolve,
         name: &WorldKey,
         id: InterfaceId,
-        _files: &mut Files,
+        files: &mut Files,
     ) {
+        if self.opts.multi_package {
+            self.import_interface_package(resolve, name, id, files);
+            return;
+        }
         let wasm_import_module = resolve.name_world_key(name);
         let mut gen = self.interface(
             Identifier::Interface(id, name),
@@ -462,6 +890,10 @@ impl WorldGenerator for MoonBit {
         if gen.gen.name_interface(resolve, id, name, false) {
             return;
         }
+        if let Some(docs) = &resolve.interfaces[id].docs.contents {
+            let qual = module_path.last().unwrap().qual.clone();
+            gen.gen.module_docs.insert(qual, docs.clone());
+        }
         gen.types(id);
 
         gen.generate_imports(
@@ -531,6 +963,8 @@ impl WorldGenerator for MoonBit {
         types: &[(&str, TypeId)],
         _files: &mut Files,
     ) {
+        let world_name = resolve.worlds[world].name.clone();
+        self.name_types(resolve, types.iter().map(|(_, ty)| *ty), "", &world_name);
         let mut gen = self.interface(Identifier::World(world), Some("$root"), resolve, true);
         for (name, ty) in types {
             gen.define_type(name, *ty);
@@ -557,56 +991,52 @@ impl WorldGenerator for MoonBit {
         self.emit_modules(exports, false);
 
         if self.opts.stubs {
-            self.src.push_str("\n#[derive(Debug)]\npub struct Stub;\n");
+            let mut stub = Source::default();
+            uwriteln!(stub, "struct Stub {{}}");
+            uwriteln!(stub, "");
             let world_id = world;
             let world = &resolve.worlds[world];
             let mut funcs = Vec::new();
             for (name, export) in world.exports.iter() {
-                let (pkg, name) = match name {
-                    WorldKey::Name(name) => (None, name),
-                    WorldKey::Interface(id) => {
-                        let interface = &resolve.interfaces[*id];
-                        (
-                            Some(interface.package.unwrap()),
-                            interface.name.as_ref().unwrap(),
-                        )
-                    }
-                };
                 match export {
                     WorldItem::Function(func) => {
                         funcs.push(func);
                     }
                     WorldItem::Interface(id) => {
+                        let module_path = compute_module_path(name, resolve, true);
                         for (resource, funcs) in
                             group_by_resource(resolve.interfaces[*id].functions.values())
                         {
                             let mut gen =
                                 self.interface(Identifier::World(world_id), None, resolve, false);
-                            let pkg = pkg.map(|pid| {
-                                let namespace = resolve.packages[pid].name.namespace.clone();
-                                let package_module = name_package_module(resolve, pid);
-                                (namespace, package_module)
-                            });
-                            gen.generate_stub(resource, pkg, name, true, &funcs);
-                            let stub = gen.finish();
-                            self.src.push_str(&stub);
+                            gen.generate_stub(resource, module_path.last(), &funcs);
+                            stub.push_str(&gen.finish());
                         }
                     }
-                    WorldItem::Type(_) => unreachable!(),
+                    // Types of a world are imported, there's nothing to implement.
+                    WorldItem::Type(_) => {}
                 }
             }
 
             for (resource, funcs) in group_by_resource(funcs.into_iter()) {
                 let mut gen = self.interface(Identifier::World(world_id), None, resolve, false);
-                gen.generate_stub(resource, None, &world.name, false, &funcs);
-                let stub = gen.finish();
-                self.src.push_str(&stub);
+                gen.generate_stub(resource, None, &funcs);
+                stub.push_str(&gen.finish());
             }
+
+            uwriteln!(stub, "fn init {{");
+            uwriteln!(stub, "init_guest(Stub::{{}})");
+            uwriteln!(stub, "}}");
+            self.push_mbt(files, "stub.mbt", &stub);
         }
 
         uwriteln!(self.src, "struct GuestImpl {{");
         for (trait_name, field) in self.export_traits.iter() {
-            uwriteln!(self.src, "mut {field}: Option[{trait_name}]");
+            if self.export_resources.contains_key(trait_name) {
+                uwriteln!(self.src, "mut {field}: Option[{trait_name}Funcs]");
+            } else {
+                uwriteln!(self.src, "mut {field}: Option[{trait_name}]");
+            }
         }
         uwriteln!(self.src, "}} derive(Default)");
         uwriteln!(self.src, "");
@@ -616,42 +1046,54 @@ impl WorldGenerator for MoonBit {
         self.src.push_str(
             &self
                 .export_traits
-                .keys()
-                .map(String::as_str)
+                .iter()
+                .map(|(trait_name, _)| trait_name.as_str())
                 .collect::<Vec<_>>()
                 .join(" + "),
         );
         uwriteln!(self.src, "](guest: T) -> Unit {{");
         for (trait_name, field) in self.export_traits.iter() {
-            uwriteln!(self.src, "guest_impl.{field} = Some(guest as {trait_name})");
+            match self.export_resources.get(trait_name) {
+                // The values of a resource are kept by their rep.
+                Some(closures) => {
+                    uwriteln!(self.src, "let {field}: Array[T] = []");
+                    uwriteln!(self.src, "guest_impl.{field} = Some({trait_name}Funcs::{{");
+                    for closure in closures {
+                        self.src.push_str(closure);
+                    }
+                    uwriteln!(self.src, "}})");
+                }
+                None => uwriteln!(self.src, "guest_impl.{field} = Some(guest as {trait_name})"),
+            }
         }
         uwriteln!(self.src, "}}\n");
 
-        let mut builtins = self.imported_builtins.iter().collect::<Vec<_>>();
-        builtins.sort();
-        for (i, builtin) in builtins.into_iter().enumerate() {
-            if i > 0 {
-                uwriteln!(self.src, "");
-            }
-            let def = match *builtin {
-                "_rael_malloc" => "(size: Int) -> Int = \"$rael.malloc\"",
-                "_rael_free" => "(ptr: Int) = \"$rael.free\"",
-                "_rael_memory_copy" => "(dst: Int, src: Int, len: Int) = \"$rael.memory_copy\"",
-                "_rael_load_i32" => "(ptr: Int) -> Int = \"$rael.load_i32\"",
-                "_rael_load_i64" => "(ptr: Int) -> Int64 = \"$rael.load_i64\"",
-                "_rael_bytes_data" => "(b: Bytes) -> Int = \"$rael.bytes_data\"",
-                "_mbt_string_data" => "(s: String) -> Int = \"$moonbit.string_data\"",
-                "_mbt_unsafe_make_string" => {
-                    "(len: Int, val: Int) -> String = \"$moonbit.unsafe_make_string\""
-                }
-                _ => unreachable!(),
-            };
-            uwriteln!(self.src, "fn {builtin}{def}");
+        emit_builtins(&mut self.src, &self.imported_builtins);
+
+        let mut imports = self
+            .interface_names
+            .values()
+            .filter(|name| name.alias.is_some())
+            .map(|name| (name.path.as_str(), name.alias.as_deref()))
+            .collect::<Vec<_>>();
+        imports.sort_by(|a, b| a.1.cmp(&b.1));
+        if self.uses_runtime_package {
+            imports.extend(self.opts.runtime_package.as_deref().map(|p| (p, None)));
+        }
+        if self.opts.multi_package || !imports.is_empty() {
+            files.push("moon.pkg.json", moon_pkg_json(&imports).as_bytes());
         }
 
         let src = mem::take(&mut self.src);
         let module_name = name.to_snake_case();
-        files.push(&format!("{module_name}.mbt"), src.as_bytes());
+        self.push_mbt(files, &format!("{module_name}.mbt"), &src);
+
+        self.manifest.world = name.clone();
+        self.manifest.runtime = RUNTIME_VERSION;
+        files.push(
+            &Manifest::file_name(name),
+            self.manifest.to_json().as_bytes(),
+        );
     }
 }
 
@@ -771,49 +1213,165 @@ struct FnSig {
     unsafe_: bool,
     private: bool,
     use_item_name: bool,
+    qualifier: Option<String>,
     generics: Option<String>,
     self_arg: Option<String>,
     self_is_first_param: bool,
     is_trait: bool,
 }
 
+/// MoonBit keywords and reserved words, which can't be used as identifiers.
+///
+/// Source: https://www.moonbitlang.com/docs/syntax
+const MBT_KEYWORDS: &[&str] = &[
+    "as",
+    "async",
+    "break",
+    "catch",
+    "const",
+    "continue",
+    "derive",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "fn",
+    "fnalias",
+    "for",
+    "guard",
+    "if",
+    "impl",
+    "import",
+    "in",
+    "is",
+    "let",
+    "loop",
+    "match",
+    "mut",
+    "priv",
+    "pub",
+    "raise",
+    "readonly",
+    "return",
+    "self",
+    "struct",
+    "test",
+    "throw",
+    "trait",
+    "traitalias",
+    "true",
+    "try",
+    "type",
+    "typealias",
+    "while",
+    "with",
+    // Reserved for future use.
+    "module",
+    "move",
+    "ref",
+    "static",
+    "super",
+    "unsafe",
+    "use",
+    "where",
+    "await",
+    "dyn",
+    "abstract",
+    "do",
+    "final",
+    "macro",
+    "override",
+    "typeof",
+    "virtual",
+    "yield",
+    "local",
+    "method",
+    "alias",
+    "package",
+    "include",
+];
+
+/// Top-level values defined by the generated bindings themselves.
+const MBT_GENERATED_VALUES: &[&str] = &["guest_impl", "init_guest"];
+
+/// Types in the MoonBit prelude, and types defined by the generated bindings
+/// themselves. The name "Guest" is reserved for traits generated by exported
+/// interfaces.
+const MBT_RESERVED_TYPES: &[&str] = &[
+    "Array",
+    "Bool",
+    "Byte",
+    "Bytes",
+    "Char",
+    "Compare",
+    "Default",
+    "Double",
+    "Eq",
+    "Error",
+    "FixedArray",
+    "Float",
+    "Float64",
+    "Guest",
+    "GuestImpl",
+    "Hash",
+    "Int",
+    "Int64",
+    "Iter",
+    "Json",
+    "Map",
+    "Option",
+    "Ref",
+    "Result",
+    "Self",
+    "Show",
+    "String",
+    "Stub",
+    "UInt",
+    "UInt64",
+    "Unit",
+];
+
+/// Constructors in the MoonBit prelude which would be shadowed by enum and
+/// variant cases of the same name.
+const MBT_RESERVED_CASES: &[&str] = &["Err", "None", "Ok", "Some"];
+
 pub fn to_mbt_ident(name: &str) -> String {
-    match name {
-        // Escape MoonBit keywords.
-        // Source: https://www.moonbitlang.com/docs/syntax
-        "as" => "as_".into(),
-        "break" => "break_".into(),
-        "continue" => "continue_".into(),
-        "else" => "else_".into(),
-        "enum" => "enum_".into(),
-        "false" => "false_".into(),
-        "fn" => "fn_".into(),
-        "if" => "if_".into(),
-        "loop" => "loop_".into(),
-        "match" => "match_".into(),
-        "mut" => "mut_".into(),
-        "pub" => "pub_".into(),
-        "return" => "return_".into(),
-        "self" => "self_".into(),
-        "struct" => "struct_".into(),
-        "trait" => "trait_".into(),
-        "true" => "true_".into(),
-        "type" => "type_".into(),
-        "while" => "while_".into(),
-        "priv" => "priv_".into(),
-        s => s.to_snake_case(),
+    let ident = name.to_snake_case();
+    if MBT_KEYWORDS.contains(&ident.as_str()) || MBT_GENERATED_VALUES.contains(&ident.as_str()) {
+        format!("{ident}_")
+    } else {
+        ident
     }
 }
 
 fn to_upper_camel_case(name: &str) -> String {
-    match name {
-        // The name "Guest" is reserved for traits generated by exported
-        // interfaces, so remap types defined in wit to something else.
-        "guest" => "Guest_".to_string(),
-        s => s.to_upper_camel_case(),
+    let ident = name.to_upper_camel_case();
+    if MBT_RESERVED_TYPES.contains(&ident.as_str()) || MBT_RESERVED_CASES.contains(&ident.as_str())
+    {
+        format!("{ident}_")
+    } else {
+        ident
     }
 }
 
+/// Returns the first of `candidates` not yet in `used`, falling back to the
+/// last candidate with an increasing numeric suffix, and marks it as used.
+fn unique_name(used: &mut HashSet<String>, candidates: &[String]) -> String {
+    let name = candidates
+        .iter()
+        .find(|name| !used.contains(*name))
+        .cloned()
+        .unwrap_or_else(|| {
+            let last = candidates.last().unwrap();
+            (2..)
+                .map(|i| format!("{last}{i}"))
+                .find(|name| !used.contains(name))
+                .unwrap()
+        });
+    used.insert(name.clone());
+    name
+}
+
 fn wasm_type(ty: WasmType) -> &'static str {
     match ty {
         WasmType::I32 => "Int",
@@ -823,47 +1381,32 @@ fn wasm_type(ty: WasmType) -> &'static str {
     }
 }
 
-fn int_repr(repr: Int) -> &'static str {
-    match repr {
-        Int::U8 => "u8",
-        Int::U16 => "u16",
-        Int::U32 => "u32",
-        Int::U64 => "u64",
-    }
-}
-
 fn bitcast(casts: &[Bitcast], operands: &[String], results: &mut Vec<String>) {
     for (cast, operand) in casts.iter().zip(operands) {
         results.push(match cast {
             Bitcast::None => operand.clone(),
-            Bitcast::I32ToI64 => format!("i64::from({})", operand),
-            Bitcast::F32ToI32 => format!("({}).to_bits() as i32", operand),
-            Bitcast::F64ToI64 => format!("({}).to_bits() as i64", operand),
-            Bitcast::I64ToI32 => format!("{} as i32", operand),
-            Bitcast::I32ToF32 => format!("f32::from_bits({} as u32)", operand),
-            Bitcast::I64ToF64 => format!("f64::from_bits({} as u64)", operand),
-            Bitcast::F32ToI64 => format!("i64::from(({}).to_bits())", operand),
-            Bitcast::I64ToF32 => format!("f32::from_bits({} as u32)", operand),
+            Bitcast::I32ToI64 => format!("({operand}).to_int64()"),
+            Bitcast::F32ToI32 => format!("({operand}).reinterpret_as_int()"),
+            Bitcast::F64ToI64 => format!("({operand}).reinterpret_as_i64()"),
+            Bitcast::I64ToI32 => format!("({operand}).to_int()"),
+            Bitcast::I32ToF32 => format!("({operand}).reinterpret_as_float()"),
+            Bitcast::I64ToF64 => format!("({operand}).reinterpret_as_double()"),
+            Bitcast::F32ToI64 => format!("({operand}).reinterpret_as_int().to_int64()"),
+            Bitcast::I64ToF32 => format!("({operand}).to_int().reinterpret_as_float()"),
         });
     }
 }
 
 enum MbtFlagsRepr {
-    U8,
-    U16,
-    U32,
-    U64,
-    U128,
+    Int,
+    Int64,
 }
 
 impl MbtFlagsRepr {
     fn new(f: &Flags) -> MbtFlagsRepr {
         match f.repr() {
-            FlagsRepr::U8 => MbtFlagsRepr::U8,
-            FlagsRepr::U16 => MbtFlagsRepr::U16,
-            FlagsRepr::U32(1) => MbtFlagsRepr::U32,
-            FlagsRepr::U32(2) => MbtFlagsRepr::U64,
-            FlagsRepr::U32(3 | 4) => MbtFlagsRepr::U128,
+            FlagsRepr::U8 | FlagsRepr::U16 | FlagsRepr::U32(1) => MbtFlagsRepr::Int,
+            FlagsRepr::U32(2) => MbtFlagsRepr::Int64,
             FlagsRepr::U32(n) => panic!("unsupported number of flags: {}", n * 32),
         }
     }
@@ -872,11 +1415,434 @@ impl MbtFlagsRepr {
 impl fmt::Display for MbtFlagsRepr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            MbtFlagsRepr::U8 => "u8".fmt(f),
-            MbtFlagsRepr::U16 => "u16".fmt(f),
-            MbtFlagsRepr::U32 => "u32".fmt(f),
-            MbtFlagsRepr::U64 => "u64".fmt(f),
-            MbtFlagsRepr::U128 => "u128".fmt(f),
+            MbtFlagsRepr::Int => "Int".fmt(f),
+            MbtFlagsRepr::Int64 => "Int64".fmt(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    /// Generates the bindings of the only world of `wit`.
+    fn generate(wit: &str, opts: Opts) -> Vec<(String, String)> {
+        generate_with_deps(&[], wit, opts)
+    }
+
+    /// Generates the bindings of the only world of `wit`, which uses the
+    /// packages `deps`.
+    fn generate_with_deps(deps: &[&str], wit: &str, opts: Opts) -> Vec<(String, String)> {
+        let mut resolve = Resolve::default();
+        for dep in deps {
+            let dep = UnresolvedPackage::parse(Path::new("dep.wit"), dep).unwrap();
+            resolve.push(dep).unwrap();
+        }
+        let pkg = UnresolvedPackage::parse(Path::new("test.wit"), wit).unwrap();
+        let pkg = resolve.push(pkg).unwrap();
+        let world = resolve.select_world(pkg, None).unwrap();
+        let mut files = Files::default();
+        opts.build()
+            .unwrap()
+            .generate(&resolve, world, &mut files)
+            .unwrap();
+        files
+            .iter()
+            .map(|(name, contents)| {
+                let contents = String::from_utf8(contents.to_vec()).unwrap();
+                (name.to_string(), contents)
+            })
+            .collect()
+    }
+
+    fn file<'a>(files: &'a [(String, String)], name: &str) -> &'a str {
+        files
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, contents)| contents.as_str())
+            .unwrap_or_else(|| panic!("{name} was not generated"))
+    }
+
+    fn multi_package() -> Opts {
+        Opts {
+            multi_package: true,
+            package_path: Some("user/app".to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn to_mbt_ident_escapes_keywords() {
+        assert_eq!(to_mbt_ident("get-value"), "get_value");
+        assert_eq!(to_mbt_ident("type"), "type_");
+        assert_eq!(to_mbt_ident("match"), "match_");
+        assert_eq!(to_mbt_ident("init-guest"), "init_guest_");
+        assert_eq!(to_mbt_ident("guest-impl"), "guest_impl_");
+    }
+
+    #[test]
+    fn to_upper_camel_case_escapes_reserved_types() {
+        assert_eq!(to_upper_camel_case("output-stream"), "OutputStream");
+        assert_eq!(to_upper_camel_case("string"), "String_");
+        assert_eq!(to_upper_camel_case("option"), "Option_");
+        assert_eq!(to_upper_camel_case("ok"), "Ok_");
+    }
+
+    #[test]
+    fn unique_name_falls_back_to_qualified_then_numbered() {
+        let mut used = HashSet::new();
+        let candidates = ["get".to_string(), "store_get".to_string()];
+        assert_eq!(unique_name(&mut used, &candidates), "get");
+        assert_eq!(unique_name(&mut used, &candidates), "store_get");
+        assert_eq!(unique_name(&mut used, &candidates), "store_get2");
+        assert_eq!(unique_name(&mut used, &candidates), "store_get3");
+        assert_eq!(unique_name(&mut used, &["get".to_string()]), "get2");
+    }
+
+    #[test]
+    fn unique_value_name_qualifies_collisions() {
+        let mut gen = MoonBit::new();
+        assert_eq!(gen.unique_value_name("get", Some("store")), "get");
+        assert_eq!(gen.unique_value_name("get", Some("cache")), "cache_get");
+        assert_eq!(gen.unique_value_name("get", None), "get2");
+        assert_eq!(gen.unique_value_name("type", None), "type_");
+        assert_eq!(gen.unique_value_name("type", None), "type_2");
+    }
+
+    #[test]
+    fn exports_keep_names_of_imported_methods() {
+        let wit = "
+            package my:app;
+
+            interface store {
+              get: func() -> s32;
+            }
+
+            world app {
+              import store;
+              import set: func();
+              export get: func() -> s32;
+              export set: func();
+            }
+        ";
+        let files = generate(wit, Opts::default());
+        let app = file(&files, "app.mbt");
+        assert!(app.contains("pub fn get(self: MyAppStore) -> Int {"));
+        assert!(app.contains("pub fn set() -> Unit {"));
+        assert!(app.contains("pub fn get() -> Int {"));
+        assert!(app.contains("pub fn set2() -> Unit {"));
+    }
+
+    const WASI_IO: &str = "
+        interface streams {
+          resource output-stream {
+            write: func(n: u32);
+          }
+        }
+    ";
+
+    const TWO_VERSIONS: &str = "
+        package my:app;
+
+        world app {
+          import wasi:io/streams@0.2.0;
+          import wasi:io/streams@0.2.1;
+        }
+    ";
+
+    fn wasi_io(version: &str) -> String {
+        format!("package wasi:io@{version};\n{WASI_IO}")
+    }
+
+    #[test]
+    fn names_multiple_package_versions() {
+        let (v0, v1) = (wasi_io("0.2.0"), wasi_io("0.2.1"));
+        let files = generate_with_deps(&[&v0, &v1], TWO_VERSIONS, Opts::default());
+        let app = file(&files, "app.mbt");
+        for expected in [
+            "pub(readonly) struct Wasi {\n  io_v0_2_0: WasiIoV0_2_0\n  io_v0_2_1: WasiIoV0_2_1\n}",
+            "pub(readonly) type WasiIoV0_2_0Streams Unit",
+            "pub(readonly) type WasiIoV0_2_1Streams Unit",
+            // The type of the first version imported keeps its name.
+            "pub struct OutputStream {",
+            "pub struct IoV0_2_1StreamsOutputStream {",
+            "pub fn IoV0_2_1StreamsOutputStream::write(self: IoV0_2_1StreamsOutputStream, n: Int) -> Unit {",
+            "fn ffi_wasi_io_v0_2_0_streams_method_output_stream_write(p0: Int, p1: Int) = \"wasi:io/streams@0.2.0\" \"[method]output-stream.write\"",
+            "fn ffi_wasi_io_v0_2_1_streams_method_output_stream_write(p0: Int, p1: Int) = \"wasi:io/streams@0.2.1\" \"[method]output-stream.write\"",
+            "fn ffi_wasi_io_v0_2_1_streams_output_stream_drop(handle: Int) = \"wasi:io/streams@0.2.1\" \"[resource-drop]output-stream\"",
+            "pub let wasi: Wasi = { io_v0_2_0: { streams: WasiIoV0_2_0Streams(()) }, io_v0_2_1: { streams: WasiIoV0_2_1Streams(()) } }",
+        ] {
+            assert!(app.contains(expected), "{expected}\nnot in\n{app}");
+        }
+    }
+
+    #[test]
+    fn names_multiple_package_versions_in_packages() {
+        let (v0, v1) = (wasi_io("0.2.0"), wasi_io("0.2.1"));
+        let files = generate_with_deps(&[&v0, &v1], TWO_VERSIONS, multi_package());
+        let expected = r#"{
+  "import": [
+    { "path": "user/app/wasi/io_v0_2_0/streams", "alias": "io_v0_2_0_streams" },
+    { "path": "user/app/wasi/io_v0_2_1/streams", "alias": "io_v0_2_1_streams" }
+  ]
+}
+"#;
+        assert_eq!(file(&files, "moon.pkg.json"), expected);
+        for version in ["0.2.0", "0.2.1"] {
+            let module = format!("io_v{}", version.replace('.', "_"));
+            let streams = file(&files, &format!("wasi/{module}/streams/streams.mbt"));
+            // Each version has its own package, so no name is qualified.
+            assert!(streams.contains("pub struct OutputStream {"));
+            let import = format!(
+                "fn ffi_method_output_stream_write(p0: Int, p1: Int) = \"wasi:io/streams@{version}\" \"[method]output-stream.write\""
+            );
+            assert!(streams.contains(&import), "{import}\nnot in\n{streams}");
+        }
+    }
+
+    #[test]
+    fn to_qual_case_keeps_versions_readable() {
+        assert_eq!(to_qual_case("io_v0_2_1"), "IoV0_2_1");
+        assert_eq!(to_qual_case("io_v0_21_0"), "IoV0_21_0");
+        assert_eq!(to_qual_case("output_stream"), "OutputStream");
+    }
+
+    #[test]
+    fn rejects_rust_only_options() {
+        let raw_strings = Opts {
+            raw_strings: true,
+            ..Default::default()
+        };
+        let err = raw_strings.build().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`--raw-strings` is only supported by the Rust generator"
+        );
+        let ownership = Opts {
+            ownership: Ownership::Borrowing {
+                duplicate_if_necessary: false,
+            },
+            ..Default::default()
+        };
+        let err = ownership.build().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "`--ownership` is only supported by the Rust generator"
+        );
+    }
+
+    #[test]
+    fn multi_package_imports_interface_also_exported() {
+        let wit = "
+            package my:app;
+
+            interface types {
+              record point { x: s32, y: s32 }
+            }
+
+            interface api {
+              use types.{point};
+              origin: func() -> point;
+            }
+
+            world app {
+              import api;
+              export api;
+            }
+        ";
+        let files = generate(wit, multi_package());
+        let expected = r#"{
+  "import": [
+    { "path": "user/app/my/app/api", "alias": "api" },
+    { "path": "user/app/my/app/types", "alias": "types" }
+  ]
+}
+"#;
+        assert_eq!(file(&files, "moon.pkg.json"), expected);
+    }
+
+    #[test]
+    fn stubs_construct_exported_resources() {
+        let wit = "
+            package my:app;
+
+            interface api {
+              resource counter {
+                constructor(start: u32);
+                get: func() -> u32;
+                merge: static func(a: borrow<counter>, b: borrow<counter>) -> counter;
+              }
+            }
+
+            world app {
+              export api;
+            }
+        ";
+        let opts = Opts {
+            stubs: true,
+            ..Default::default()
+        };
+        let files = generate(wit, opts);
+        let stub = file(&files, "stub.mbt");
+        assert!(
+            stub.contains("fn Stub::new(start: Int) -> Stub {"),
+            "{stub}"
+        );
+        assert!(stub.contains("fn GuestCounter::get(self: Stub) -> Int {"));
+        assert!(stub.contains("fn Stub::merge(a: Counter, b: Counter) -> OwnCounter {"));
+        assert!(stub.contains("init_guest(Stub::{})"));
+
+        let app = file(&files, "app.mbt");
+        assert!(
+            app.contains("  new(Int) -> Self;\n  get(Self) -> Int;\n"),
+            "{app}"
+        );
+        assert!(app.contains("  merge(Counter, Counter) -> OwnCounter;\n"));
+        assert!(app.contains("Counter::new((guest_impl.t0.unwrap().new)(arg0))"));
+        assert!(app.contains("(guest_impl.t0.unwrap().get)(Counter::{ handle: arg0 })"));
+        assert!(app.contains("let t0: Array[T] = []"));
+        assert!(app.contains("t0.push(T::new(p0))"));
+        assert!(app.contains("get: fn(p0) { t0[p0.handle].get() },"));
+        assert!(app.contains("merge: fn(p0, p1) { T::merge(p0, p1) },"));
+        assert!(!app.contains("Impl as"));
+    }
+
+    #[test]
+    fn frees_return_area_after_lifting() {
+        let wit = "
+            package my:app;
+
+            interface env {
+              initial-cwd: func() -> option<string>;
+            }
+
+            world app {
+              import env;
+            }
+        ";
+        let files = generate(wit, Opts::default());
+        let app = file(&files, "app.mbt");
+        let load = app.find("_rael_load_i32(ret_area + 8)").unwrap();
+        let free = app.find("_rael_free(ret_area)").unwrap();
+        assert!(load < free, "{app}");
+        assert!(
+            app[free..].starts_with("_rael_free(ret_area)\n  ret"),
+            "{app}"
+        );
+    }
+
+    #[test]
+    fn lifts_lists() {
+        let wit = "
+            package my:app;
+
+            interface env {
+              get-arguments: func() -> list<string>;
+              read: func(len: u64) -> list<u8>;
+              get-matrix: func() -> list<list<s32>>;
+            }
+
+            world app {
+              import env;
+            }
+        ";
+        let files = generate(wit, Opts::default());
+        let app = file(&files, "app.mbt");
+        assert!(app.contains("-> Array[String] {"), "{app}");
+        assert!(app.contains("-> Bytes {"), "{app}");
+        assert!(app.contains("-> Array[Array[Int]] {"), "{app}");
+        assert!(app.contains("let result5: Array[String] = []"), "{app}");
+        assert!(app.contains("for i = 0; i < len5; i = i + 1 {"), "{app}");
+        assert!(app.contains("result5.push(e5)"), "{app}");
+        assert!(app.contains("Bytes::make(len2, 0)"), "{app}");
+        // The lists are freed once lifted.
+        assert!(
+            app.contains("if len5 > 0 {\n    _rael_free(base5)"),
+            "{app}"
+        );
+        assert!(app.contains("if len2 > 0 {\n    _rael_free(ptr2)"), "{app}");
+        assert!(!app.contains("Vec"), "{app}");
+        assert!(!app.contains("alloc::"), "{app}");
+    }
+
+    #[test]
+    fn lowers_lists() {
+        let wit = "
+            package my:app;
+
+            interface env {
+              write: func(contents: list<u8>);
+              set: func(entries: list<tuple<string, list<u8>>>);
+            }
+
+            world app {
+              import env;
+            }
+        ";
+        let files = generate(wit, Opts::default());
+        let app = file(&files, "app.mbt");
+        assert!(app.contains("let ptr0 = _rael_bytes_data(vec0)"), "{app}");
+        assert!(
+            app.contains("let result2 = _rael_malloc_aligned(len2 * 16, 4)"),
+            "{app}"
+        );
+        assert!(app.contains("let e = vec2[i]"), "{app}");
+        assert!(app.contains("let (t0_0, t0_1) = e\n"), "{app}");
+        // The list lowered for the call is freed after it.
+        let call = app.find("ffi_my_app_env_set(result2, len2)").unwrap();
+        assert!(app[call..].contains("_rael_free(result2)"), "{app}");
+        assert!(!app.contains("Vec"), "{app}");
+        assert!(!app.contains("alloc::"), "{app}");
+    }
+
+    #[test]
+    fn matches_variants_with_moonbit_syntax() {
+        let wit = "
+            package my:app;
+
+            interface env {
+              variant value {
+                none,
+                int(s64),
+                float(f32),
+                flag(bool),
+              }
+              set: func(value: value, default: option<u32>) -> result<u32>;
+            }
+
+            world app {
+              import env;
+            }
+        ";
+        let files = generate(wit, Opts::default());
+        let app = file(&files, "app.mbt");
+        assert!(app.contains("Value::None_ => (0, 0L)"), "{app}");
+        assert!(app.contains("Value::Int_(e) => (1, e)"), "{app}");
+        assert!(
+            app.contains("Value::Float_(e) => (2, (e).reinterpret_as_int().to_int64())"),
+            "{app}"
+        );
+        assert!(
+            app.contains("Value::Flag(e) => (3, (if e { 1 } else { 0 }).to_int64())"),
+            "{app}"
+        );
+        assert!(app.contains("Some(e) => (1, e)\n"), "{app}");
+        assert!(app.contains("None => (0, 0)\n"), "{app}");
+        assert!(
+            app.contains("_ => abort(\"invalid enum discriminant\")"),
+            "{app}"
+        );
+        for rust in ["i32", "i64", "f32", "},\n", "};", " as "] {
+            let code = app
+                .lines()
+                .filter(|line| !line.trim_start().starts_with("//"))
+                .collect::<Vec<_>>()
+                .join("\n")
+                .replace("_i32", "")
+                .replace("_i64", "");
+            assert!(!code.contains(rust), "{rust} in {app}");
         }
     }
 }
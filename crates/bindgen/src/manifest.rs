@@ -0,0 +1,155 @@
+use anyhow::{bail, Context, Result};
+use heck::ToSnakeCase;
+use serde_json::{json, Map, Value};
+
+/// The exports of the generated bindings, written next to the `.mbt` file so
+/// that `componentize` uses exactly the names bindgen chose.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub world: String,
+    /// The version of the componentize runtime the bindings call into.
+    pub runtime: u32,
+    pub exports: Vec<ExportedFunction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportedFunction {
+    /// The name of the `pub fn` in MoonBit.
+    pub function: String,
+    /// The canonical core wasm export name, including `--export-prefix`.
+    pub export: String,
+    /// Whether the core wasm function returns a value.
+    pub has_rv: bool,
+    /// The MoonBit function and export name of the `cabi_post_` function.
+    pub post_return: Option<(String, String)>,
+}
+
+impl Manifest {
+    /// The manifest file of the bindings of `world`.
+    pub fn file_name(world: &str) -> String {
+        format!("{}.exports.json", world.to_snake_case())
+    }
+
+    pub fn to_json(&self) -> String {
+        let exports = self
+            .exports
+            .iter()
+            .map(|f| {
+                let mut export = Map::new();
+                export.insert("function".into(), json!(f.function));
+                export.insert("export".into(), json!(f.export));
+                export.insert("result".into(), json!(f.has_rv));
+                if let Some((function, export_name)) = &f.post_return {
+                    export.insert(
+                        "post-return".into(),
+                        json!({ "function": function, "export": export_name }),
+                    );
+                }
+                Value::Object(export)
+            })
+            .collect::<Vec<_>>();
+        let json = json!({
+            "world": self.world,
+            "runtime": self.runtime,
+            "exports": exports,
+        });
+        serde_json::to_string_pretty(&json).unwrap() + "\n"
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        fn str_field(value: &Value, key: &str) -> Result<String> {
+            match value.get(key).and_then(|v| v.as_str()) {
+                Some(s) => Ok(s.to_string()),
+                None => bail!("missing string field `{key}` in export manifest"),
+            }
+        }
+
+        let json: Value = serde_json::from_str(json).context("invalid export manifest")?;
+        let mut exports = Vec::new();
+        for export in json
+            .get("exports")
+            .and_then(|e| e.as_array())
+            .context("missing `exports` in export manifest")?
+        {
+            let has_rv = export
+                .get("result")
+                .and_then(|r| r.as_bool())
+                .context("missing boolean field `result` in export manifest")?;
+            let post_return = match export.get("post-return") {
+                Some(post) => Some((str_field(post, "function")?, str_field(post, "export")?)),
+                None => None,
+            };
+            exports.push(ExportedFunction {
+                function: str_field(export, "function")?,
+                export: str_field(export, "export")?,
+                has_rv,
+                post_return,
+            });
+        }
+        let runtime = json
+            .get("runtime")
+            .and_then(|r| r.as_u64())
+            .context("missing `runtime` in export manifest")?;
+        Ok(Manifest {
+            world: str_field(&json, "world")?,
+            runtime: runtime as u32,
+            exports,
+        })
+    }
+
+    /// Finds the export name and return-value flag of a MoonBit function,
+    /// which is either an exported function or its post-return function.
+    pub fn lookup(&self, function: &str) -> Option<(&str, bool)> {
+        self.exports.iter().find_map(|f| {
+            if f.function == function {
+                Some((f.export.as_str(), f.has_rv))
+            } else {
+                match &f.post_return {
+                    Some((post, export)) if post == function => Some((export.as_str(), false)),
+                    _ => None,
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportedFunction, Manifest};
+
+    #[test]
+    fn round_trips_through_json() {
+        let manifest = Manifest {
+            world: "my-world".into(),
+            runtime: 2,
+            exports: vec![
+                ExportedFunction {
+                    function: "wasmExportHello".into(),
+                    export: "hello".into(),
+                    has_rv: false,
+                    post_return: None,
+                },
+                ExportedFunction {
+                    function: "wasmExportApiGet".into(),
+                    export: "my:app/api#get".into(),
+                    has_rv: true,
+                    post_return: Some((
+                        "wasmExportApiGetPostReturn".into(),
+                        "cabi_post_my:app/api#get".into(),
+                    )),
+                },
+            ],
+        };
+        let json = manifest.to_json();
+        assert_eq!(Manifest::from_json(&json).unwrap(), manifest);
+        assert_eq!(Manifest::file_name("my-world"), "my_world.exports.json");
+    }
+
+    #[test]
+    fn rejects_incomplete_manifests() {
+        let json = r#"{ "world": "w", "exports": [] }"#;
+        assert!(Manifest::from_json(json).is_err());
+        let json = r#"{ "world": "w", "runtime": 1, "exports": [{ "function": "f" }] }"#;
+        assert!(Manifest::from_json(json).is_err());
+    }
+}
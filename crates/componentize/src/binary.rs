@@ -0,0 +1,653 @@
+//! The same rewrites as `componentize()` on the text format, for a binary core
+//! module built with `moon build --target=wasm`.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use anyhow::{anyhow, bail, Result};
+use wasm_encoder::{
+    CodeSection, ConstExpr, ElementSection, Elements, Encode, EntityType, ExportKind,
+    ExportSection, Function, FunctionSection, GlobalSection, ImportSection, Instruction,
+    RawSection, StartSection, SubType, TypeSection, ValType,
+};
+use wasmparser::{
+    ElementItems, ElementKind, ElementSectionReader, ExternalKind, FuncType, GlobalSectionReader,
+    Name, NameSectionReader, Operator, OperatorsReader, Parser, Payload, TypeRef,
+};
+use wit_bindgen_mbt::Manifest;
+
+use crate::debug::{self, CodeMap};
+use crate::print::Print;
+use crate::runtime::{self, Runtime};
+use crate::{export_key, Config, OptLevel};
+
+/// What becomes of an imported function.
+enum ImportKind {
+    Kept,
+    /// A no-op for the prints of spectest the runtime doesn't provide, or if
+    /// there's nowhere to print to.
+    Stub,
+    /// A function linked from the runtime.
+    Builtin(u32),
+}
+
+pub(crate) fn rewrite<'a>(
+    wasm: &[u8],
+    manifest: &'a Manifest,
+    runtime: &Runtime,
+    config: &Config,
+    print: &Print,
+) -> Result<(Vec<u8>, HashSet<&'a str>)> {
+    // Collect the index spaces first, so that the sections can be rewritten in
+    // a single pass afterwards.
+    let mut types = Vec::new();
+    let mut imported_funcs = Vec::new();
+    let mut defined_funcs = Vec::new();
+    let mut func_names = HashMap::new();
+    let mut globals = 0;
+    let mut has_imports = false;
+    let mut has_globals = false;
+    let mut has_start = false;
+    let mut has_realloc = false;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for group in reader {
+                    for ty in group?.into_types() {
+                        types.push(match ty.composite_type {
+                            wasmparser::CompositeType::Func(f) => Some(f),
+                            _ => None,
+                        });
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                has_imports = true;
+                for import in reader {
+                    let import = import?;
+                    match import.ty {
+                        TypeRef::Func(ty) => imported_funcs.push((import.module, import.name, ty)),
+                        _ if import.module == "spectest" => {
+                            bail!("unsupported spectest import {:?}", import.name)
+                        }
+                        TypeRef::Global(_) => globals += 1,
+                        _ => {}
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    defined_funcs.push(ty?);
+                }
+            }
+            Payload::GlobalSection(reader) => {
+                has_globals = true;
+                globals += reader.count();
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    has_realloc |= export?.name == "cabi_realloc";
+                }
+            }
+            Payload::StartSection { .. } => has_start = true,
+            Payload::CustomSection(reader) if reader.name() == "name" => {
+                for name in NameSectionReader::new(reader.data(), reader.data_offset()) {
+                    if let Name::Function(map) = name? {
+                        for naming in map {
+                            let naming = naming?;
+                            func_names.insert(naming.name, naming.index);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let func_type = |index: u32| -> Result<&FuncType> {
+        let ty = match index.checked_sub(imported_funcs.len() as u32) {
+            Some(i) => defined_funcs[i as usize],
+            None => imported_funcs[index as usize].2,
+        };
+        types[ty as usize]
+            .as_ref()
+            .ok_or_else(|| anyhow!("function {index} has no function type"))
+    };
+
+    // Link the runtime functions for the imported builtins and prints, and
+    // `cabi_realloc` if the module doesn't define one.
+    let mut missing = BTreeSet::new();
+    let mut imports = Vec::new();
+    for (i, (module, name, _)) in imported_funcs.iter().enumerate() {
+        let builtin = |index: u32, builtin: &str| -> Result<ImportKind> {
+            if func_type(i as u32)? != &runtime.func(index).ty {
+                bail!("`{builtin}` is imported with a different type than the runtime's");
+            }
+            Ok(ImportKind::Builtin(index))
+        };
+        let kind = if *module == "spectest" {
+            if !func_type(i as u32)?.results().is_empty() {
+                bail!("unsupported spectest import {name:?}");
+            }
+            let name = format!("spectest.{name}");
+            match runtime.get(&name) {
+                Some(index) if *print != Print::Discard => builtin(index, &name)?,
+                _ => ImportKind::Stub,
+            }
+        } else if let Some(name) = runtime::builtin_name(module, name) {
+            match runtime.get(&name) {
+                Some(index) => builtin(index, &name)?,
+                None => {
+                    missing.insert(name);
+                    ImportKind::Kept
+                }
+            }
+        } else {
+            ImportKind::Kept
+        };
+        imports.push(kind);
+    }
+    if !missing.is_empty() {
+        return Err(runtime::missing(&missing));
+    }
+    let mut roots = imports
+        .iter()
+        .filter_map(|kind| match kind {
+            ImportKind::Builtin(index) => Some(*index),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let link_realloc = !has_realloc && func_names.contains_key("rael.malloc");
+    if link_realloc {
+        roots.extend(runtime.get("cabi_realloc"));
+    }
+    let mut linked = runtime.link(roots.iter().copied());
+    // `print.write` goes to another runtime function, unless it's imported.
+    let print_write = match print {
+        Print::Discard => runtime.get("print.discard"),
+        Print::Stdout { .. } => runtime.get("print.stdout"),
+        Print::Host { .. } => None,
+    };
+    let prints = |linked| {
+        runtime
+            .imports_of(linked)
+            .iter()
+            .any(|(_, import)| import.module == "print")
+    };
+    if let Some(index) = print_write.filter(|_| prints(&linked)) {
+        roots.push(index);
+        linked = runtime.link(roots);
+    }
+
+    // The runtime imports not resolved in the module become imports of the
+    // module, after the kept ones, unless the module imports them already.
+    let mut new_imports = Vec::new();
+    let mut reused = Vec::new();
+    for (index, import) in runtime.imports_of(&linked) {
+        let (module, name) = match (import.module.as_str(), print) {
+            ("env", _) | ("print", Print::Discard | Print::Stdout { .. }) => continue,
+            ("print", Print::Host { module, name }) => (module.as_str(), name.as_str()),
+            ("wasi:cli/stdout", Print::Stdout { stdout, .. }) => (stdout.as_str(), &*import.name),
+            ("wasi:io/streams", Print::Stdout { streams, .. }) => (streams.as_str(), &*import.name),
+            (module, _) => bail!(
+                "the runtime import `{module}` `{}` is unavailable",
+                import.name
+            ),
+        };
+        let existing = imported_funcs
+            .iter()
+            .position(|(m, n, _)| (*m, *n) == (module, name));
+        if let Some(i) = existing {
+            if func_type(i as u32)? != &import.ty {
+                bail!("`{module}` `{name}` is imported with a different type than the runtime's");
+            }
+            reused.push((index, i));
+            continue;
+        }
+        new_imports.push((index, module, name, &import.ty));
+    }
+
+    // The kept and new imports and the defined functions are followed by the
+    // stubs and the linked runtime functions.
+    let kept = imports
+        .iter()
+        .filter(|kind| matches!(kind, ImportKind::Kept))
+        .count() as u32;
+    let imported = kept + new_imports.len() as u32;
+    let stubs = imports
+        .iter()
+        .enumerate()
+        .filter(|(_, kind)| matches!(kind, ImportKind::Stub))
+        .map(|(i, _)| imported_funcs[i].2)
+        .collect::<Vec<_>>();
+    let defined = defined_funcs.len() as u32;
+    let mut runtime_funcs = vec![u32::MAX; runtime.imports.len() + runtime.funcs.len()];
+    for (i, (index, ..)) in new_imports.iter().enumerate() {
+        runtime_funcs[*index as usize] = kept + i as u32;
+    }
+    for (i, index) in linked.iter().enumerate() {
+        runtime_funcs[*index as usize] = imported + defined + stubs.len() as u32 + i as u32;
+    }
+    let linked_globals = runtime.globals_of(&linked);
+    let mut runtime_globals = vec![u32::MAX; runtime.globals.len()];
+    for (i, index) in linked_globals.iter().enumerate() {
+        runtime_globals[*index as usize] = globals + i as u32;
+    }
+    let mut funcs = Vec::new();
+    let (mut next_kept, mut next_stub) = (0, imported + defined);
+    for kind in imports.iter() {
+        let next = match kind {
+            ImportKind::Kept => &mut next_kept,
+            ImportKind::Stub => &mut next_stub,
+            ImportKind::Builtin(index) => {
+                funcs.push(runtime_funcs[*index as usize]);
+                continue;
+            }
+        };
+        funcs.push(*next);
+        *next += 1;
+    }
+    funcs.extend((0..defined).map(|i| imported + i));
+    for (index, i) in reused {
+        runtime_funcs[index as usize] = funcs[i];
+    }
+    for (index, import) in runtime.imports_of(&linked) {
+        runtime_funcs[index as usize] = match import.module.as_str() {
+            "env" => {
+                let Some(func) = func_names.get(import.name.as_str()) else {
+                    bail!(
+                        "the runtime needs `{}`, which the module does not define",
+                        import.name
+                    );
+                };
+                funcs[*func as usize]
+            }
+            "print" if print_write.is_some() => runtime_funcs[print_write.unwrap() as usize],
+            _ => continue,
+        };
+    }
+
+    let mut new_types = Vec::new();
+    let mut drop_result = HashMap::new();
+    let mut exported = HashSet::new();
+    let mut start = None;
+    for payload in Parser::new(0).parse_all(wasm) {
+        if let Payload::ExportSection(reader) = payload? {
+            for export in reader {
+                let export = export?;
+                if export.kind != ExternalKind::Func {
+                    continue;
+                }
+                if export.name == "_start" {
+                    if has_start {
+                        bail!("the module has both a start function and a `_start` export");
+                    }
+                    start = Some(funcs[export.index as usize]);
+                }
+                let key = export_key(export.name);
+                let Some((_, has_rv)) = manifest.lookup(key) else {
+                    continue;
+                };
+                if let Some(f) = manifest.exports.iter().find(|f| f.function == key) {
+                    exported.insert(f.function.as_str());
+                }
+                let ty = func_type(export.index)?;
+                let is_defined = export.index as usize >= imported_funcs.len();
+                // Older MoonBit compiles a `pub fn` without a return value to
+                // a function returning i32.
+                if config.fixups.drop_unit_result
+                    && !has_rv
+                    && is_defined
+                    && ty.results().len() == 1
+                {
+                    let i = types.len() + new_types.len();
+                    new_types.push(FuncType::new(ty.params().iter().copied(), []));
+                    drop_result.insert(export.index, i as u32);
+                }
+            }
+        }
+    }
+    let runtime_types = (types.len() + new_types.len()) as u32;
+    new_types.extend(linked.iter().map(|index| runtime.func(*index).ty.clone()));
+    let import_types = (types.len() + new_types.len()) as u32;
+    new_types.extend(new_imports.iter().map(|(.., ty)| (*ty).clone()));
+
+    let add_imports = |section: &mut ImportSection| {
+        for (i, (_, module, name, _)) in new_imports.iter().enumerate() {
+            section.import(module, name, EntityType::Function(import_types + i as u32));
+        }
+    };
+    let add_globals = |section: &mut GlobalSection| -> Result<()> {
+        for index in linked_globals.iter() {
+            let (ty, init) = &runtime.globals[*index as usize];
+            let ty = (*ty)
+                .try_into()
+                .map_err(|_| anyhow!("unsupported global type"))?;
+            section.global(ty, &ConstExpr::raw(runtime.wasm[init.clone()].to_vec()));
+        }
+        Ok(())
+    };
+
+    // For the name section in debug mode.
+    let mut added_names = BTreeMap::new();
+    for index in linked
+        .iter()
+        .chain(new_imports.iter().map(|(index, ..)| index))
+    {
+        if let Some(name) = runtime.name(*index) {
+            added_names.insert(runtime_funcs[*index as usize], name);
+        }
+    }
+
+    let mut module = wasm_encoder::Module::new();
+    let mut code = CodeSection::new();
+    let mut code_map = CodeMap::default();
+    let mut defined_index = imported_funcs.len() as u32;
+    for payload in Parser::new(0).parse_all(wasm) {
+        let payload = payload?;
+        // Add the import and global sections if the module has none.
+        let order = match &payload {
+            Payload::End(_) => u8::MAX,
+            payload => payload.as_section().map_or(0, |(id, _)| section_order(id)),
+        };
+        if !has_imports && !new_imports.is_empty() && order > section_order(2) {
+            let mut section = ImportSection::new();
+            add_imports(&mut section);
+            module.section(&section);
+            has_imports = true;
+        }
+        if !has_globals && !linked_globals.is_empty() && order > section_order(6) {
+            let mut section = GlobalSection::new();
+            add_globals(&mut section)?;
+            module.section(&section);
+            has_globals = true;
+        }
+        match payload {
+            Payload::TypeSection(reader) => {
+                let mut section = TypeSection::new();
+                for group in reader {
+                    let group = group?;
+                    let explicit = group.is_explicit_rec_group();
+                    let subtypes = group
+                        .into_types()
+                        .map(|ty| SubType::try_from(ty).map_err(|_| anyhow!("unsupported type")))
+                        .collect::<Result<Vec<_>>>()?;
+                    if explicit {
+                        section.rec(subtypes);
+                    } else {
+                        section.subtype(&subtypes[0]);
+                    }
+                }
+                for ty in new_types.iter() {
+                    section.function(val_types(ty.params())?, val_types(ty.results())?);
+                }
+                module.section(&section);
+            }
+            Payload::ImportSection(reader) => {
+                let mut section = ImportSection::new();
+                let mut kinds = imports.iter();
+                for import in reader {
+                    let import = import?;
+                    if let TypeRef::Func(_) = import.ty {
+                        if !matches!(kinds.next(), Some(ImportKind::Kept)) {
+                            continue;
+                        }
+                    }
+                    let ty = EntityType::try_from(import.ty)
+                        .map_err(|_| anyhow!("unsupported import {:?}", import.name))?;
+                    section.import(import.module, import.name, ty);
+                }
+                add_imports(&mut section);
+                module.section(&section);
+            }
+            Payload::FunctionSection(reader) => {
+                let mut section = FunctionSection::new();
+                for (i, ty) in reader.into_iter().enumerate() {
+                    let index = imported_funcs.len() as u32 + i as u32;
+                    section.function(drop_result.get(&index).copied().unwrap_or(ty?));
+                }
+                for ty in stubs.iter() {
+                    section.function(*ty);
+                }
+                for i in 0..linked.len() as u32 {
+                    section.function(runtime_types + i);
+                }
+                module.section(&section);
+            }
+            Payload::GlobalSection(reader) => {
+                let mut section = copy_globals(wasm, reader, &funcs)?;
+                add_globals(&mut section)?;
+                module.section(&section);
+            }
+            Payload::ExportSection(reader) => {
+                let mut section = ExportSection::new();
+                for export in reader {
+                    let export = export?;
+                    let kind = ExportKind::from(export.kind);
+                    let mut name = export.name;
+                    let mut index = export.index;
+                    match export.kind {
+                        ExternalKind::Func if name == "_start" => continue,
+                        ExternalKind::Func => {
+                            index = funcs[index as usize];
+                            if let Some((symbol, _)) = manifest.lookup(export_key(name)) {
+                                name = symbol;
+                            }
+                        }
+                        ExternalKind::Memory if name == "moonbit.memory" => name = "memory",
+                        _ => {}
+                    }
+                    section.export(name, kind, index);
+                }
+                if link_realloc {
+                    let index = runtime.get("cabi_realloc").unwrap();
+                    section.export(
+                        "cabi_realloc",
+                        ExportKind::Func,
+                        runtime_funcs[index as usize],
+                    );
+                }
+                module.section(&section);
+                if let Some(function_index) = start {
+                    module.section(&StartSection { function_index });
+                }
+            }
+            Payload::StartSection { func, .. } => {
+                module.section(&StartSection {
+                    function_index: funcs[func as usize],
+                });
+            }
+            Payload::ElementSection(reader) => {
+                module.section(&copy_elements(wasm, reader, &funcs)?);
+            }
+            Payload::CodeSectionEntry(body) => {
+                let range = body.range();
+                let ops = body.get_operators_reader()?;
+                let mut bytes = wasm[range.start..ops.original_position()].to_vec();
+                let mut moves = vec![(range.start, 0)];
+                rewrite_ops(wasm, ops, &funcs, &mut bytes, &mut moves)?;
+                if drop_result.contains_key(&defined_index) {
+                    bytes.insert(bytes.len() - 1, 0x1a); // drop
+                    moves.push((range.end - 1, bytes.len() - 1));
+                }
+                code.raw(&bytes);
+                code_map.push(range, moves);
+                defined_index += 1;
+                if defined_index as usize == imported_funcs.len() + defined_funcs.len() {
+                    for _ in stubs.iter() {
+                        let mut f = Function::new([]);
+                        f.instruction(&Instruction::End);
+                        code.function(&f);
+                    }
+                    for index in linked.iter() {
+                        let func = runtime.func(*index);
+                        code.raw(&func.encode(&runtime.wasm, &runtime_funcs, &runtime_globals));
+                    }
+                    module.section(&code);
+                }
+            }
+            Payload::CodeSectionStart { .. } | Payload::End(_) => {}
+            Payload::CustomSection(reader) if reader.name() == "name" => {
+                if config.debug {
+                    let reader = NameSectionReader::new(reader.data(), reader.data_offset());
+                    module.section(&debug::names(reader, &funcs, &added_names)?);
+                }
+            }
+            // The offsets in the code are stale now, the source map is added
+            // back in debug mode, unless optimising.
+            Payload::CustomSection(reader)
+                if reader.name() == "sourceMappingURL" || reader.name().starts_with(".debug_") => {}
+            Payload::CustomSection(reader) => {
+                module.section(&wasm_encoder::CustomSection {
+                    name: reader.name().into(),
+                    data: reader.data().into(),
+                });
+            }
+            Payload::Version { .. } => {}
+            payload => match payload.as_section() {
+                Some((id, range)) => {
+                    module.section(&RawSection {
+                        id,
+                        data: &wasm[range],
+                    });
+                }
+                None => bail!("unsupported section in the core module"),
+            },
+        }
+    }
+    let keep_source_map = config.debug && config.opt_level == OptLevel::O0;
+    if let Some(source_map) = config.source_map.as_ref().filter(|_| keep_source_map) {
+        code_map.locate(module.as_slice())?;
+        let mut url = Vec::new();
+        debug::source_map_url(source_map, &code_map)?.encode(&mut url);
+        module.section(&wasm_encoder::CustomSection {
+            name: "sourceMappingURL".into(),
+            data: url.into(),
+        });
+    }
+    Ok((module.finish(), exported))
+}
+
+/// The position of a section in a module, for sections with the given `id`.
+fn section_order(id: u8) -> u8 {
+    match id {
+        // The tag section goes after the memory section, and the data count
+        // section before the code section.
+        13 => 11,
+        12 => 19,
+        id => id * 2,
+    }
+}
+
+enum ElementList {
+    Functions(Vec<u32>),
+    Expressions(wasm_encoder::RefType, Vec<ConstExpr>),
+}
+
+/// Copies the globals from `reader`, updating function indices.
+pub(crate) fn copy_globals(
+    wasm: &[u8],
+    reader: GlobalSectionReader,
+    funcs: &[u32],
+) -> Result<GlobalSection> {
+    let mut section = GlobalSection::new();
+    for global in reader {
+        let global = global?;
+        let ty = global
+            .ty
+            .try_into()
+            .map_err(|_| anyhow!("unsupported global type"))?;
+        let init = const_expr(wasm, global.init_expr.get_operators_reader(), funcs)?;
+        section.global(ty, &init);
+    }
+    Ok(section)
+}
+
+/// Copies the element segments from `reader`, updating function indices.
+pub(crate) fn copy_elements(
+    wasm: &[u8],
+    reader: ElementSectionReader,
+    funcs: &[u32],
+) -> Result<ElementSection> {
+    let mut section = ElementSection::new();
+    for element in reader {
+        let element = element?;
+        let items = match element.items {
+            ElementItems::Functions(reader) => ElementList::Functions(
+                reader
+                    .into_iter()
+                    .map(|f| Ok(funcs[f? as usize]))
+                    .collect::<Result<_>>()?,
+            ),
+            ElementItems::Expressions(ty, reader) => ElementList::Expressions(
+                ty.try_into()
+                    .map_err(|_| anyhow!("unsupported element type"))?,
+                reader
+                    .into_iter()
+                    .map(|expr| const_expr(wasm, expr?.get_operators_reader(), funcs))
+                    .collect::<Result<_>>()?,
+            ),
+        };
+        let elements = match &items {
+            ElementList::Functions(funcs) => Elements::Functions(funcs),
+            ElementList::Expressions(ty, exprs) => Elements::Expressions(*ty, exprs),
+        };
+        match element.kind {
+            ElementKind::Passive => section.passive(elements),
+            ElementKind::Declared => section.declared(elements),
+            ElementKind::Active {
+                table_index,
+                offset_expr,
+            } => {
+                let offset = const_expr(wasm, offset_expr.get_operators_reader(), funcs)?;
+                section.active(table_index, &offset, elements)
+            }
+        };
+    }
+    Ok(section)
+}
+
+/// Copies the instructions from `reader`, updating function indices, and
+/// recording the old and new offsets of the moved instructions in `moves`.
+pub(crate) fn rewrite_ops(
+    wasm: &[u8],
+    mut reader: OperatorsReader,
+    funcs: &[u32],
+    out: &mut Vec<u8>,
+    moves: &mut Vec<(usize, usize)>,
+) -> Result<()> {
+    let mut copied = reader.original_position();
+    while !reader.eof() {
+        let (op, offset) = reader.read_with_offset()?;
+        let instr = match op {
+            Operator::Call { function_index } => Instruction::Call(funcs[function_index as usize]),
+            Operator::ReturnCall { function_index } => {
+                Instruction::ReturnCall(funcs[function_index as usize])
+            }
+            Operator::RefFunc { function_index } => {
+                Instruction::RefFunc(funcs[function_index as usize])
+            }
+            _ => continue,
+        };
+        out.extend_from_slice(&wasm[copied..offset]);
+        moves.push((offset, out.len()));
+        instr.encode(out);
+        copied = reader.original_position();
+        moves.push((copied, out.len()));
+    }
+    out.extend_from_slice(&wasm[copied..reader.original_position()]);
+    Ok(())
+}
+
+pub(crate) fn const_expr(wasm: &[u8], reader: OperatorsReader, funcs: &[u32]) -> Result<ConstExpr> {
+    let mut bytes = Vec::new();
+    rewrite_ops(wasm, reader, funcs, &mut bytes, &mut Vec::new())?;
+    // `ConstExpr` adds the `end` itself.
+    bytes.pop();
+    Ok(ConstExpr::raw(bytes))
+}
+
+fn val_types(types: &[wasmparser::ValType]) -> Result<Vec<ValType>> {
+    types
+        .iter()
+        .map(|ty| ValType::try_from(*ty).map_err(|_| anyhow!("unsupported value type {ty:?}")))
+        .collect()
+}
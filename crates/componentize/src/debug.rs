@@ -0,0 +1,388 @@
+//! The debug information of the core module kept in `--debug` mode: the name
+//! section, and the source map with the offsets of the rewritten code.
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Result};
+use wasm_encoder::{IndirectNameMap, NameMap, NameSection};
+use wasmparser::{Name, NameSectionReader, Parser, Payload};
+
+/// Where the bodies of the defined functions moved to.
+#[derive(Default)]
+pub(crate) struct CodeMap {
+    bodies: Vec<BodyMap>,
+}
+
+struct BodyMap {
+    old: Range<usize>,
+    new_start: usize,
+    /// The old offsets of the copied chunks of the body, with their new
+    /// offsets relative to the start of the body.
+    chunks: Vec<(usize, usize)>,
+}
+
+impl CodeMap {
+    pub fn push(&mut self, old: Range<usize>, chunks: Vec<(usize, usize)>) {
+        self.bodies.push(BodyMap {
+            old,
+            new_start: 0,
+            chunks,
+        });
+    }
+
+    /// Sets the new starts of the bodies from the rewritten module, where
+    /// the defined functions come first.
+    pub fn locate(&mut self, wasm: &[u8]) -> Result<()> {
+        let mut bodies = self.bodies.iter_mut();
+        for payload in Parser::new(0).parse_all(wasm) {
+            if let Payload::CodeSectionEntry(body) = payload? {
+                match bodies.next() {
+                    Some(map) => map.new_start = body.range().start,
+                    None => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn map(&self, offset: usize) -> Option<usize> {
+        let i = self.bodies.partition_point(|body| body.old.end <= offset);
+        let body = self
+            .bodies
+            .get(i)
+            .filter(|body| body.old.contains(&offset))?;
+        let j = body.chunks.partition_point(|(old, _)| *old <= offset);
+        let (old, new) = body.chunks[j.checked_sub(1)?];
+        Some(body.new_start + new + offset - old)
+    }
+}
+
+/// The name section with the function indices of the rewritten module, plus
+/// the `added` function names. Removed functions are mapped to `u32::MAX`.
+pub(crate) fn names(
+    reader: NameSectionReader,
+    funcs: &[u32],
+    added: &BTreeMap<u32, &str>,
+) -> Result<NameSection> {
+    let same = Some;
+    let func = |i: u32| Some(funcs[i as usize]).filter(|i| *i != u32::MAX);
+    let mut section = NameSection::new();
+    for name in reader {
+        match name? {
+            Name::Module { name, .. } => section.module(name),
+            Name::Function(map) => {
+                let mut names = name_map(map, func)?;
+                names.extend(added.iter().map(|(index, name)| (*index, *name)));
+                section.functions(&encode(&names));
+            }
+            Name::Local(map) => section.locals(&indirect(map, func)?),
+            Name::Label(map) => section.labels(&indirect(map, func)?),
+            Name::Type(map) => section.types(&encode(&name_map(map, same)?)),
+            Name::Table(map) => section.tables(&encode(&name_map(map, same)?)),
+            Name::Memory(map) => section.memories(&encode(&name_map(map, same)?)),
+            Name::Global(map) => section.globals(&encode(&name_map(map, same)?)),
+            Name::Element(map) => section.elements(&encode(&name_map(map, same)?)),
+            Name::Data(map) => section.data(&encode(&name_map(map, same)?)),
+            Name::Tag(map) => section.tags(&encode(&name_map(map, same)?)),
+            Name::Unknown { .. } => {}
+        }
+    }
+    Ok(section)
+}
+
+/// The names of `map` by their new index, which must be in order when encoded.
+fn name_map<'a>(
+    map: wasmparser::NameMap<'a>,
+    remap: impl Fn(u32) -> Option<u32>,
+) -> Result<BTreeMap<u32, &'a str>> {
+    let mut names = BTreeMap::new();
+    for naming in map {
+        let naming = naming?;
+        if let Some(index) = remap(naming.index) {
+            names.insert(index, naming.name);
+        }
+    }
+    Ok(names)
+}
+
+fn encode(names: &BTreeMap<u32, &str>) -> NameMap {
+    let mut map = NameMap::new();
+    for (index, name) in names {
+        map.append(*index, name);
+    }
+    map
+}
+
+/// The names of the locals or labels of the functions of `map`.
+fn indirect(
+    map: wasmparser::IndirectNameMap,
+    func: impl Fn(u32) -> Option<u32>,
+) -> Result<IndirectNameMap> {
+    let mut names = BTreeMap::new();
+    for naming in map {
+        let naming = naming?;
+        if let Some(index) = func(naming.index) {
+            names.insert(index, name_map(naming.names, Some)?);
+        }
+    }
+    let mut map = IndirectNameMap::new();
+    for (index, names) in names.iter() {
+        map.append(*index, &encode(names));
+    }
+    Ok(map)
+}
+
+/// The source map `json` with the offsets of the rewritten module, as a data
+/// URL for the `sourceMappingURL` section.
+pub(crate) fn source_map_url(json: &str, code: &CodeMap) -> Result<String> {
+    let mut map: serde_json::Value = serde_json::from_str(json)?;
+    let mappings = map
+        .get("mappings")
+        .and_then(|mappings| mappings.as_str())
+        .ok_or_else(|| anyhow!("the source map has no mappings"))?;
+
+    // The generated column is the offset in the module, on a single line.
+    let mut segments = Vec::new();
+    let mut fields = [0i64; 5];
+    for (line, text) in mappings.split(';').enumerate() {
+        fields[0] = 0;
+        for segment in text.split(',').filter(|segment| !segment.is_empty()) {
+            let deltas = decode_vlq(segment)?;
+            for (field, delta) in fields.iter_mut().zip(deltas.iter()) {
+                *field += delta;
+            }
+            if line > 0 {
+                bail!("the source map has more than one line");
+            }
+            if let Some(offset) = code.map(fields[0] as usize) {
+                let mut segment = fields;
+                segment[0] = offset as i64;
+                segments.push((segment, deltas.len()));
+            }
+        }
+    }
+    segments.sort_by_key(|(segment, _)| segment[0]);
+
+    let mut mappings = String::new();
+    let mut previous = [0i64; 5];
+    for (segment, len) in segments {
+        if !mappings.is_empty() {
+            mappings.push(',');
+        }
+        for i in 0..len {
+            encode_vlq(segment[i] - previous[i], &mut mappings);
+            previous[i] = segment[i];
+        }
+    }
+    map["mappings"] = mappings.into();
+    let json = serde_json::to_string(&map)?;
+    Ok(format!(
+        "data:application/json;base64,{}",
+        base64(json.as_bytes())
+    ))
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn decode_vlq(segment: &str) -> Result<Vec<i64>> {
+    let mut values = Vec::new();
+    let (mut value, mut shift) = (0i64, 0);
+    for c in segment.bytes() {
+        let digit = BASE64
+            .iter()
+            .position(|b| *b == c)
+            .ok_or_else(|| anyhow!("invalid source map mappings"))? as i64;
+        value += (digit & 0x1f) << shift;
+        if digit & 0x20 != 0 {
+            shift += 5;
+            continue;
+        }
+        values.push(if value & 1 != 0 {
+            -(value >> 1)
+        } else {
+            value >> 1
+        });
+        (value, shift) = (0, 0);
+    }
+    Ok(values)
+}
+
+fn encode_vlq(value: i64, out: &mut String) {
+    let mut value = if value < 0 {
+        (-value << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let digit = value & 0x1f;
+        value >>= 5;
+        let continued = if value > 0 { 0x20 } else { 0 };
+        out.push(BASE64[(digit | continued) as usize] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmparser::{BinaryReader, Operator};
+
+    use super::*;
+    use crate::tests::{config, load_world, manifest, wasm};
+    use crate::{componentize_wasm, Config};
+
+    /// The offsets of the instructions of the body of the function `name`
+    /// in the core module `wasm`, which has a name section.
+    fn instructions(wasm: &[u8], name: &str) -> Vec<(usize, String)> {
+        let mut names = BTreeMap::new();
+        let mut imported = 0;
+        let mut bodies = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.unwrap() {
+                Payload::ImportSection(reader) => imported = reader.count(),
+                Payload::CodeSectionEntry(body) => bodies.push(body),
+                Payload::CustomSection(reader) if reader.name() == "name" => {
+                    for name in NameSectionReader::new(reader.data(), reader.data_offset()) {
+                        if let Name::Function(map) = name.unwrap() {
+                            for naming in map {
+                                let naming = naming.unwrap();
+                                names.insert(naming.name.to_string(), naming.index);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let body = &bodies[(names[name] - imported) as usize];
+        let mut reader = body.get_operators_reader().unwrap();
+        let mut ops = Vec::new();
+        while !reader.eof() {
+            let (op, offset) = reader.read_with_offset().unwrap();
+            let op = match op {
+                Operator::I32Const { value } => format!("i32.const {value}"),
+                Operator::Call { .. } => "call".into(),
+                Operator::End => "end".into(),
+                op => format!("{op:?}"),
+            };
+            ops.push((offset, op));
+        }
+        ops
+    }
+
+    fn decode_base64(text: &str) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let (mut n, mut bits) = (0u32, 0);
+        for c in text.bytes().filter(|c| *c != b'=') {
+            n = n << 6 | BASE64.iter().position(|b| *b == c).unwrap() as u32;
+            bits += 6;
+            if bits >= 8 {
+                bits -= 8;
+                bytes.push((n >> bits) as u8);
+                n &= (1 << bits) - 1;
+            }
+        }
+        bytes
+    }
+
+    /// The core module with the `sourceMappingURL` section in `component`,
+    /// and the generated offsets and source lines of its source map.
+    fn source_map(component: &[u8]) -> (Vec<u8>, Vec<(usize, i64)>) {
+        for payload in Parser::new(0).parse_all(component) {
+            let Payload::ModuleSection { range, .. } = payload.unwrap() else {
+                continue;
+            };
+            let module = &component[range];
+            for payload in Parser::new(0).parse_all(module) {
+                let Payload::CustomSection(reader) = payload.unwrap() else {
+                    continue;
+                };
+                if reader.name() != "sourceMappingURL" {
+                    continue;
+                }
+                let url = BinaryReader::new(reader.data()).read_string().unwrap();
+                let json = url.strip_prefix("data:application/json;base64,").unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&decode_base64(json)).unwrap();
+                assert_eq!(json["sources"][0], "app.mbt");
+                let mut fields = [0i64; 4];
+                let mut segments = Vec::new();
+                for segment in json["mappings"].as_str().unwrap().split(',') {
+                    for (field, delta) in fields.iter_mut().zip(decode_vlq(segment).unwrap()) {
+                        *field += delta;
+                    }
+                    segments.push((fields[0] as usize, fields[2]));
+                }
+                return (module.to_vec(), segments);
+            }
+        }
+        panic!("no core module has a source map");
+    }
+
+    #[test]
+    fn keeps_names_and_source_map() {
+        // The source lines of the instructions of `wasmExportRun`.
+        let wasm = wasm();
+        let run = instructions(&wasm, "user/app::wasmExportRun");
+        assert_eq!(run[0].1, "i32.const 0");
+        assert_eq!(run[1].1, "call");
+        let mut mappings = String::new();
+        let mut previous = [0i64; 4];
+        for (line, (offset, _)) in [3, 4].into_iter().zip(&run) {
+            let segment = [*offset as i64, 0, line, 2];
+            if !mappings.is_empty() {
+                mappings.push(',');
+            }
+            for (value, previous) in segment.iter().zip(previous.iter_mut()) {
+                encode_vlq(value - *previous, &mut mappings);
+                *previous = *value;
+            }
+        }
+        let json = serde_json::json!({
+            "version": 3,
+            "sources": ["app.mbt"],
+            "names": [],
+            "mappings": mappings,
+        });
+
+        let config = Config {
+            debug: true,
+            source_map: Some(json.to_string()),
+            ..config()
+        };
+        let (resolve, world) = load_world();
+        let component = componentize_wasm(&wasm, resolve, world, &manifest(), &config).unwrap();
+        let (module, segments) = source_map(&component);
+        // The names of the MoonBit functions are kept, and the source map
+        // points at the same instructions in the rewritten body.
+        let run = instructions(&module, "user/app::wasmExportRun");
+        assert!(instructions(&module, "*init*/0").len() > 1);
+        let lines = segments
+            .iter()
+            .map(|(offset, line)| {
+                let op = run.iter().find(|(start, _)| start == offset).unwrap();
+                (op.1.as_str(), *line)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(lines, [("i32.const 0", 3), ("call", 4)]);
+    }
+}
@@ -0,0 +1,137 @@
+//! Workarounds for bugs of older MoonBit compilers.
+
+use std::fmt;
+use std::process::Command;
+
+/// Whether to apply the fixups.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum FixupMode {
+    /// Only the fixups for the detected compiler version, or all of them if
+    /// the version is unknown.
+    #[default]
+    Auto,
+    On,
+    Off,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fixups {
+    /// A `pub fn` returning `Unit` is compiled to return an `i32`, fixed in
+    /// the 2024-06-25 version of the compiler.
+    pub drop_unit_result: bool,
+}
+
+impl Fixups {
+    pub const ALL: Fixups = Fixups {
+        drop_unit_result: true,
+    };
+    pub const NONE: Fixups = Fixups {
+        drop_unit_result: false,
+    };
+
+    pub fn new(mode: FixupMode, version: Option<&MooncVersion>) -> Self {
+        match (mode, version) {
+            (FixupMode::On, _) | (FixupMode::Auto, None) => Fixups::ALL,
+            (FixupMode::Off, _) => Fixups::NONE,
+            (FixupMode::Auto, Some(version)) => Fixups {
+                drop_unit_result: version.date < 20240625,
+            },
+        }
+    }
+}
+
+/// The version of `moonc`, with its release date as `YYYYMMDD`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MooncVersion {
+    pub version: String,
+    pub date: u32,
+}
+
+impl MooncVersion {
+    /// Runs `moon version --all`, returning `None` if MoonBit isn't installed
+    /// or the output isn't understood.
+    pub fn detect() -> Option<Self> {
+        let output = Command::new("moon")
+            .arg("version")
+            .arg("--all")
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        Self::parse(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    /// Parses lines like `moonc v0.1.20240624+2d0d4b5`, falling back to the
+    /// version of `moon` itself if there's no `moonc` line.
+    pub fn parse(output: &str) -> Option<Self> {
+        let mut lines = output.lines().map(str::trim);
+        let line = lines
+            .clone()
+            .find(|line| line.starts_with("moonc "))
+            .or_else(|| lines.find(|line| line.starts_with("moon ")))?;
+        let version = line.split_whitespace().nth(1)?;
+        let date = version
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|part| part.len() == 8 && part.starts_with("20"))?
+            .parse()
+            .ok()?;
+        Some(MooncVersion {
+            version: version.to_string(),
+            date,
+        })
+    }
+}
+
+impl fmt::Display for MooncVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_moonc_version() {
+        let output = "moon 0.1.20240626 (f6d7f3d 2024-06-26)\n\
+                      moonc v0.1.20240624+2d0d4b5\n\
+                      moonrun 0.1.20240626 (f6d7f3d 2024-06-26)\n";
+        let version = MooncVersion::parse(output).unwrap();
+        assert_eq!(version.version, "v0.1.20240624+2d0d4b5");
+        assert_eq!(version.date, 20240624);
+        assert_eq!(version.to_string(), "v0.1.20240624+2d0d4b5");
+    }
+
+    #[test]
+    fn falls_back_to_moon_version() {
+        let version = MooncVersion::parse("moon 0.1.20240710 (abc 2024-07-10)\n").unwrap();
+        assert_eq!(version.date, 20240710);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert_eq!(MooncVersion::parse(""), None);
+        assert_eq!(MooncVersion::parse("moonrun 0.1.20240626\n"), None);
+        assert_eq!(MooncVersion::parse("moonc\n"), None);
+        assert_eq!(MooncVersion::parse("moonc v0.1.0+2d0d4b5\n"), None);
+        assert_eq!(MooncVersion::parse("moonc v0.1.1024062+2d0d4b5\n"), None);
+    }
+
+    #[test]
+    fn applies_fixups_by_version() {
+        let version = |date| MooncVersion {
+            version: String::new(),
+            date,
+        };
+        let old = version(20240624);
+        let new = version(20240625);
+        assert_eq!(Fixups::new(FixupMode::Auto, Some(&old)), Fixups::ALL);
+        assert_eq!(Fixups::new(FixupMode::Auto, Some(&new)), Fixups::NONE);
+        assert_eq!(Fixups::new(FixupMode::Auto, None), Fixups::ALL);
+        assert_eq!(Fixups::new(FixupMode::On, Some(&new)), Fixups::ALL);
+        assert_eq!(Fixups::new(FixupMode::Off, Some(&old)), Fixups::NONE);
+    }
+}
@@ -1,21 +1,201 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::{fs, mem};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 
-use wast::core::{
-    Func, FuncKind, Import, InlineExport, Instruction, Memory, Module, ModuleField, ModuleKind,
-};
+use anyhow::{bail, Context};
+use wast::core::{Func, FuncKind, Import, Instruction, Module, ModuleField, ModuleKind};
 use wast::parser::ParseBuffer;
 use wast::token::Index;
 use wast::Wat;
 use wit_bindgen_core::wit_parser::{Resolve, WorldId};
-use wit_bindgen_core::{Files, WorldGenerator};
-use wit_component::{embed_component_metadata, ComponentEncoder, StringEncoding};
+use wit_bindgen_mbt::Manifest;
+use wit_component::{embed_component_metadata, ComponentEncoder};
+
+mod binary;
+mod debug;
+mod fixups;
+mod metadata;
+mod optimize;
+mod print;
+mod runtime;
+mod validate;
+
+pub use fixups::{FixupMode, Fixups, MooncVersion};
+pub use metadata::Metadata;
+pub use optimize::OptLevel;
+use print::Print;
+use runtime::Runtime;
+pub use runtime::VERSION as RUNTIME_VERSION;
+
+/// The options of `componentize()` besides its inputs.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The compiler of the core module, for the producers section.
+    pub moonc: Option<MooncVersion>,
+    pub fixups: Fixups,
+    /// The `func(s: string)` imported by the world to print to, `name` or
+    /// `interface#name`; by default the prints go to `wasi:cli/stdout` if the
+    /// world imports it, or are discarded.
+    pub print_import: Option<String>,
+    /// Adapters by the name of the module whose imports they implement, like
+    /// `wasi_snapshot_preview1`.
+    pub adapters: Vec<(String, Vec<u8>)>,
+    pub metadata: Metadata,
+    /// Keeps the function names of the core module, and its source map.
+    pub debug: bool,
+    /// The source map of the core module, see `read_source_map()`. It is
+    /// dropped when optimising.
+    pub source_map: Option<String>,
+    pub opt_level: OptLevel,
+    pub string_encoding: StringEncoding,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            moonc: None,
+            fixups: Fixups::ALL,
+            print_import: None,
+            adapters: Vec::new(),
+            metadata: Metadata::default(),
+            debug: false,
+            source_map: None,
+            opt_level: OptLevel::O0,
+            string_encoding: StringEncoding::Utf8,
+        }
+    }
+}
+
+/// The encoding of the strings in the canonical ABI.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum StringEncoding {
+    #[default]
+    Utf8,
+    Utf16,
+    CompactUtf16,
+}
+
+impl From<StringEncoding> for wit_component::StringEncoding {
+    fn from(encoding: StringEncoding) -> Self {
+        match encoding {
+            StringEncoding::Utf8 => wit_component::StringEncoding::UTF8,
+            StringEncoding::Utf16 => wit_component::StringEncoding::UTF16,
+            StringEncoding::CompactUtf16 => wit_component::StringEncoding::CompactUTF16,
+        }
+    }
+}
+
+// The options of `componentize`, also used by the build.
+#[derive(Default)]
+#[cfg_attr(feature = "clap", derive(clap::Args))]
+pub struct ConfigOpts {
+    /// Workarounds for bugs of older MoonBit compilers; `auto` applies the
+    /// ones needed by the version reported by `moon version`.
+    #[cfg_attr(feature = "clap", arg(long, value_enum, default_value_t))]
+    pub fixups: FixupMode,
+
+    /// The `func(s: string)` imported by the world for `println`, `name` or
+    /// `interface#name`; defaults to `wasi:cli/stdout` if imported.
+    #[cfg_attr(feature = "clap", arg(long, value_name = "FUNC"))]
+    pub print_import: Option<String>,
+
+    /// An adapter for the imports of the core module from `NAME`, e.g.
+    /// `wasi_snapshot_preview1=wasi_snapshot_preview1.reactor.wasm`.
+    #[cfg_attr(feature = "clap", arg(long = "adapt", value_name = "NAME=PATH"))]
+    pub adapters: Vec<String>,
+
+    /// The name of the component.
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub name: Option<String>,
+
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub description: Option<String>,
+
+    #[cfg_attr(feature = "clap", arg(long = "author", value_name = "AUTHOR"))]
+    pub authors: Vec<String>,
+
+    /// The URL of the source repository.
+    #[cfg_attr(feature = "clap", arg(long, value_name = "URL"))]
+    pub source: Option<String>,
+
+    /// Keep the function names and the source map of the MoonBit module, for
+    /// stack traces and debuggers; the build also uses the debug profile.
+    #[cfg_attr(feature = "clap", arg(long))]
+    pub debug: bool,
+
+    /// Optimise the core module, with `wasm-opt` if it is on the `PATH`.
+    #[cfg_attr(
+        feature = "clap",
+        arg(short = 'O', long, value_enum, default_value_t, value_name = "LEVEL")
+    )]
+    pub opt_level: OptLevel,
+
+    /// The encoding of the strings passed to and from the component, UTF-8
+    /// by default.
+    #[cfg_attr(feature = "clap", arg(long, value_enum))]
+    pub string_encoding: Option<StringEncoding>,
+
+    /// The name and version of the tool recorded as `processed-by`.
+    #[cfg_attr(feature = "clap", arg(skip))]
+    pub processed_by: Option<(String, String)>,
+}
+
+impl ConfigOpts {
+    pub fn config(&self) -> anyhow::Result<Config> {
+        let adapters = self
+            .adapters
+            .iter()
+            .map(|adapter| {
+                let Some((name, path)) = adapter.split_once('=') else {
+                    bail!("expected `NAME=PATH` for --adapt, found `{adapter}`");
+                };
+                let wasm = fs::read(path)
+                    .with_context(|| format!("failed to read the adapter {path:?}"))?;
+                Ok((name.to_string(), wasm))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let moonc = MooncVersion::detect();
+        match (&moonc, self.fixups) {
+            (Some(moonc), _) => println!("Detected moonc {moonc}"),
+            (None, FixupMode::Auto) => println!("Unknown MoonBit version, applying all fixups"),
+            (None, _) => {}
+        }
+        Ok(Config {
+            fixups: Fixups::new(self.fixups, moonc.as_ref()),
+            moonc,
+            print_import: self.print_import.clone(),
+            adapters,
+            metadata: Metadata {
+                name: self.name.clone(),
+                description: self.description.clone(),
+                authors: self.authors.clone(),
+                source: self.source.clone(),
+                processed_by: self.processed_by.clone(),
+            },
+            debug: self.debug,
+            source_map: None,
+            opt_level: self.opt_level,
+            string_encoding: self.string_encoding.unwrap_or_default(),
+        })
+    }
+}
 
 #[cfg_attr(feature = "clap", derive(clap::Args))]
 pub struct Opts {
+    #[cfg_attr(feature = "clap", arg(long, required_unless_present = "wasm"))]
+    wat: Option<PathBuf>,
+
+    /// A binary core module, e.g. from `moon build --target=wasm`.
+    #[cfg_attr(feature = "clap", arg(long, conflicts_with = "wat"))]
+    wasm: Option<PathBuf>,
+
+    /// The `<world>.exports.json` written by bindgen next to the bindings.
     #[cfg_attr(feature = "clap", arg(long))]
-    wat: PathBuf,
+    manifest: PathBuf,
+
+    #[cfg_attr(feature = "clap", command(flatten))]
+    pub config: ConfigOpts,
 }
 
 impl Opts {
@@ -25,9 +205,24 @@ impl Opts {
         world: WorldId,
         out_dir: Option<PathBuf>,
     ) -> anyhow::Result<()> {
-        let wat = fs::read_to_string(&self.wat)?;
-        let wasm = componentize(&wat, resolve, world)?;
-        let target = self.wat.with_extension("wasm");
+        let manifest = read_manifest(&self.manifest)?;
+        let mut config = self.config.config()?;
+        let (wasm, target) = match (&self.wat, &self.wasm) {
+            (Some(wat), _) => {
+                let text = fs::read_to_string(wat)?;
+                let wasm = componentize(&text, resolve, world, &manifest, &config)?;
+                (wasm, wat.with_extension("wasm"))
+            }
+            (None, Some(module)) => {
+                let wasm = fs::read(module)?;
+                if config.debug {
+                    config.source_map = read_source_map(&wasm, module)?;
+                }
+                let wasm = componentize_wasm(&wasm, resolve, world, &manifest, &config)?;
+                (wasm, module.with_extension("component.wasm"))
+            }
+            (None, None) => bail!("either --wat or --wasm is required"),
+        };
         let target = match out_dir {
             Some(out_dir) => out_dir.join(target.file_name().unwrap()),
             None => target,
@@ -38,145 +233,600 @@ impl Opts {
     }
 }
 
-pub fn componentize(wat: &str, resolve: Resolve, world: WorldId) -> anyhow::Result<Vec<u8>> {
-    // Run `bindgen` again to retrieve exported_symbols. This HACK can be removed once MoonBit supports custom FFI export names
-    let mut gen = wit_bindgen_mbt::MoonBit::default();
-    gen.generate(&resolve, world, &mut Files::default())?;
-    let exported_symbols = gen.exported_symbols;
-
-    // Some ABI functions for lift/lower. These can be removed once MoonBit directly supports them
-    let mut impls = HashMap::from([
-        (
-            "rael.memory_copy",
-            ParseBuffer::new(
-                "func $rael.memory_copy \
-                        (param $dest i32) (param $src i32) (param $len i32) \
-                        (memory.copy \
-                            (local.get $dest) (local.get $src) (local.get $len)\
-                        )",
-            )?,
-        ),
-        (
-            "rael.load_i32",
-            ParseBuffer::new(
-                "func $rael.load_i32 \
-                        (param $ptr i32) (result i32) \
-                        (i32.load (local.get $ptr))",
-            )?,
-        ),
-        (
-            "rael.load_i64",
-            ParseBuffer::new(
-                "func $rael.load_i64 \
-                        (param $ptr i32) (result i64) \
-                        (i64.load (local.get $ptr))",
-            )?,
-        ),
-        (
-            "rael.bytes_data",
-            ParseBuffer::new(
-                "func $rael.bytes_data \
-                        (param $str i32) (result i32) \
-                        (i32.add (local.get $str) (i32.const 4))",
-            )?,
-        ),
-        (
-            "moonbit.string_data",
-            ParseBuffer::new(
-                "func $moonbit.string_data \
-                        (param $str i32) (result i32) \
-                        (i32.add (local.get $str) (i32.const 4))",
-            )?,
-        ),
-        ("printc", ParseBuffer::new("func $printc (param $ptr i32)")?),
-    ]);
-    let mut builtins = HashMap::new();
-    let mut realloc = Some(ParseBuffer::new(
-        "func (export \"cabi_realloc\") \
-            (param i32) (param i32) (param i32) (param $len i32) (result i32) \
-            (call $rael.malloc (local.get $len))",
-    )?);
+/// Reads the export manifest written by bindgen.
+pub fn read_manifest(path: &Path) -> anyhow::Result<Manifest> {
+    let json = fs::read_to_string(path).with_context(|| {
+        format!("failed to read {path:?}, run `componentize-mbt bindgen` to generate it")
+    })?;
+    Manifest::from_json(&json).with_context(|| format!("failed to parse {path:?}"))
+}
+
+/// Reads the source map named by the `sourceMappingURL` section of the core
+/// module at `path`, if it has one.
+pub fn read_source_map(wasm: &[u8], path: &Path) -> anyhow::Result<Option<String>> {
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        if let wasmparser::Payload::CustomSection(reader) = payload? {
+            if reader.name() == "sourceMappingURL" {
+                let url = wasmparser::BinaryReader::new(reader.data()).read_string()?;
+                let map = path.parent().unwrap_or(Path::new(".")).join(url);
+                let json = fs::read_to_string(&map)
+                    .with_context(|| format!("failed to read the source map {map:?}"))?;
+                return Ok(Some(json));
+            }
+        }
+    }
+    Ok(None)
+}
+
+pub fn componentize(
+    wat: &str,
+    resolve: Resolve,
+    world: WorldId,
+    manifest: &Manifest,
+    config: &Config,
+) -> anyhow::Result<Vec<u8>> {
+    // MoonBit doesn't check the calls of builtins in the text format, they are
+    // declared here as imports and linked from the runtime like in a binary
+    // module.
+    let runtime = Runtime::load()?;
+    let imports = runtime
+        .builtins()
+        .map(|(name, ty)| {
+            let params = ty.params().iter().map(|ty| format!(" (param {ty})"));
+            let results = ty.results().iter().map(|ty| format!(" (result {ty})"));
+            let sig = params.chain(results).collect::<String>();
+            let field = format!(
+                "import \"{}\" \"{name}\" (func ${name}{sig})",
+                runtime::MODULE
+            );
+            (name, field)
+        })
+        .collect::<Vec<_>>();
+    let imports = imports
+        .iter()
+        .map(|(name, field)| Ok((*name, ParseBuffer::new(field)?)))
+        .collect::<anyhow::Result<HashMap<_, _>>>()?;
 
     let buf = ParseBuffer::new(wat)?;
     let mut ast = wast::parser::parse(&buf)?;
-    let mut start = None;
-
     if let Wat::Module(Module {
         kind: ModuleKind::Text(ref mut fields),
         ..
     }) = &mut ast
     {
-        fields.retain_mut(|field| match field {
-            ModuleField::Import(Import {
-                module: "spectest", ..
-            }) => false,
-            ModuleField::Memory(Memory {
-                exports: InlineExport { names },
-                ..
-            }) if names == &vec!["moonbit.memory"] => {
-                names[0] = "memory";
-                true
-            }
-            ModuleField::Func(Func {
-                kind: FuncKind::Inline { expression, .. },
-                exports,
-                ty,
-                ..
-            }) => {
-                if exports.names.len() == 1 {
-                    if let Some((_, key)) = exports.names[0].split_once("::") {
-                        if let Some((symbol, has_rv)) = exported_symbols.get(key) {
-                            exports.names[0] = symbol;
-
-                            // This seems to be a bug in MoonBit - pub fn has no return value, but the WASM func returns i32
-                            // This was fixed in the 2024-06-25 version of the MonnBit compiler.
-                            if !has_rv {
-                                if let Some(ty) = &mut ty.inline {
-                                    if ty.results.len() == 1 {
-                                        ty.results = Box::new([]);
-                                        let mut instrs = Default::default();
-                                        mem::swap(&mut expression.instrs, &mut instrs);
-                                        let mut instrs = instrs.into_vec();
-                                        instrs.push(Instruction::Drop);
-                                        expression.instrs = instrs.into();
-                                    }
-                                }
+        let mut defined = HashSet::new();
+        let mut called = BTreeSet::new();
+        for field in fields.iter() {
+            match field {
+                ModuleField::Func(Func { id, kind, .. }) => {
+                    defined.extend(id.map(|id| id.name()));
+                    if let FuncKind::Inline { expression, .. } = kind {
+                        for instr in expression.instrs.iter() {
+                            if let Instruction::Call(Index::Id(id))
+                            | Instruction::ReturnCall(Index::Id(id)) = instr
+                            {
+                                called.insert(id.name());
                             }
                         }
                     }
                 }
-                for instr in expression.instrs.iter() {
-                    if let Instruction::Call(Index::Id(id)) = instr {
-                        let name = id.name();
-                        if !builtins.contains_key(name) {
-                            if let Some((name, imp)) = impls.remove_entry(name) {
-                                builtins.insert(name, imp);
-                            }
-                        }
-                        if name == "rael.malloc" {
-                            if let Some(realloc) = realloc.take() {
-                                builtins.insert(name, realloc);
-                            }
-                        }
-                    }
+                ModuleField::Import(Import { item, .. }) => {
+                    defined.extend(item.id.map(|id| id.name()));
                 }
-                true
+                _ => {}
             }
-            ModuleField::Export(e) if e.name == "_start" => {
-                start = Some(e.item);
-                false
+        }
+
+        let mut missing = BTreeSet::new();
+        for name in called.into_iter().filter(|name| !defined.contains(name)) {
+            match imports.get(name) {
+                Some(import) => fields.insert(0, wast::parser::parse(import)?),
+                None => {
+                    missing.insert(name.to_string());
+                }
             }
-            _ => true,
+        }
+        if !missing.is_empty() {
+            return Err(runtime::missing(&missing));
+        }
+    }
+
+    link(&ast.encode()?, resolve, world, manifest, &runtime, config)
+}
+
+/// Like `componentize()`, but takes a binary core module.
+pub fn componentize_wasm(
+    wasm: &[u8],
+    resolve: Resolve,
+    world: WorldId,
+    manifest: &Manifest,
+    config: &Config,
+) -> anyhow::Result<Vec<u8>> {
+    link(wasm, resolve, world, manifest, &Runtime::load()?, config)
+}
+
+fn link(
+    wasm: &[u8],
+    resolve: Resolve,
+    world: WorldId,
+    manifest: &Manifest,
+    runtime: &Runtime,
+    config: &Config,
+) -> anyhow::Result<Vec<u8>> {
+    check_world(&resolve, world, manifest)?;
+    let print = Print::new(&resolve, world, config.print_import.as_deref())?;
+    let (buf, exported) = binary::rewrite(wasm, manifest, runtime, config, &print)?;
+    check_exported(manifest, &exported)?;
+    let mut buf = optimize::optimize(buf, config.opt_level, config.debug)?;
+    embed_component_metadata(&mut buf, &resolve, world, config.string_encoding.into())?;
+    let mut encoder = ComponentEncoder::default().module(&buf)?;
+    for (name, adapter) in config.adapters.iter() {
+        encoder = encoder
+            .adapter(name, adapter)
+            .with_context(|| format!("failed to use the adapter for `{name}`"))?;
+    }
+    let component = encoder
+        .encode()
+        .map_err(|err| validate::encode_error(err, &buf, manifest))?;
+    validate::validate(&component, manifest)?;
+    metadata::add(&component, &config.metadata, config.moonc.as_ref())
+}
+
+/// Exports are named after the MoonBit function, possibly qualified with the
+/// package like `author/pkg::name`.
+fn export_key(name: &str) -> &str {
+    name.rsplit("::").next().unwrap()
+}
+
+fn check_world(resolve: &Resolve, world: WorldId, manifest: &Manifest) -> anyhow::Result<()> {
+    let world_name = &resolve.worlds[world].name;
+    if &manifest.world != world_name {
+        bail!(
+            "the bindings were generated for world `{}`, not `{world_name}`; run bindgen again",
+            manifest.world
+        );
+    }
+    if manifest.runtime > RUNTIME_VERSION {
+        bail!(
+            "the bindings need version {} of the runtime, but componentize-mbt only has version {RUNTIME_VERSION}; upgrade componentize-mbt",
+            manifest.runtime
+        );
+    }
+    Ok(())
+}
+
+fn check_exported(manifest: &Manifest, exported: &HashSet<&str>) -> anyhow::Result<()> {
+    let missing = manifest
+        .exports
+        .iter()
+        .filter(|f| !exported.contains(f.function.as_str()))
+        .map(|f| f.function.as_str())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "functions not exported by the module: {}; add them to `link.wasm.exports` in moon.pkg.json",
+            missing.join(", ")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmparser::{Name, NameSectionReader, Parser, Payload, Validator, WasmFeatures};
+    use wit_bindgen_core::wit_parser::UnresolvedPackage;
+    use wit_bindgen_mbt::ExportedFunction;
+
+    use super::*;
+
+    const WIT: &str = r#"
+        package my:app;
+
+        world app {
+          import log: func(s: string);
+          export run: func() -> u32;
+        }
+    "#;
+
+    /// A module like `moon build` emits for the world `app`, printing with
+    /// `println` in `main` and calling a builtin in `run`.
+    const FIELDS: &str = r#"
+        (import "spectest" "print_char" (func $printc (param i32)))
+        (memory $moonbit.memory (export "memory") 1)
+        (func $rael.malloc (param i32) (result i32)
+          i32.const 1024)
+        (func $user/app::wasmExportRun (export "user/app::wasmExportRun") (result i32)
+          i32.const 0
+          call $rael.load_i32)
+        (func $*init*/0 (export "_start")
+          i32.const 104
+          call $printc)
+    "#;
+
+    pub(crate) fn load_world() -> (Resolve, WorldId) {
+        load_world_with_deps(&[], WIT)
+    }
+
+    /// Loads the only world of `wit`, which uses the packages `deps`.
+    fn load_world_with_deps(deps: &[&str], wit: &str) -> (Resolve, WorldId) {
+        let mut resolve = Resolve::default();
+        for dep in deps {
+            let dep = UnresolvedPackage::parse(Path::new("dep.wit"), dep).unwrap();
+            resolve.push(dep).unwrap();
+        }
+        let pkg = UnresolvedPackage::parse(Path::new("app.wit"), wit).unwrap();
+        let pkg = resolve.push(pkg).unwrap();
+        let world = resolve.select_world(pkg, None).unwrap();
+        (resolve, world)
+    }
+
+    pub(crate) fn manifest() -> Manifest {
+        Manifest {
+            world: "app".into(),
+            runtime: RUNTIME_VERSION,
+            exports: vec![ExportedFunction {
+                function: "wasmExportRun".into(),
+                export: "run".into(),
+                has_rv: true,
+                post_return: None,
+            }],
+        }
+    }
+
+    pub(crate) fn config() -> Config {
+        Config {
+            print_import: Some("log".into()),
+            ..Config::default()
+        }
+    }
+
+    /// The module in the text format, calling the builtins without importing
+    /// them.
+    fn wat() -> String {
+        format!("(module {FIELDS})")
+    }
+
+    /// The module in the binary format, importing the builtins.
+    pub(crate) fn wasm() -> Vec<u8> {
+        let wat = format!(
+            r#"(module
+                (import "rael" "load_i32" (func $rael.load_i32 (param i32) (result i32)))
+                {FIELDS})"#
+        );
+        let buf = ParseBuffer::new(&wat).unwrap();
+        wast::parser::parse::<Wat>(&buf).unwrap().encode().unwrap()
+    }
+
+    /// Validates `component` and returns its exports, the imports of its core
+    /// modules and whether one of them has a start function.
+    fn inspect(component: &[u8]) -> (Vec<String>, Vec<(String, String)>, bool) {
+        let mut validator = Validator::new_with_features(WasmFeatures {
+            component_model: true,
+            ..Default::default()
         });
+        validator.validate_all(component).unwrap();
+
+        let mut exports = Vec::new();
+        let mut imports = Vec::new();
+        let mut has_start = false;
+        for payload in Parser::new(0).parse_all(component) {
+            match payload.unwrap() {
+                Payload::ComponentExportSection(reader) => {
+                    for export in reader {
+                        exports.push(export.unwrap().name.0.to_string());
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        imports.push((import.module.to_string(), import.name.to_string()));
+                    }
+                }
+                Payload::StartSection { .. } => has_start = true,
+                _ => {}
+            }
+        }
+        (exports, imports, has_start)
+    }
+
+    fn check(component: &[u8]) {
+        let (exports, imports, has_start) = inspect(component);
+        assert_eq!(exports, ["run"]);
+        assert!(has_start, "`_start` is not the start function");
+        for (module, name) in imports.iter() {
+            assert!(
+                !["spectest", "rael", runtime::MODULE].contains(&module.as_str()),
+                "`{module}` `{name}` is still imported"
+            );
+        }
+        assert!(
+            imports.contains(&("$root".into(), "log".into())),
+            "the prints don't go to `log`: {imports:?}"
+        );
+    }
+
+    #[test]
+    fn componentizes_wat() {
+        let (resolve, world) = load_world();
+        let component = componentize(&wat(), resolve, world, &manifest(), &config()).unwrap();
+        check(&component);
+    }
+
+    #[test]
+    fn componentizes_wasm() {
+        let (resolve, world) = load_world();
+        let component = componentize_wasm(&wasm(), resolve, world, &manifest(), &config()).unwrap();
+        check(&component);
+    }
+
+    #[test]
+    fn rejects_missing_exports() {
+        let mut manifest = manifest();
+        manifest.exports[0].function = "wasmExportStop".into();
+        let (resolve, world) = load_world();
+        let err = componentize(&wat(), resolve, world, &manifest, &config()).unwrap_err();
+        assert!(err.to_string().contains("wasmExportStop"), "{err}");
+    }
+
+    #[test]
+    fn adds_metadata() {
+        let producers = |config: &Config| {
+            let (resolve, world) = load_world();
+            let component = componentize(&wat(), resolve, world, &manifest(), config).unwrap();
+            let wasm_metadata::Metadata::Component {
+                name, producers, ..
+            } = wasm_metadata::Metadata::from_binary(&component).unwrap()
+            else {
+                panic!("expected a component");
+            };
+            let fields = producers
+                .unwrap()
+                .iter()
+                .flat_map(|(field, values)| {
+                    values
+                        .iter()
+                        // Added by the encoder itself.
+                        .filter(|(name, _)| *name != "wit-component")
+                        .map(|(name, version)| format!("{field} {name} {version}"))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            (name, fields)
+        };
+
+        let detected = Config {
+            moonc: Some(MooncVersion {
+                version: "v0.1.20240703".into(),
+                date: 20240703,
+            }),
+            metadata: Metadata {
+                name: Some("app".into()),
+                processed_by: Some(("componentize-mbt".into(), "0.2.0".into())),
+                ..Default::default()
+            },
+            ..config()
+        };
+        let (name, mut fields) = producers(&detected);
+        fields.sort();
+        assert_eq!(name.as_deref(), Some("app"));
+        assert_eq!(
+            fields,
+            [
+                "language MoonBit v0.1.20240703",
+                "processed-by componentize-mbt 0.2.0"
+            ]
+        );
+
+        // The language is left out if the MoonBit version is unknown.
+        let (name, fields) = producers(&config());
+        assert_eq!(name, None);
+        assert_eq!(
+            fields,
+            [concat!(
+                "processed-by ",
+                env!("CARGO_PKG_NAME"),
+                " ",
+                env!("CARGO_PKG_VERSION")
+            )]
+        );
+    }
 
-        for buf in builtins.values() {
-            let field: ModuleField = wast::parser::parse(buf)?;
-            fields.push(field);
+    #[test]
+    fn links_builtins_used_by_the_module_only() {
+        // The function names are only kept for debugging.
+        let config = Config {
+            debug: true,
+            ..config()
+        };
+        let (resolve, world) = load_world();
+        let component = componentize(&wat(), resolve, world, &manifest(), &config).unwrap();
+        let mut names = Vec::new();
+        for payload in Parser::new(0).parse_all(&component) {
+            if let Payload::CustomSection(reader) = payload.unwrap() {
+                if reader.name() == "name" {
+                    for name in NameSectionReader::new(reader.data(), reader.data_offset()) {
+                        if let Name::Function(map) = name.unwrap() {
+                            names.extend(map.into_iter().map(|n| n.unwrap().name.to_string()));
+                        }
+                    }
+                }
+            }
         }
-        fields.push(ModuleField::Start(start.expect("_start")));
+        assert!(
+            names.iter().any(|name| name == "rael.load_i32"),
+            "{names:?}"
+        );
+        assert!(
+            !names.iter().any(|name| name == "rael.load_i64"),
+            "{names:?}"
+        );
     }
 
-    let mut buf = ast.encode()?;
-    embed_component_metadata(&mut buf, &resolve, world, StringEncoding::UTF8)?;
-    ComponentEncoder::default().module(&buf)?.encode()
+    #[test]
+    fn rejects_unknown_builtins() {
+        let wat = wat().replace("$rael.load_i32", "$rael.load_i128");
+        let (resolve, world) = load_world();
+        let err = componentize(&wat, resolve, world, &manifest(), &config()).unwrap_err();
+        assert!(err.to_string().contains("rael.load_i128"), "{err}");
+
+        let wasm = ParseBuffer::new(
+            r#"(module (import "rael" "load_i128" (func (param i32) (result i32))))"#,
+        )
+        .and_then(|buf| wast::parser::parse::<Wat>(&buf)?.encode())
+        .unwrap();
+        let (resolve, world) = load_world();
+        let err = componentize_wasm(&wasm, resolve, world, &manifest(), &config()).unwrap_err();
+        assert!(err.to_string().contains("rael.load_i128"), "{err}");
+    }
+
+    #[test]
+    fn rejects_builtins_of_another_type() {
+        let wasm = ParseBuffer::new(
+            r#"(module (import "rael" "load_i32" (func (param i64) (result i32))))"#,
+        )
+        .and_then(|buf| wast::parser::parse::<Wat>(&buf)?.encode())
+        .unwrap();
+        let (resolve, world) = load_world();
+        let err = componentize_wasm(&wasm, resolve, world, &manifest(), &config()).unwrap_err();
+        assert!(err.to_string().contains("different type"), "{err}");
+    }
+
+    const WASI_IO: &str = r#"
+        package wasi:io@0.2.0;
+
+        interface error {
+          resource error;
+        }
+
+        interface streams {
+          use error.{error};
+
+          variant stream-error {
+            last-operation-failed(error),
+            closed,
+          }
+
+          resource output-stream {
+            blocking-write-and-flush: func(contents: list<u8>) -> result<_, stream-error>;
+          }
+        }
+    "#;
+
+    const WASI_CLI: &str = r#"
+        package wasi:cli@0.2.0;
+
+        interface stdout {
+          use wasi:io/streams@0.2.0.{output-stream};
+
+          get-stdout: func() -> output-stream;
+        }
+    "#;
+
+    #[test]
+    fn prints_to_wasi_stdout() {
+        let wit = r#"
+            package my:app;
+
+            world app {
+              import wasi:cli/stdout@0.2.0;
+              export run: func() -> u32;
+            }
+        "#;
+        let config = Config::default();
+        for wasm in [None, Some(wasm())] {
+            let (resolve, world) = load_world_with_deps(&[WASI_IO, WASI_CLI], wit);
+            let component = match &wasm {
+                None => componentize(&wat(), resolve, world, &manifest(), &config),
+                Some(wasm) => componentize_wasm(wasm, resolve, world, &manifest(), &config),
+            }
+            .unwrap();
+            let (_, imports, _) = inspect(&component);
+            let stdout = ("wasi:cli/stdout@0.2.0".into(), "get-stdout".into());
+            let write = (
+                "wasi:io/streams@0.2.0".into(),
+                "[method]output-stream.blocking-write-and-flush".into(),
+            );
+            assert!(imports.contains(&stdout), "{imports:?}");
+            assert!(imports.contains(&write), "{imports:?}");
+            assert!(!imports.iter().any(|(module, _)| module == "spectest"));
+        }
+    }
+
+    #[test]
+    fn componentizes_with_adapters() {
+        // An adapter printing with an interface of its own world.
+        let adapter = r#"
+            (module
+              (import "env" "memory" (memory 0))
+              (import "my:adapter/logging" "log" (func $log (param i32 i32)))
+              (func (export "print") (param i32 i32)
+                local.get 0
+                local.get 1
+                call $log))
+        "#;
+        let mut adapter = wast::parser::parse::<Wat>(&ParseBuffer::new(adapter).unwrap())
+            .unwrap()
+            .encode()
+            .unwrap();
+        let (resolve, world) = load_world_with_deps(
+            &[],
+            "package my:adapter;
+             interface logging { log: func(s: string); }
+             world adapter { import logging; }",
+        );
+        embed_component_metadata(
+            &mut adapter,
+            &resolve,
+            world,
+            wit_component::StringEncoding::UTF8,
+        )
+        .unwrap();
+
+        let wat = wat().replace(
+            "(memory",
+            r#"(import "my_adapter" "print" (func (param i32 i32)))
+               (memory"#,
+        );
+        let config = Config {
+            adapters: vec![("my_adapter".into(), adapter)],
+            ..Config::default()
+        };
+        let (resolve, world) = load_world();
+        let component = componentize(&wat, resolve, world, &manifest(), &config).unwrap();
+        let (exports, imports, _) = inspect(&component);
+        assert_eq!(exports, ["run"]);
+        assert!(imports.contains(&("my_adapter".into(), "print".into())));
+        assert!(imports.contains(&("my:adapter/logging".into(), "log".into())));
+
+        // Without the adapter, the import can't be resolved.
+        let (resolve, world) = load_world();
+        assert!(componentize(&wat, resolve, world, &manifest(), &Config::default()).is_err());
+    }
+
+    #[test]
+    fn reports_invalid_modules_by_function() {
+        // Caught when encoding the component.
+        let mistyped = wat().replace(
+            "(result i32)\n          i32.const 0\n          call $rael.load_i32",
+            "(result i64) i64.const 0",
+        );
+        let (resolve, world) = load_world();
+        let err = componentize(&mistyped, resolve, world, &manifest(), &config()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the component is invalid in `run`, exported by the MoonBit function `wasmExportRun`"
+        );
+        assert!(format!("{err:#}").contains("type mismatch"), "{err:#}");
+
+        // Caught when validating the component.
+        let invalid = wat().replace("i32.const 0\n          call $rael.load_i32", "i64.const 0");
+        let (resolve, world) = load_world();
+        let err = componentize(&invalid, resolve, world, &manifest(), &config()).unwrap_err();
+        assert!(
+            err.to_string().starts_with(
+                "the component is invalid in `run`, exported by the MoonBit function `wasmExportRun`: type mismatch"
+            ),
+            "{err}"
+        );
+    }
 }
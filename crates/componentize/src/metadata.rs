@@ -0,0 +1,63 @@
+//! The name, producers and registry metadata of the component, as shown by
+//! `wasm-tools metadata show`.
+
+use anyhow::Result;
+use wasm_metadata::{AddMetadata, Link, LinkType, Producers, RegistryMetadata};
+
+use crate::MooncVersion;
+
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub authors: Vec<String>,
+    /// The URL of the source repository.
+    pub source: Option<String>,
+    /// The name and version of the tool recorded as `processed-by`, this
+    /// library if unset.
+    pub processed_by: Option<(String, String)>,
+}
+
+impl Metadata {
+    fn registry_metadata(&self) -> Option<RegistryMetadata> {
+        if self.description.is_none() && self.authors.is_empty() && self.source.is_none() {
+            return None;
+        }
+        let mut registry = RegistryMetadata::default();
+        registry.set_description(self.description.clone());
+        if !self.authors.is_empty() {
+            registry.set_authors(Some(self.authors.clone()));
+        }
+        registry.set_links(self.source.as_ref().map(|source| {
+            vec![Link {
+                ty: LinkType::Repository,
+                value: source.clone(),
+            }]
+        }));
+        Some(registry)
+    }
+}
+
+pub(crate) fn add(
+    component: &[u8],
+    metadata: &Metadata,
+    moonc: Option<&MooncVersion>,
+) -> Result<Vec<u8>> {
+    let mut producers = Producers::empty();
+    if let Some(moonc) = moonc {
+        producers.add("language", "MoonBit", &moonc.version);
+    }
+    let (tool, version) = metadata.processed_by.as_ref().map_or(
+        (env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        |(tool, version)| (tool.as_str(), version.as_str()),
+    );
+    producers.add("processed-by", tool, version);
+    let component = producers.add_to_wasm(component)?;
+
+    AddMetadata {
+        name: metadata.name.clone(),
+        registry_metadata: metadata.registry_metadata(),
+        ..Default::default()
+    }
+    .to_wasm(&component)
+}
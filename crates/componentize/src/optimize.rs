@@ -0,0 +1,730 @@
+//! The optimisation of the core module once the runtime is linked: with
+//! binaryen's `wasm-opt` if it's on the `PATH`, or else by inlining the small
+//! functions like the builtins and removing the unused functions.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::process::Command;
+
+use anyhow::{anyhow, bail, Context, Result};
+use wasm_encoder::{
+    CodeSection, Encode, ExportKind, ExportSection, Function, FunctionSection, Instruction,
+    RawSection, StartSection, ValType,
+};
+use wasmparser::{
+    ElementItems, ElementKind, ExternalKind, FunctionBody, NameSectionReader, Operator,
+    OperatorsReader, Parser, Payload, TypeRef,
+};
+
+use crate::{binary, debug};
+
+/// How much to optimise the core module, passed on to `wasm-opt`. Without it,
+/// `1` removes the unused functions, `2` also inlines the small functions
+/// without control flow like the builtins, and `3` larger ones.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum OptLevel {
+    #[default]
+    #[cfg_attr(feature = "clap", value(name = "0"))]
+    O0,
+    #[cfg_attr(feature = "clap", value(name = "1"))]
+    O1,
+    #[cfg_attr(feature = "clap", value(name = "2"))]
+    O2,
+    #[cfg_attr(feature = "clap", value(name = "3"))]
+    O3,
+}
+
+impl OptLevel {
+    /// The number of instructions of the functions to inline.
+    fn inline_limit(self) -> Option<usize> {
+        match self {
+            OptLevel::O0 | OptLevel::O1 => None,
+            OptLevel::O2 => Some(8),
+            OptLevel::O3 => Some(32),
+        }
+    }
+}
+
+/// The features used by MoonBit and the runtime, besides the default ones of
+/// `wasm-opt`.
+const FEATURES: &[&str] = &[
+    "--enable-bulk-memory",
+    "--enable-multivalue",
+    "--enable-mutable-globals",
+    "--enable-nontrapping-float-to-int",
+    "--enable-reference-types",
+    "--enable-sign-ext",
+];
+
+pub(crate) fn optimize(wasm: Vec<u8>, level: OptLevel, debug: bool) -> Result<Vec<u8>> {
+    if level == OptLevel::O0 {
+        return Ok(wasm);
+    }
+    if let Some(wasm) = wasm_opt(&wasm, level, debug)? {
+        return Ok(wasm);
+    }
+    println!("wasm-opt not found, applying the built-in optimisations");
+    let wasm = match level.inline_limit() {
+        Some(limit) => inline(&wasm, limit)?,
+        None => wasm,
+    };
+    eliminate(&wasm)
+}
+
+/// Runs `wasm-opt`, unless it's not installed.
+fn wasm_opt(wasm: &[u8], level: OptLevel, debug: bool) -> Result<Option<Vec<u8>>> {
+    let input = std::env::temp_dir().join(format!("componentize-mbt-{}.wasm", std::process::id()));
+    let output = input.with_extension("opt.wasm");
+    fs::write(&input, wasm).with_context(|| format!("failed to write {input:?}"))?;
+    let mut cmd = Command::new("wasm-opt");
+    cmd.arg(format!("-O{}", level as u8)).args(FEATURES);
+    if debug {
+        cmd.arg("-g");
+    }
+    cmd.arg(&input).arg("-o").arg(&output);
+    let result = cmd.output();
+    fs::remove_file(&input)?;
+    let out = match result {
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        result => result.context("failed to run wasm-opt")?,
+    };
+    println!("Execute: {cmd:?}");
+    if !out.status.success() {
+        bail!(
+            "wasm-opt failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    let wasm = fs::read(&output).with_context(|| format!("failed to read {output:?}"))?;
+    fs::remove_file(&output)?;
+    Ok(Some(wasm))
+}
+
+/// Inlines the calls to the functions of at most `limit` instructions, without
+/// locals or control flow: their arguments are moved to new locals of the
+/// caller, followed by their body.
+fn inline(wasm: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut types = Vec::new();
+    let mut funcs = Vec::new();
+    let mut imported = 0;
+    let mut bodies = HashMap::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for group in reader {
+                    for ty in group?.into_types() {
+                        types.push(match ty.composite_type {
+                            wasmparser::CompositeType::Func(f) => Some(f),
+                            _ => None,
+                        });
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Func(ty) = import?.ty {
+                        funcs.push(ty);
+                        imported += 1;
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    funcs.push(ty?);
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                bodies.insert(imported + bodies.len() as u32, body);
+            }
+            _ => {}
+        }
+    }
+    let params = |index: u32| -> Result<&[wasmparser::ValType]> {
+        let ty = types[funcs[index as usize] as usize]
+            .as_ref()
+            .ok_or_else(|| anyhow!("function {index} has no function type"))?;
+        Ok(ty.params())
+    };
+    let mut inlined = HashMap::new();
+    for (index, body) in bodies.iter() {
+        if let Some(callee) = Inlined::new(body, params(*index)?.len(), limit)? {
+            inlined.insert(*index, callee);
+        }
+    }
+    if inlined.is_empty() {
+        return Ok(wasm.to_vec());
+    }
+
+    let mut module = wasm_encoder::Module::new();
+    let mut code = CodeSection::new();
+    let mut index = imported;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::CodeSectionEntry(body) => {
+                let mut locals = Vec::new();
+                let mut next_local = params(index)?.len() as u32;
+                for local in body.get_locals_reader()? {
+                    let (count, ty) = local?;
+                    locals.push((count, val_type(ty)?));
+                    next_local += count;
+                }
+                // The locals for the arguments used by each inlined function.
+                let mut args = HashMap::new();
+                let mut bytes = Vec::new();
+                let mut reader = body.get_operators_reader()?;
+                let mut copied = reader.original_position();
+                while !reader.eof() {
+                    let (op, offset) = reader.read_with_offset()?;
+                    let Operator::Call { function_index } = op else {
+                        continue;
+                    };
+                    let Some(callee) = inlined.get(&function_index) else {
+                        continue;
+                    };
+                    bytes.extend_from_slice(&wasm[copied..offset]);
+                    copied = reader.original_position();
+                    let args = match args.entry(function_index) {
+                        Entry::Occupied(entry) => entry.into_mut(),
+                        Entry::Vacant(entry) => {
+                            let mut args = Vec::new();
+                            for (ty, used) in params(function_index)?.iter().zip(&callee.used) {
+                                args.push(used.then_some(next_local));
+                                if *used && !callee.on_stack {
+                                    locals.push((1, val_type(*ty)?));
+                                    next_local += 1;
+                                }
+                            }
+                            entry.insert(args)
+                        }
+                    };
+                    callee.copy(wasm, args, &mut bytes)?;
+                }
+                bytes.extend_from_slice(&wasm[copied..reader.original_position()]);
+                let mut f = Function::new(locals);
+                f.raw(bytes);
+                code.function(&f);
+                index += 1;
+                if index as usize == funcs.len() {
+                    module.section(&code);
+                }
+            }
+            Payload::CodeSectionStart { .. } | Payload::Version { .. } | Payload::End(_) => {}
+            payload => copy_section(&mut module, wasm, &payload)?,
+        }
+    }
+    Ok(module.finish())
+}
+
+/// A function small enough to inline, with no locals and no control flow.
+struct Inlined<'a> {
+    body: &'a FunctionBody<'a>,
+    /// Which parameters the function uses.
+    used: Vec<bool>,
+    /// Whether the function starts by getting all its parameters in order and
+    /// doesn't use them otherwise, so that the arguments stay on the stack.
+    on_stack: bool,
+}
+
+impl<'a> Inlined<'a> {
+    fn new(body: &'a FunctionBody<'a>, params: usize, limit: usize) -> Result<Option<Self>> {
+        if body.get_locals_reader()?.get_count() != 0 {
+            return Ok(None);
+        }
+        let mut used = vec![false; params];
+        let mut on_stack = true;
+        let mut reader = body.get_operators_reader()?;
+        let mut count = 0;
+        while !reader.eof() {
+            match reader.read()? {
+                Operator::End if reader.eof() => {}
+                Operator::LocalGet { local_index } if count < params => {
+                    on_stack &= local_index as usize == count;
+                    used[local_index as usize] = true;
+                    count += 1;
+                }
+                Operator::LocalGet { local_index }
+                | Operator::LocalSet { local_index }
+                | Operator::LocalTee { local_index } => {
+                    on_stack = false;
+                    used[local_index as usize] = true;
+                    count += 1;
+                }
+                Operator::Block { .. }
+                | Operator::Loop { .. }
+                | Operator::If { .. }
+                | Operator::Else
+                | Operator::End
+                | Operator::Br { .. }
+                | Operator::BrIf { .. }
+                | Operator::BrTable { .. }
+                | Operator::BrOnNull { .. }
+                | Operator::BrOnNonNull { .. }
+                | Operator::Return
+                | Operator::ReturnCall { .. }
+                | Operator::ReturnCallIndirect { .. }
+                | Operator::ReturnCallRef { .. }
+                | Operator::Try { .. }
+                | Operator::Catch { .. }
+                | Operator::CatchAll
+                | Operator::Delegate { .. }
+                | Operator::Rethrow { .. } => return Ok(None),
+                _ => {
+                    on_stack &= count >= params;
+                    count += 1;
+                }
+            }
+            if count > limit {
+                return Ok(None);
+            }
+        }
+        on_stack &= count >= params;
+        Ok(Some(Inlined {
+            body,
+            used,
+            on_stack,
+        }))
+    }
+
+    /// Copies the body without its `end` in place of a call, with the used
+    /// parameters in the caller's locals `args`.
+    fn copy(&self, wasm: &[u8], args: &[Option<u32>], out: &mut Vec<u8>) -> Result<()> {
+        let mut reader = self.body.get_operators_reader()?;
+        if self.on_stack {
+            for _ in args {
+                reader.read()?;
+            }
+        } else {
+            for arg in args.iter().rev() {
+                match arg {
+                    Some(local) => Instruction::LocalSet(*local),
+                    None => Instruction::Drop,
+                }
+                .encode(out);
+            }
+        }
+        let arg = |index: u32| args[index as usize].unwrap();
+        while !reader.eof() {
+            let (op, offset) = reader.read_with_offset()?;
+            let instr = match op {
+                Operator::LocalGet { local_index } => Instruction::LocalGet(arg(local_index)),
+                Operator::LocalSet { local_index } => Instruction::LocalSet(arg(local_index)),
+                Operator::LocalTee { local_index } => Instruction::LocalTee(arg(local_index)),
+                Operator::End if reader.eof() => break,
+                _ => {
+                    out.extend_from_slice(&wasm[offset..reader.original_position()]);
+                    continue;
+                }
+            };
+            instr.encode(out);
+        }
+        Ok(())
+    }
+}
+
+/// Removes the defined functions that are neither exported nor referenced, in
+/// the start function, the element segments and the globals, nor called by
+/// the other functions kept. The imports are kept as the world declares them.
+fn eliminate(wasm: &[u8]) -> Result<Vec<u8>> {
+    let mut imported = 0;
+    let mut bodies = Vec::new();
+    let mut roots = Vec::new();
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Func(_) = import?.ty {
+                        imported += 1;
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        roots.push(export.index);
+                    }
+                }
+            }
+            Payload::StartSection { func, .. } => roots.push(func),
+            Payload::GlobalSection(reader) => {
+                for global in reader {
+                    referenced(global?.init_expr.get_operators_reader(), &mut roots)?;
+                }
+            }
+            Payload::ElementSection(reader) => {
+                for element in reader {
+                    let element = element?;
+                    if let ElementKind::Active { offset_expr, .. } = element.kind {
+                        referenced(offset_expr.get_operators_reader(), &mut roots)?;
+                    }
+                    match element.items {
+                        ElementItems::Functions(reader) => {
+                            for func in reader {
+                                roots.push(func?);
+                            }
+                        }
+                        ElementItems::Expressions(_, reader) => {
+                            for expr in reader {
+                                referenced(expr?.get_operators_reader(), &mut roots)?;
+                            }
+                        }
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => bodies.push(body),
+            _ => {}
+        }
+    }
+
+    let mut used = HashSet::new();
+    while let Some(index) = roots.pop() {
+        if !used.insert(index) {
+            continue;
+        }
+        if let Some(body) = index.checked_sub(imported).map(|i| &bodies[i as usize]) {
+            referenced(body.get_operators_reader()?, &mut roots)?;
+        }
+    }
+    if used.iter().filter(|index| **index >= imported).count() == bodies.len() {
+        return Ok(wasm.to_vec());
+    }
+    let mut funcs = (0..imported).collect::<Vec<_>>();
+    let mut next = imported;
+    for i in 0..bodies.len() as u32 {
+        if used.contains(&(imported + i)) {
+            funcs.push(next);
+            next += 1;
+        } else {
+            funcs.push(u32::MAX);
+        }
+    }
+    let kept = |index: u32| funcs[index as usize] != u32::MAX;
+
+    let mut module = wasm_encoder::Module::new();
+    let mut code = CodeSection::new();
+    let mut index = imported;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::FunctionSection(reader) => {
+                let mut section = FunctionSection::new();
+                for (i, ty) in reader.into_iter().enumerate() {
+                    let ty = ty?;
+                    if kept(imported + i as u32) {
+                        section.function(ty);
+                    }
+                }
+                module.section(&section);
+            }
+            Payload::ExportSection(reader) => {
+                let mut section = ExportSection::new();
+                for export in reader {
+                    let export = export?;
+                    let index = match export.kind {
+                        ExternalKind::Func => funcs[export.index as usize],
+                        _ => export.index,
+                    };
+                    section.export(export.name, ExportKind::from(export.kind), index);
+                }
+                module.section(&section);
+            }
+            Payload::StartSection { func, .. } => {
+                module.section(&StartSection {
+                    function_index: funcs[func as usize],
+                });
+            }
+            Payload::GlobalSection(reader) => {
+                module.section(&binary::copy_globals(wasm, reader, &funcs)?);
+            }
+            Payload::ElementSection(reader) => {
+                module.section(&binary::copy_elements(wasm, reader, &funcs)?);
+            }
+            Payload::CodeSectionEntry(body) => {
+                if kept(index) {
+                    let ops = body.get_operators_reader()?;
+                    let mut bytes = wasm[body.range().start..ops.original_position()].to_vec();
+                    binary::rewrite_ops(wasm, ops, &funcs, &mut bytes, &mut Vec::new())?;
+                    code.raw(&bytes);
+                }
+                index += 1;
+                if index == imported + bodies.len() as u32 {
+                    module.section(&code);
+                }
+            }
+            Payload::CustomSection(reader) if reader.name() == "name" => {
+                let reader = NameSectionReader::new(reader.data(), reader.data_offset());
+                module.section(&debug::names(reader, &funcs, &BTreeMap::new())?);
+            }
+            Payload::CodeSectionStart { .. } | Payload::Version { .. } | Payload::End(_) => {}
+            payload => copy_section(&mut module, wasm, &payload)?,
+        }
+    }
+    Ok(module.finish())
+}
+
+/// Adds the functions called or referenced by the instructions of `reader`.
+fn referenced(mut reader: OperatorsReader, funcs: &mut Vec<u32>) -> Result<()> {
+    while !reader.eof() {
+        match reader.read()? {
+            Operator::Call { function_index }
+            | Operator::ReturnCall { function_index }
+            | Operator::RefFunc { function_index } => funcs.push(function_index),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn copy_section(module: &mut wasm_encoder::Module, wasm: &[u8], payload: &Payload) -> Result<()> {
+    match payload {
+        Payload::CustomSection(reader) => {
+            module.section(&wasm_encoder::CustomSection {
+                name: reader.name().into(),
+                data: reader.data().into(),
+            });
+        }
+        payload => match payload.as_section() {
+            Some((id, range)) => {
+                module.section(&RawSection {
+                    id,
+                    data: &wasm[range],
+                });
+            }
+            None => bail!("unsupported section in the core module"),
+        },
+    }
+    Ok(())
+}
+
+fn val_type(ty: wasmparser::ValType) -> Result<ValType> {
+    ValType::try_from(ty).map_err(|_| anyhow!("unsupported value type {ty:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::{Engine, Linker, Module, Store};
+    use wasmparser::{Name, Validator};
+
+    use super::*;
+
+    fn wat(wat: &str) -> Vec<u8> {
+        let buf = wast::parser::ParseBuffer::new(wat).unwrap();
+        let wasm = wast::parser::parse::<wast::Wat>(&buf)
+            .unwrap()
+            .encode()
+            .unwrap();
+        Validator::new().validate_all(&wasm).unwrap();
+        wasm
+    }
+
+    /// Calls the export `run` of the module, after validating it.
+    fn run(wasm: &[u8], arg: i32) -> i32 {
+        Validator::new().validate_all(wasm).unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let run = instance.get_typed_func::<i32, i32>(&store, "run").unwrap();
+        run.call(&mut store, arg).unwrap()
+    }
+
+    /// The names of the functions and the number of calls in their bodies.
+    fn functions(wasm: &[u8]) -> Vec<(String, usize)> {
+        let mut names = Vec::new();
+        let mut calls = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.unwrap() {
+                Payload::CodeSectionEntry(body) => {
+                    let mut reader = body.get_operators_reader().unwrap();
+                    let mut count = 0;
+                    while !reader.eof() {
+                        if let Operator::Call { .. } = reader.read().unwrap() {
+                            count += 1;
+                        }
+                    }
+                    calls.push(count);
+                }
+                Payload::CustomSection(reader) if reader.name() == "name" => {
+                    for name in NameSectionReader::new(reader.data(), reader.data_offset()) {
+                        if let Name::Function(map) = name.unwrap() {
+                            names.extend(map.into_iter().map(|n| n.unwrap().name.to_string()));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        names.into_iter().zip(calls).collect()
+    }
+
+    #[test]
+    fn inlines_small_functions() {
+        let wasm = wat(r#"
+            (module
+              (func $add (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add)
+              (func $run (export "run") (param i32) (result i32)
+                local.get 0
+                i32.const 2
+                call $add
+                i32.const 3
+                call $add))
+        "#);
+        let inlined = inline(&wasm, 8).unwrap();
+        assert_eq!(functions(&inlined), [("add".into(), 0), ("run".into(), 0)]);
+        assert_eq!(run(&inlined, 1), 6);
+
+        // Too large for the limit.
+        assert_eq!(inline(&wasm, 2).unwrap(), wasm);
+    }
+
+    #[test]
+    fn inlines_with_arguments_in_locals() {
+        // The arguments are computed and the parameters used out of order,
+        // more than once or not at all.
+        let wasm = wat(r#"
+            (module
+              (func $sub (param i32 i32 i32) (result i32)
+                local.get 1
+                local.get 0
+                i32.sub
+                local.get 0
+                i32.mul)
+              (func $run (export "run") (param i32) (result i32)
+                (local i64)
+                local.get 0
+                i32.const 1
+                i32.add
+                i32.const 10
+                i32.const 99
+                call $sub
+                i32.const 20
+                i32.const 0
+                call $sub))
+        "#);
+        let inlined = inline(&wasm, 8).unwrap();
+        assert_eq!(functions(&inlined)[1], ("run".into(), 0));
+        // (10 - (x + 1)) * (x + 1), then (20 - y) * y.
+        let sub = |a: i32, b: i32| (b - a) * a;
+        assert_eq!(run(&inlined, 3), sub(sub(4, 10), 20));
+        assert_eq!(run(&wasm, 3), run(&inlined, 3));
+    }
+
+    #[test]
+    fn keeps_calls_with_locals_or_control_flow() {
+        let wasm = wat(r#"
+            (module
+              (func $max (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                local.get 0
+                local.get 1
+                i32.gt_s
+                select)
+              (func $abs (param i32) (result i32)
+                local.get 0
+                i32.const 0
+                i32.lt_s
+                if (result i32)
+                  i32.const 0
+                  local.get 0
+                  i32.sub
+                else
+                  local.get 0
+                end)
+              (func $double (param i32) (result i32)
+                (local i32)
+                local.get 0
+                local.tee 1
+                local.get 1
+                i32.add)
+              (func $run (export "run") (param i32) (result i32)
+                local.get 0
+                call $abs
+                call $double
+                i32.const 5
+                call $max))
+        "#);
+        let inlined = inline(&wasm, 8).unwrap();
+        assert_eq!(
+            functions(&inlined),
+            [
+                ("max".into(), 0),
+                ("abs".into(), 0),
+                ("double".into(), 0),
+                ("run".into(), 2)
+            ]
+        );
+        assert_eq!(run(&inlined, -4), 8);
+        assert_eq!(run(&inlined, 1), 5);
+    }
+
+    #[test]
+    fn eliminates_unused_functions() {
+        let wasm = wat(r#"
+            (module
+              (type $get (func (result i32)))
+              (table 3 funcref)
+              (elem (i32.const 0) func $in_table)
+              (elem (i32.const 1) funcref (ref.func $in_expr))
+              (elem declare func $declared)
+              (global $ref funcref (ref.func $in_global))
+              (func $unused (result i32)
+                call $called)
+              (func $in_table (result i32)
+                i32.const 1)
+              (func $in_expr (result i32)
+                i32.const 10)
+              (func $declared (result i32)
+                i32.const 100)
+              (func $in_global (result i32)
+                i32.const 1000)
+              (func $called (result i32)
+                i32.const 10000)
+              (func $dead (result i32)
+                call $dead)
+              (func $run (export "run") (param i32) (result i32)
+                i32.const 2
+                ref.func $declared
+                table.set
+                i32.const 0
+                call_indirect (type $get)
+                i32.const 1
+                call_indirect (type $get)
+                i32.add
+                i32.const 2
+                call_indirect (type $get)
+                i32.add
+                call $called
+                i32.add))
+        "#);
+        let eliminated = eliminate(&wasm).unwrap();
+        let names = functions(&eliminated)
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                "in_table",
+                "in_expr",
+                "declared",
+                "in_global",
+                "called",
+                "run"
+            ]
+        );
+        assert_eq!(run(&eliminated, 0), 10111);
+
+        // Nothing else to remove.
+        assert_eq!(eliminate(&eliminated).unwrap(), eliminated);
+    }
+}
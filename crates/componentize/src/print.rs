@@ -0,0 +1,76 @@
+//! Where the prints of spectest go in the component.
+
+use anyhow::{bail, Result};
+use wit_bindgen_core::wit_parser::{Resolve, Type, WorldId, WorldItem, WorldKey};
+
+/// The core module name of the functions imported by the world itself.
+const ROOT_MODULE: &str = "$root";
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Print {
+    /// The world has nowhere to print to.
+    Discard,
+    /// To `wasi:cli/stdout`, with the import names of `wasi:cli/stdout` and
+    /// `wasi:io/streams` as imported by the world.
+    Stdout { stdout: String, streams: String },
+    /// To a `func(s: string)` imported by the world.
+    Host { module: String, name: String },
+}
+
+impl Print {
+    /// `import` is the function to print to, `name` for a function imported by
+    /// the world itself or `interface#name`. Without one, the prints go to
+    /// `wasi:cli/stdout` if the world imports it.
+    pub fn new(resolve: &Resolve, world: WorldId, import: Option<&str>) -> Result<Self> {
+        let imports = &resolve.worlds[world].imports;
+        let Some(import) = import else {
+            let find = |interface: &str| {
+                imports
+                    .keys()
+                    .map(|key| resolve.name_world_key(key))
+                    .find(|name| unversioned(name) == interface)
+            };
+            return Ok(match (find("wasi:cli/stdout"), find("wasi:io/streams")) {
+                (Some(stdout), Some(streams)) => Print::Stdout { stdout, streams },
+                _ => Print::Discard,
+            });
+        };
+
+        let (interface, name) = match import.rsplit_once('#') {
+            Some((interface, name)) => (Some(interface), name),
+            None => (None, import),
+        };
+        let found = imports
+            .iter()
+            .find_map(|(key, item)| match (interface, item) {
+                (None, WorldItem::Function(func)) if func.name == name => {
+                    Some((ROOT_MODULE.to_string(), func))
+                }
+                (Some(interface), WorldItem::Interface(id)) if matches(resolve, key, interface) => {
+                    let func = resolve.interfaces[*id].functions.get(name)?;
+                    Some((resolve.name_world_key(key), func))
+                }
+                _ => None,
+            });
+        let Some((module, func)) = found else {
+            bail!("the world does not import `{import}` to print to");
+        };
+        if !matches!(func.params[..], [(_, Type::String)]) || func.results.len() != 0 {
+            bail!("`{import}` must be a `func(s: string)` to print to");
+        }
+        Ok(Print::Host {
+            module,
+            name: func.name.clone(),
+        })
+    }
+}
+
+/// Whether `key` is `interface`, with or without the version.
+fn matches(resolve: &Resolve, key: &WorldKey, interface: &str) -> bool {
+    let name = resolve.name_world_key(key);
+    name == interface || unversioned(&name) == interface
+}
+
+fn unversioned(name: &str) -> &str {
+    name.split('@').next().unwrap()
+}
@@ -0,0 +1,402 @@
+//! The builtins linked into the MoonBit module, see `runtime.wat`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+use wasm_encoder::{Encode, Instruction};
+use wasmparser::{
+    ExternalKind, FuncType, GlobalType, Name, NameSectionReader, Operator, Parser, Payload, TypeRef,
+};
+
+/// The version of `runtime.wat`, shared with bindgen so that the manifests
+/// of the bindings require this runtime.
+pub use wit_bindgen_mbt::RUNTIME_VERSION as VERSION;
+
+/// Imports from this module name are resolved to the runtime by `name`.
+pub(crate) const MODULE: &str = "componentize-mbt:runtime";
+
+pub(crate) struct Runtime {
+    pub wasm: Vec<u8>,
+    /// The imported functions, see `runtime.wat` for how they are resolved.
+    pub imports: Vec<RuntimeImport>,
+    pub funcs: Vec<RuntimeFunc>,
+    /// The type and the range of the init expression of the globals.
+    pub globals: Vec<(GlobalType, Range<usize>)>,
+    exports: HashMap<String, u32>,
+    names: HashMap<u32, String>,
+}
+
+pub(crate) struct RuntimeImport {
+    pub module: String,
+    pub name: String,
+    pub ty: FuncType,
+}
+
+pub(crate) struct RuntimeFunc {
+    pub ty: FuncType,
+    /// The body, split around the indices of the called functions and the
+    /// accessed globals.
+    chunks: Vec<Range<usize>>,
+    relocs: Vec<Reloc>,
+}
+
+enum Reloc {
+    Call(u32),
+    GlobalGet(u32),
+    GlobalSet(u32),
+}
+
+impl RuntimeFunc {
+    /// The body with `funcs` and `globals` as the indices of the runtime
+    /// functions and globals.
+    pub fn encode(&self, wasm: &[u8], funcs: &[u32], globals: &[u32]) -> Vec<u8> {
+        let mut body = wasm[self.chunks[0].clone()].to_vec();
+        for (reloc, chunk) in self.relocs.iter().zip(&self.chunks[1..]) {
+            match reloc {
+                Reloc::Call(f) => Instruction::Call(funcs[*f as usize]),
+                Reloc::GlobalGet(g) => Instruction::GlobalGet(globals[*g as usize]),
+                Reloc::GlobalSet(g) => Instruction::GlobalSet(globals[*g as usize]),
+            }
+            .encode(&mut body);
+            body.extend_from_slice(&wasm[chunk.clone()]);
+        }
+        body
+    }
+
+    fn calls(&self) -> impl Iterator<Item = u32> + '_ {
+        self.relocs.iter().filter_map(|reloc| match reloc {
+            Reloc::Call(f) => Some(*f),
+            _ => None,
+        })
+    }
+
+    fn globals(&self) -> impl Iterator<Item = u32> + '_ {
+        self.relocs.iter().filter_map(|reloc| match reloc {
+            Reloc::GlobalGet(g) | Reloc::GlobalSet(g) => Some(*g),
+            _ => None,
+        })
+    }
+}
+
+impl Runtime {
+    pub fn load() -> Result<Self> {
+        let wasm = wast::parser::parse::<wast::Wat>(&wast::parser::ParseBuffer::new(
+            include_str!("runtime.wat"),
+        )?)?
+        .encode()
+        .context("failed to build the runtime")?;
+
+        let mut types = Vec::new();
+        let mut imports = Vec::new();
+        let mut func_types = Vec::new();
+        let mut funcs = Vec::new();
+        let mut globals = Vec::new();
+        let mut exports = HashMap::new();
+        let mut names = HashMap::new();
+        for payload in Parser::new(0).parse_all(&wasm) {
+            match payload? {
+                Payload::TypeSection(reader) => {
+                    for group in reader {
+                        for ty in group?.into_types() {
+                            types.push(match ty.composite_type {
+                                wasmparser::CompositeType::Func(f) => f,
+                                _ => bail!("unsupported type in the runtime"),
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        if let TypeRef::Func(ty) = import.ty {
+                            imports.push(RuntimeImport {
+                                module: import.module.to_string(),
+                                name: import.name.to_string(),
+                                ty: types[ty as usize].clone(),
+                            });
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        func_types.push(types[ty? as usize].clone());
+                    }
+                }
+                Payload::GlobalSection(reader) => {
+                    for global in reader {
+                        let global = global?;
+                        let mut ops = global.init_expr.get_operators_reader();
+                        let start = ops.original_position();
+                        while !ops.eof() {
+                            ops.read()?;
+                        }
+                        // Without the trailing `end`, like `ConstExpr::raw`.
+                        globals.push((global.ty, start..ops.original_position() - 1));
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export?;
+                        if export.kind == ExternalKind::Func {
+                            exports.insert(export.name.to_string(), export.index);
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let mut chunks = Vec::new();
+                    let mut relocs = Vec::new();
+                    let mut copied = body.range().start;
+                    let mut ops = body.get_operators_reader()?;
+                    while !ops.eof() {
+                        let (op, offset) = ops.read_with_offset()?;
+                        let reloc = match op {
+                            Operator::Call { function_index } => Reloc::Call(function_index),
+                            Operator::GlobalGet { global_index } => Reloc::GlobalGet(global_index),
+                            Operator::GlobalSet { global_index } => Reloc::GlobalSet(global_index),
+                            _ => continue,
+                        };
+                        chunks.push(copied..offset);
+                        relocs.push(reloc);
+                        copied = ops.original_position();
+                    }
+                    chunks.push(copied..body.range().end);
+                    funcs.push(RuntimeFunc {
+                        ty: func_types[funcs.len()].clone(),
+                        chunks,
+                        relocs,
+                    });
+                }
+                Payload::CustomSection(reader) if reader.name() == "name" => {
+                    for name in NameSectionReader::new(reader.data(), reader.data_offset()) {
+                        if let Name::Function(map) = name? {
+                            for naming in map {
+                                let naming = naming?;
+                                names.insert(naming.index, naming.name.to_string());
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(Runtime {
+            wasm,
+            imports,
+            funcs,
+            globals,
+            exports,
+            names,
+        })
+    }
+
+    /// The runtime function index of the builtin `name`.
+    pub fn get(&self, name: &str) -> Option<u32> {
+        self.exports.get(name).copied()
+    }
+
+    /// The name of a function for the name section, which is its `$id` or
+    /// else its export name.
+    pub fn name(&self, index: u32) -> Option<&str> {
+        self.names.get(&index).map(String::as_str).or_else(|| {
+            self.exports
+                .iter()
+                .find(|(_, i)| **i == index)
+                .map(|(name, _)| name.as_str())
+        })
+    }
+
+    pub fn func(&self, index: u32) -> &RuntimeFunc {
+        &self.funcs[index as usize - self.imports.len()]
+    }
+
+    /// The names and types of all builtins.
+    pub fn builtins(&self) -> impl Iterator<Item = (&str, &FuncType)> {
+        self.exports
+            .iter()
+            .map(|(name, index)| (name.as_str(), &self.func(*index).ty))
+    }
+
+    /// The defined functions needed by `roots`, in index order.
+    pub fn link(&self, roots: impl IntoIterator<Item = u32>) -> BTreeSet<u32> {
+        let mut linked = BTreeSet::new();
+        let mut stack = roots.into_iter().collect::<Vec<_>>();
+        while let Some(index) = stack.pop() {
+            if (index as usize) < self.imports.len() || !linked.insert(index) {
+                continue;
+            }
+            stack.extend(self.func(index).calls());
+        }
+        linked
+    }
+
+    /// The runtime imports used by the `linked` functions.
+    pub fn imports_of(&self, linked: &BTreeSet<u32>) -> Vec<(u32, &RuntimeImport)> {
+        linked
+            .iter()
+            .flat_map(|index| self.func(*index).calls())
+            .filter(|index| (*index as usize) < self.imports.len())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|index| (index, &self.imports[index as usize]))
+            .collect()
+    }
+
+    /// The globals used by the `linked` functions, in index order.
+    pub fn globals_of(&self, linked: &BTreeSet<u32>) -> BTreeSet<u32> {
+        linked
+            .iter()
+            .flat_map(|index| self.func(*index).globals())
+            .collect()
+    }
+}
+
+/// The builtin a function import of the MoonBit module refers to, if any.
+pub(crate) fn builtin_name(module: &str, name: &str) -> Option<String> {
+    match module {
+        MODULE => Some(name.to_string()),
+        "rael" | "moonbit" => Some(format!("{module}.{name}")),
+        _ => None,
+    }
+}
+
+pub(crate) fn missing(names: &BTreeSet<String>) -> anyhow::Error {
+    anyhow!(
+        "the componentize-mbt runtime (version {VERSION}) does not provide: {}",
+        names.iter().cloned().collect::<Vec<_>>().join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmi::{Caller, Engine, Instance, Linker, Memory, MemoryType, Module, Store};
+
+    /// Instantiates the runtime with a bump allocator for `rael.malloc`,
+    /// whose next address is the data of the store.
+    fn instantiate() -> (Store<i32>, Instance) {
+        let wasm = wast::parser::parse::<wast::Wat>(
+            &wast::parser::ParseBuffer::new(include_str!("runtime.wat")).unwrap(),
+        )
+        .unwrap()
+        .encode()
+        .unwrap();
+        let engine = Engine::default();
+        let module = Module::new(&engine, &wasm[..]).unwrap();
+        let mut store = Store::new(&engine, 1024);
+        let memory = Memory::new(&mut store, MemoryType::new(1, None).unwrap()).unwrap();
+        let mut linker = Linker::new(&engine);
+        linker.define("env", "memory", memory).unwrap();
+        linker
+            .func_wrap(
+                "env",
+                "rael.malloc",
+                |mut caller: Caller<i32>, size: i32| {
+                    let ptr = *caller.data();
+                    *caller.data_mut() += size;
+                    ptr
+                },
+            )
+            .unwrap();
+        linker
+            .func_wrap("print", "write", |_: i32, _: i32| {})
+            .unwrap();
+        linker
+            .func_wrap("wasi:cli/stdout", "get-stdout", || 0)
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasi:io/streams",
+                "[method]output-stream.blocking-write-and-flush",
+                |_: i32, _: i32, _: i32, _: i32| {},
+            )
+            .unwrap();
+        linker
+            .func_wrap(
+                "wasi:io/streams",
+                "[resource-drop]output-stream",
+                |_: i32| {},
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        (store, instance)
+    }
+
+    fn malloc(store: &mut Store<i32>, instance: &Instance, size: i32, align: i32) -> i32 {
+        let func = instance
+            .get_typed_func::<(i32, i32), i32>(&*store, "rael.malloc_aligned")
+            .unwrap();
+        let ptr = func.call(&mut *store, (size, align)).unwrap();
+        assert_eq!(ptr % align, 0, "{ptr} is not aligned to {align}");
+        ptr
+    }
+
+    fn free(store: &mut Store<i32>, instance: &Instance, ptr: i32) {
+        let func = instance
+            .get_typed_func::<i32, ()>(&*store, "rael.free")
+            .unwrap();
+        func.call(&mut *store, ptr).unwrap();
+    }
+
+    #[test]
+    fn reuses_freed_blocks() {
+        let (mut store, instance) = instantiate();
+        let a = malloc(&mut store, &instance, 12, 8);
+        let b = malloc(&mut store, &instance, 12, 8);
+        assert!(b >= a + 12, "{a} and {b} overlap");
+
+        free(&mut store, &instance, a);
+        let allocated = *store.data();
+        assert_eq!(malloc(&mut store, &instance, 12, 8), a);
+        assert_eq!(*store.data(), allocated);
+
+        // A freed block is only reused if the allocation fits in it.
+        free(&mut store, &instance, b);
+        let c = malloc(&mut store, &instance, 100, 4);
+        assert!(c > b);
+        let allocated = *store.data();
+        let d = malloc(&mut store, &instance, 4, 4);
+        assert!(d >= b - 8 && d < b + 12, "{d} is not in the block of {b}");
+        assert_eq!(*store.data(), allocated);
+    }
+
+    #[test]
+    fn frees_over_aligned_blocks() {
+        let (mut store, instance) = instantiate();
+        let blocks = (0..3)
+            .map(|_| malloc(&mut store, &instance, 4, 16))
+            .collect::<Vec<_>>();
+        for ptr in blocks.iter().rev() {
+            free(&mut store, &instance, *ptr);
+        }
+        free(&mut store, &instance, 0);
+        let allocated = *store.data();
+        for ptr in blocks.iter() {
+            assert_eq!(malloc(&mut store, &instance, 4, 16), *ptr);
+        }
+        assert_eq!(*store.data(), allocated);
+    }
+
+    #[test]
+    fn realloc_frees_the_old_block() {
+        let (mut store, instance) = instantiate();
+        let realloc = instance
+            .get_typed_func::<(i32, i32, i32, i32), i32>(&store, "cabi_realloc")
+            .unwrap();
+        let store_u32 = instance
+            .get_typed_func::<(i32, i32), ()>(&store, "rael.store_i32")
+            .unwrap();
+        let load_u32 = instance
+            .get_typed_func::<i32, i32>(&store, "rael.load_i32")
+            .unwrap();
+
+        let old = realloc.call(&mut store, (0, 0, 4, 4)).unwrap();
+        store_u32.call(&mut store, (old, 42)).unwrap();
+        let new = realloc.call(&mut store, (old, 4, 4, 64)).unwrap();
+        assert_eq!(load_u32.call(&mut store, new).unwrap(), 42);
+        assert_eq!(malloc(&mut store, &instance, 4, 4), old);
+    }
+}
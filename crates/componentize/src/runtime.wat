@@ -0,0 +1,218 @@
+;; The componentize-mbt runtime, version 2.
+;;
+;; Builtins for the lift/lower code of the generated bindings, and the prints
+;; of spectest. `componentize` links the functions called by the MoonBit module
+;; by their export name, and resolves the imports of the runtime:
+;;
+;; - `env` to the memory and functions of the same name in the MoonBit module;
+;; - `print.write` to `print.stdout`, or to the `func(s: string)` imported by
+;;   the world for the prints;
+;; - the WASI interfaces to the versions imported by the world.
+(module
+  (import "env" "memory" (memory 0))
+  (import "env" "rael.malloc" (func $rael.malloc (param i32) (result i32)))
+  (import "print" "write" (func $print.write (param $ptr i32) (param $len i32)))
+  (import "wasi:cli/stdout" "get-stdout" (func $get-stdout (result i32)))
+  (import "wasi:io/streams" "[method]output-stream.blocking-write-and-flush"
+    (func $blocking-write-and-flush
+      (param $stream i32) (param $ptr i32) (param $len i32) (param $ret i32)))
+  (import "wasi:io/streams" "[resource-drop]output-stream"
+    (func $drop-output-stream (param $stream i32)))
+
+  (global $print.buffer (mut i32) (i32.const 0))
+  ;; The blocks freed by `rael.free`, linked by their first 4 bytes.
+  (global $free.blocks (mut i32) (i32.const 0))
+
+  (func $rael.memory_copy (export "rael.memory_copy")
+    (param $dst i32) (param $src i32) (param $len i32)
+    (memory.copy (local.get $dst) (local.get $src) (local.get $len)))
+
+  (func (export "rael.bytes_data") (param $bytes i32) (result i32)
+    (i32.add (local.get $bytes) (i32.const 4)))
+
+  (func (export "moonbit.string_data") (param $str i32) (result i32)
+    (i32.add (local.get $str) (i32.const 4)))
+
+  (func (export "rael.load_i8") (param $ptr i32) (result i32)
+    (i32.load8_s (local.get $ptr)))
+  (func (export "rael.load_u8") (param $ptr i32) (result i32)
+    (i32.load8_u (local.get $ptr)))
+  (func (export "rael.load_i16") (param $ptr i32) (result i32)
+    (i32.load16_s (local.get $ptr)))
+  (func (export "rael.load_u16") (param $ptr i32) (result i32)
+    (i32.load16_u (local.get $ptr)))
+  (func (export "rael.load_i32") (param $ptr i32) (result i32)
+    (i32.load (local.get $ptr)))
+  (func (export "rael.load_i64") (param $ptr i32) (result i64)
+    (i64.load (local.get $ptr)))
+  (func (export "rael.load_f32") (param $ptr i32) (result f32)
+    (f32.load (local.get $ptr)))
+  (func (export "rael.load_f64") (param $ptr i32) (result f64)
+    (f64.load (local.get $ptr)))
+
+  (func (export "rael.store_i8") (param $ptr i32) (param $val i32)
+    (i32.store8 (local.get $ptr) (local.get $val)))
+  (func (export "rael.store_i16") (param $ptr i32) (param $val i32)
+    (i32.store16 (local.get $ptr) (local.get $val)))
+  (func (export "rael.store_i32") (param $ptr i32) (param $val i32)
+    (i32.store (local.get $ptr) (local.get $val)))
+  (func (export "rael.store_i64") (param $ptr i32) (param $val i64)
+    (i64.store (local.get $ptr) (local.get $val)))
+  (func (export "rael.store_f32") (param $ptr i32) (param $val f32)
+    (f32.store (local.get $ptr) (local.get $val)))
+  (func (export "rael.store_f64") (param $ptr i32) (param $val f64)
+    (f64.store (local.get $ptr) (local.get $val)))
+
+  ;; Allocates `size` bytes aligned to `align`, which must be a power of two.
+  ;; The block is taken from the freed ones if one fits, or else allocated
+  ;; with `rael.malloc`. The 8 bytes before the returned address hold the
+  ;; address and the size of the block, for `rael.free`.
+  (func $rael.malloc_aligned (export "rael.malloc_aligned")
+    (param $size i32) (param $align i32) (result i32)
+    (local $mask i32) (local $prev i32) (local $block i32) (local $total i32)
+    (local $ptr i32)
+    (local.set $mask (i32.sub (local.get $align) (i32.const 1)))
+    ;; Reuses the first freed block that fits.
+    (local.set $block (global.get $free.blocks))
+    (block $found
+      (loop $next
+        (if (local.get $block)
+          (then
+            (local.set $total (i32.load offset=4 (local.get $block)))
+            (local.set $ptr (call $rael.align_block (local.get $block) (local.get $mask)))
+            (if (i32.le_u
+                  (i32.add (local.get $ptr) (local.get $size))
+                  (i32.add (local.get $block) (local.get $total)))
+              (then
+                (if (local.get $prev)
+                  (then (i32.store (local.get $prev) (i32.load (local.get $block))))
+                  (else (global.set $free.blocks (i32.load (local.get $block)))))
+                (br $found)))
+            (local.set $prev (local.get $block))
+            (local.set $block (i32.load (local.get $block)))
+            (br $next))))
+      (local.set $total
+        (i32.add (local.get $size) (i32.add (local.get $mask) (i32.const 8))))
+      (local.set $block (call $rael.malloc (local.get $total)))
+      (local.set $ptr (call $rael.align_block (local.get $block) (local.get $mask))))
+    (i32.store (i32.sub (local.get $ptr) (i32.const 8)) (local.get $block))
+    (i32.store (i32.sub (local.get $ptr) (i32.const 4)) (local.get $total))
+    (local.get $ptr))
+
+  ;; The first address after the header of `block` aligned with `mask`.
+  (func $rael.align_block (param $block i32) (param $mask i32) (result i32)
+    (i32.and
+      (i32.add (local.get $block) (i32.add (local.get $mask) (i32.const 8)))
+      (i32.xor (local.get $mask) (i32.const -1))))
+
+  ;; Frees a block of `rael.malloc_aligned`, linking it into the freed blocks
+  ;; by its first 4 bytes, followed by its size.
+  (func $rael.free (export "rael.free") (param $ptr i32)
+    (local $block i32)
+    (if (i32.eqz (local.get $ptr))
+      (then (return)))
+    (local.set $block (i32.load (i32.sub (local.get $ptr) (i32.const 8))))
+    (i32.store offset=4 (local.get $block)
+      (i32.load (i32.sub (local.get $ptr) (i32.const 4))))
+    (i32.store (local.get $block) (global.get $free.blocks))
+    (global.set $free.blocks (local.get $block)))
+
+  ;; 32 bytes for the printed text, followed by the return area of the write.
+  (func $print.buffer (result i32)
+    (if (i32.eqz (global.get $print.buffer))
+      (then
+        (global.set $print.buffer
+          (call $rael.malloc_aligned (i32.const 48) (i32.const 4)))))
+    (global.get $print.buffer))
+
+  ;; Writes the UTF-8 encoding of `char`.
+  (func (export "spectest.print_char") (export "printc") (param $char i32)
+    (local $buf i32) (local $len i32)
+    (local.set $buf (call $print.buffer))
+    (if (i32.lt_u (local.get $char) (i32.const 0x80))
+      (then
+        (i32.store8 (local.get $buf) (local.get $char))
+        (local.set $len (i32.const 1)))
+      (else (if (i32.lt_u (local.get $char) (i32.const 0x800))
+        (then
+          (i32.store8 (local.get $buf)
+            (i32.or (i32.shr_u (local.get $char) (i32.const 6)) (i32.const 0xc0)))
+          (local.set $len (i32.const 2)))
+        (else (if (i32.lt_u (local.get $char) (i32.const 0x10000))
+          (then
+            (i32.store8 (local.get $buf)
+              (i32.or (i32.shr_u (local.get $char) (i32.const 12)) (i32.const 0xe0)))
+            (local.set $len (i32.const 3)))
+          (else
+            (i32.store8 (local.get $buf)
+              (i32.or (i32.shr_u (local.get $char) (i32.const 18)) (i32.const 0xf0)))
+            (local.set $len (i32.const 4))))))))
+    ;; The continuation bytes, from the last one.
+    (local.set $buf (i32.add (local.get $buf) (local.get $len)))
+    (block $done
+      (loop $next
+        (local.set $buf (i32.sub (local.get $buf) (i32.const 1)))
+        (br_if $done (i32.eq (local.get $buf) (global.get $print.buffer)))
+        (i32.store8 (local.get $buf)
+          (i32.or (i32.and (local.get $char) (i32.const 0x3f)) (i32.const 0x80)))
+        (local.set $char (i32.shr_u (local.get $char) (i32.const 6)))
+        (br $next)))
+    (call $print.write (local.get $buf) (local.get $len)))
+
+  (func (export "spectest.print_i32") (param $n i32)
+    (call $spectest.print_i64 (i64.extend_i32_s (local.get $n))))
+
+  ;; Writes `n` in decimal, followed by a newline.
+  (func $spectest.print_i64 (export "spectest.print_i64") (param $n i64)
+    (local $buf i32) (local $pos i32) (local $abs i64)
+    (local.set $buf (call $print.buffer))
+    (local.set $pos (i32.const 31))
+    (i32.store8 (i32.add (local.get $buf) (local.get $pos)) (i32.const 0x0a))
+    (local.set $abs
+      (select
+        (i64.sub (i64.const 0) (local.get $n))
+        (local.get $n)
+        (i64.lt_s (local.get $n) (i64.const 0))))
+    (loop $next
+      (local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+      (i64.store8 (i32.add (local.get $buf) (local.get $pos))
+        (i64.add (i64.rem_u (local.get $abs) (i64.const 10)) (i64.const 0x30)))
+      (local.set $abs (i64.div_u (local.get $abs) (i64.const 10)))
+      (br_if $next (i64.ne (local.get $abs) (i64.const 0))))
+    (if (i64.lt_s (local.get $n) (i64.const 0))
+      (then
+        (local.set $pos (i32.sub (local.get $pos) (i32.const 1)))
+        (i32.store8 (i32.add (local.get $buf) (local.get $pos)) (i32.const 0x2d))))
+    (call $print.write
+      (i32.add (local.get $buf) (local.get $pos))
+      (i32.sub (i32.const 32) (local.get $pos))))
+
+  (func (export "print.stdout") (param $ptr i32) (param $len i32)
+    (local $stdout i32)
+    (local.set $stdout (call $get-stdout))
+    (call $blocking-write-and-flush
+      (local.get $stdout)
+      (local.get $ptr)
+      (local.get $len)
+      (i32.add (call $print.buffer) (i32.const 32)))
+    (call $drop-output-stream (local.get $stdout)))
+
+  (func (export "print.discard") (param $ptr i32) (param $len i32))
+
+  (func (export "cabi_realloc")
+    (param $old i32) (param $old_size i32) (param $align i32) (param $new_size i32)
+    (result i32)
+    (local $new i32)
+    (local.set $new (call $rael.malloc_aligned (local.get $new_size) (local.get $align)))
+    (if (local.get $old)
+      (then
+        (call $rael.memory_copy
+          (local.get $new)
+          (local.get $old)
+          (select
+            (local.get $old_size)
+            (local.get $new_size)
+            (i32.lt_u (local.get $old_size) (local.get $new_size))))
+        (call $rael.free (local.get $old))))
+    (local.get $new))
+)
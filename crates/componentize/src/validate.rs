@@ -0,0 +1,157 @@
+//! Validation of the encoded component, reporting the errors in the code of
+//! the core module by the exported MoonBit function.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{bail, Result};
+use wasmparser::{
+    BinaryReaderError, Encoding, ExternalKind, Parser, Payload, TypeRef, Validator, WasmFeatures,
+};
+use wit_bindgen_mbt::Manifest;
+
+pub(crate) fn validate(component: &[u8], manifest: &Manifest) -> Result<()> {
+    let mut validator = Validator::new_with_features(WasmFeatures {
+        component_model: true,
+        ..Default::default()
+    });
+    let Err(err) = validator.validate_all(component) else {
+        return Ok(());
+    };
+    let offset = err.offset();
+    let location = match locate(component, offset)? {
+        Some(func) => format!(" in {}", func.describe(manifest)),
+        None => String::new(),
+    };
+    bail!(
+        "the component is invalid{location}: {} (at offset {offset:#x})",
+        err.message()
+    );
+}
+
+/// A function of a core module of the component.
+struct CoreFunc<'a> {
+    module: usize,
+    index: u32,
+    export: Option<&'a str>,
+}
+
+impl CoreFunc<'_> {
+    fn describe(&self, manifest: &Manifest) -> String {
+        let Some(export) = self.export else {
+            return format!("function {} of core module {}", self.index, self.module);
+        };
+        describe_export(manifest, export)
+            .unwrap_or_else(|| format!("the core function exported as `{export}`"))
+    }
+}
+
+/// Names the MoonBit function of a core export, if it's in the manifest.
+fn describe_export(manifest: &Manifest, export: &str) -> Option<String> {
+    for f in manifest.exports.iter() {
+        if f.export == export {
+            return Some(format!(
+                "`{export}`, exported by the MoonBit function `{}`",
+                f.function
+            ));
+        }
+        if let Some((function, _)) = f.post_return.as_ref().filter(|(_, e)| e == export) {
+            return Some(format!(
+                "`{export}`, the post-return MoonBit function `{function}`"
+            ));
+        }
+    }
+    None
+}
+
+/// Adds the MoonBit function to the errors of encoding the core `module` into
+/// a component, found by the offset of an invalid module or by the export
+/// named in the error.
+pub(crate) fn encode_error(
+    err: anyhow::Error,
+    module: &[u8],
+    manifest: &Manifest,
+) -> anyhow::Error {
+    let location = match err
+        .chain()
+        .find_map(|e| e.downcast_ref::<BinaryReaderError>())
+    {
+        Some(e) => locate(module, e.offset())
+            .ok()
+            .flatten()
+            .map(|func| func.describe(manifest)),
+        None => {
+            let messages = err.chain().map(|e| e.to_string()).collect::<Vec<_>>();
+            manifest
+                .exports
+                .iter()
+                .flat_map(|f| {
+                    let post_return = f.post_return.as_ref().map(|(_, e)| e.as_str());
+                    [Some(f.export.as_str()), post_return]
+                })
+                .flatten()
+                .find(|export| {
+                    let quoted = format!("`{export}`");
+                    messages.iter().any(|message| message.contains(&quoted))
+                })
+                .and_then(|export| describe_export(manifest, export))
+        }
+    };
+    match location {
+        Some(location) => err.context(format!("the component is invalid in {location}")),
+        None => err,
+    }
+}
+
+/// Finds the function whose body contains `offset`.
+fn locate(component: &[u8], offset: usize) -> Result<Option<CoreFunc<'_>>> {
+    let mut modules = 0;
+    let mut in_module = false;
+    let mut imported = 0;
+    let mut bodies = Vec::<Range<usize>>::new();
+    let mut exports = HashMap::new();
+    for payload in Parser::new(0).parse_all(component) {
+        match payload? {
+            Payload::Version {
+                encoding: Encoding::Module,
+                ..
+            } => {
+                in_module = true;
+                imported = 0;
+                bodies.clear();
+                exports.clear();
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Func(_) = import?.ty {
+                        imported += 1;
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export?;
+                    if export.kind == ExternalKind::Func {
+                        exports.insert(export.index, export.name);
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => bodies.push(body.range()),
+            // Core modules can't be nested, this is the end of the module.
+            Payload::End(_) if in_module => {
+                if let Some(i) = bodies.iter().position(|body| body.contains(&offset)) {
+                    let index = imported + i as u32;
+                    return Ok(Some(CoreFunc {
+                        module: modules,
+                        index,
+                        export: exports.get(&index).copied(),
+                    }));
+                }
+                in_module = false;
+                modules += 1;
+            }
+            _ => {}
+        }
+    }
+    Ok(None)
+}
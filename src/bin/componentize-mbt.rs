@@ -1,11 +1,12 @@
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use wit_bindgen_core::Files;
-use wit_parser::{Resolve, UnresolvedPackage, WorldId};
+use wit_bindgen_mbt::Manifest;
+use wit_parser::{PackageId, Resolve, UnresolvedPackage, WorldId};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -13,11 +14,29 @@ struct Cli {
     #[clap(short, long)]
     world: Option<String>,
 
+    #[clap(flatten)]
+    config: componentize_mbt::ConfigOpts,
+
+    /// Build with the release profile, the default; `--debug` builds with the
+    /// debug profile instead.
+    #[clap(long, conflicts_with = "debug")]
+    release: bool,
+
+    /// The target directory of `moon`, `target` by default.
+    #[clap(long, value_name = "DIR")]
+    target_dir: Option<PathBuf>,
+
+    /// Check that the bindings in the `bindgen-out-dir` of the project are up
+    /// to date with the WIT instead of building, failing if they aren't.
+    #[clap(long)]
+    check: bool,
+
     #[command(subcommand)]
     opts: Option<Opt>,
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
 enum Opt {
     Bindgen {
         #[clap(flatten)]
@@ -33,6 +52,19 @@ enum Opt {
         #[clap(flatten)]
         args: Common,
     },
+    Init {
+        /// The starter world of the project.
+        #[clap(long, value_enum, default_value_t = Template::Basic)]
+        world_template: Template,
+
+        /// The name of the module, `username/<directory>` by default.
+        #[clap(long)]
+        name: Option<String>,
+
+        /// The directory of the project, the current one by default.
+        #[clap(value_name = "DIR")]
+        dir: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -40,112 +72,1351 @@ struct Common {
     #[clap(long = "out-dir")]
     out_dir: Option<PathBuf>,
 
-    #[clap(value_name = "DOCUMENT", index = 1, default_value = "wit")]
-    wit: PathBuf,
+    /// The WIT file or directory, `wit` by default.
+    #[clap(value_name = "DOCUMENT", index = 1)]
+    wit: Option<PathBuf>,
 }
 
-impl Common {
-    fn parse_wit(&self, world: Option<&str>) -> Result<(Resolve, WorldId)> {
-        let mut resolve = Resolve::default();
-        let pkg = if self.wit.is_dir() {
-            resolve.push_dir(&self.wit)?.0
-        } else {
-            resolve.push(UnresolvedPackage::parse_file(&self.wit)?)?
+/// Parses the WIT file or directory at `path`.
+fn parse_wit(path: &Path) -> Result<(Resolve, PackageId)> {
+    let mut resolve = Resolve::default();
+    let pkg = if path.is_dir() {
+        resolve.push_dir(path)?.0
+    } else {
+        resolve.push(UnresolvedPackage::parse_file(path)?)?
+    };
+    Ok((resolve, pkg))
+}
+
+/// The `componentize-mbt` section of `moon.mod.json`, with the defaults of
+/// the command line options, so that a plain `componentize-mbt` always builds
+/// the same components.
+#[derive(Default)]
+struct Project {
+    /// The directory of `moon.mod.json`, which the paths are relative to.
+    root: PathBuf,
+    wit: Option<PathBuf>,
+    world: Option<String>,
+    bindgen_out_dir: Option<PathBuf>,
+    export_prefix: Option<String>,
+    /// The options of `bindgen` without a path.
+    derive: Vec<String>,
+    multi_package: bool,
+    with: Vec<(String, String)>,
+    runtime_package: Option<String>,
+    format: bool,
+    stubs: bool,
+    string_encoding: Option<componentize_mbt::StringEncoding>,
+    /// As `NAME=PATH` like `--adapt`.
+    adapters: Vec<String>,
+    /// Where the build writes the components, instead of the target directory.
+    out_dir: Option<PathBuf>,
+}
+
+impl Project {
+    /// Reads the section from the `moon.mod.json` of the directory `root`, if
+    /// there is one.
+    fn load(root: &Path) -> Result<Self> {
+        let path = root.join("moon.mod.json");
+        let mut project = Project {
+            root: root.to_path_buf(),
+            ..Default::default()
+        };
+        if !path.exists() {
+            return Ok(project);
+        }
+        let json = fs::read_to_string(&path)?;
+        let json: serde_json::Value = serde_json::from_str(&json)?;
+        let Some(section) = json.get("componentize-mbt") else {
+            return Ok(project);
+        };
+        let section = section
+            .as_object()
+            .ok_or_else(|| anyhow::anyhow!("{path:?} Format error!"))?;
+        let string = |key: &str| -> Result<Option<String>> {
+            match section.get(key) {
+                Some(value) => match value.as_str() {
+                    Some(value) => Ok(Some(value.to_string())),
+                    None => anyhow::bail!("`componentize-mbt.{key}` must be a string in {path:?}"),
+                },
+                None => Ok(None),
+            }
+        };
+        let flag = |key: &str| -> Result<bool> {
+            match section.get(key) {
+                Some(value) => value.as_bool().ok_or_else(|| {
+                    anyhow::anyhow!("`componentize-mbt.{key}` must be a boolean in {path:?}")
+                }),
+                None => Ok(false),
+            }
+        };
+        let map = |key: &str, what: &str| -> Result<Vec<(String, String)>> {
+            let Some(value) = section.get(key) else {
+                return Ok(Vec::new());
+            };
+            let error = || anyhow::anyhow!("`componentize-mbt.{key}` must map {what} in {path:?}");
+            let mut entries = Vec::new();
+            for (name, value) in value.as_object().ok_or_else(error)? {
+                let value = value.as_str().ok_or_else(error)?;
+                entries.push((name.clone(), value.to_string()));
+            }
+            Ok(entries)
         };
-        let world = resolve.select_world(pkg, world)?;
-        Ok((resolve, world))
+
+        project.wit = string("wit")?.map(|wit| root.join(wit));
+        project.world = string("world")?;
+        project.bindgen_out_dir = string("bindgen-out-dir")?.map(|dir| root.join(dir));
+        project.export_prefix = string("export-prefix")?;
+        if let Some(derive) = section.get("derive") {
+            let error = || {
+                anyhow::anyhow!("`componentize-mbt.derive` must be a list of traits in {path:?}")
+            };
+            for name in derive.as_array().ok_or_else(error)? {
+                project
+                    .derive
+                    .push(name.as_str().ok_or_else(error)?.to_string());
+            }
+        }
+        project.multi_package = flag("multi-package")?;
+        project.with = map("with", "interfaces to packages")?;
+        project.runtime_package = string("runtime-package")?;
+        project.format = flag("format")?;
+        project.stubs = flag("stubs")?;
+        if let Some(encoding) = string("string-encoding")? {
+            project.string_encoding = Some(
+                componentize_mbt::StringEncoding::from_str(&encoding, false).map_err(|_| {
+                    anyhow::anyhow!(
+                        "unknown string encoding `{encoding}` in {path:?}, expected `utf8`, `utf16` or `compact-utf16`"
+                    )
+                })?,
+            );
+        }
+        for (name, adapter) in map("adapters", "names to paths")? {
+            let adapter = root.join(adapter);
+            project
+                .adapters
+                .push(format!("{name}={}", adapter.display()));
+        }
+        project.out_dir = string("out-dir")?.map(|dir| root.join(dir));
+        for key in section.keys() {
+            if !KEYS.contains(&key.as_str()) {
+                anyhow::bail!("unknown key `componentize-mbt.{key}` in {path:?}");
+            }
+        }
+        Ok(project)
+    }
+
+    fn wit(&self, wit: Option<&Path>) -> PathBuf {
+        match wit {
+            Some(wit) => wit.to_path_buf(),
+            None => self.wit.clone().unwrap_or_else(|| self.root.join("wit")),
+        }
+    }
+
+    /// Fills the options of `bindgen` not given on the command line.
+    fn bindgen_opts(&self, opts: &mut wit_bindgen_mbt::Opts) {
+        opts.export_prefix = opts.export_prefix.take().or(self.export_prefix.clone());
+        if opts.derive.is_empty() {
+            opts.derive = self.derive.clone();
+        }
+        opts.multi_package |= self.multi_package;
+        for (interface, package) in &self.with {
+            opts.with
+                .entry(interface.clone())
+                .or_insert_with(|| package.clone());
+        }
+        opts.runtime_package = opts.runtime_package.take().or(self.runtime_package.clone());
+        opts.format |= self.format;
+        opts.stubs |= self.stubs;
+    }
+
+    /// Fills the options of `componentize` not given on the command line.
+    fn config_opts(&self, opts: &mut componentize_mbt::ConfigOpts) {
+        if opts.adapters.is_empty() {
+            opts.adapters = self.adapters.clone();
+        }
+        opts.string_encoding = opts.string_encoding.or(self.string_encoding);
+        opts.processed_by = Some((
+            env!("CARGO_BIN_NAME").to_string(),
+            env!("CARGO_PKG_VERSION").to_string(),
+        ));
     }
 }
 
+const KEYS: &[&str] = &[
+    "wit",
+    "world",
+    "bindgen-out-dir",
+    "export-prefix",
+    "derive",
+    "multi-package",
+    "with",
+    "runtime-package",
+    "format",
+    "stubs",
+    "string-encoding",
+    "adapters",
+    "out-dir",
+];
+
 fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let world = cli.world.as_deref();
+    let mut cli = Cli::parse();
+    let project = Project::load(Path::new(""))?;
+    let world = cli.world.as_deref().or(project.world.as_deref());
     match cli.opts {
-        Some(Opt::Bindgen { opts, args }) => {
-            let mut files = Files::default();
-            let (resolve, world) = args.parse_wit(world)?;
-            opts.build().generate(&resolve, world, &mut files)?;
-            for (name, contents) in files.iter() {
-                let dst = match &args.out_dir {
-                    Some(ref path) => path.join(name),
-                    None => name.into(),
-                };
-                println!("Generating {:?}", dst);
-                if let Some(parent) = dst.parent() {
-                    fs::create_dir_all(parent)
-                        .with_context(|| format!("failed to create {:?}", parent))?;
-                }
-                fs::write(&dst, contents).with_context(|| format!("failed to write {:?}", dst))?;
+        Some(Opt::Bindgen { mut opts, mut args }) => {
+            args.out_dir = args.out_dir.or(project.bindgen_out_dir.clone());
+            project.bindgen_opts(&mut opts);
+            if opts.multi_package && opts.package_path.is_none() {
+                let out_dir = args.out_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+                opts.package_path = Some(package_path(&out_dir)?);
             }
+            let wit = project.wit(args.wit.as_deref());
+            let bindings = bindings(&wit, world, &opts, args.out_dir.as_deref())?;
+            write_bindings(&bindings)?;
         }
-        Some(Opt::Componentize { opts, args }) => {
-            let (resolve, world) = args.parse_wit(world)?;
+        Some(Opt::Componentize { mut opts, args }) => {
+            project.config_opts(&mut opts.config);
+            let (resolve, pkg) = parse_wit(&project.wit(args.wit.as_deref()))?;
+            let world = resolve.select_world(pkg, world)?;
             opts.run(resolve, world, args.out_dir)?;
         }
-        None => build(world)?,
+        Some(Opt::Init {
+            world_template,
+            name,
+            dir,
+        }) => init(world_template, name, dir.as_deref())?,
+        None if cli.check => check_bindings(world, &project)?,
+        None => {
+            project.config_opts(&mut cli.config);
+            let config = cli.config.config()?;
+            update_bindings(world, &project)?;
+            build(world, &project, cli.target_dir.as_deref(), config)?
+        }
+    }
+
+    Ok(())
+}
+
+/// Finds the MoonBit package path of `dir` from the `name` (and `source`) of
+/// the enclosing `moon.mod.json`.
+fn package_path(dir: &Path) -> Result<String> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    let dir = fs::canonicalize(dir)?;
+    let root = dir
+        .ancestors()
+        .find(|p| p.join("moon.mod.json").exists())
+        .ok_or_else(|| anyhow::anyhow!("{dir:?} is not inside a MoonBit module"))?;
+    let json = fs::read_to_string(root.join("moon.mod.json"))?;
+    let json: serde_json::Value = serde_json::from_str(&json)?;
+    let name = json
+        .get("name")
+        .and_then(|n| n.as_str())
+        .ok_or_else(|| anyhow::anyhow!("{root:?}/moon.mod.json has no name"))?;
+    let source = json.get("source").and_then(|s| s.as_str()).unwrap_or("");
+    let rel = dir
+        .strip_prefix(root.join(source))
+        .with_context(|| format!("{dir:?} is not inside the source directory of {root:?}"))?;
+    let mut path = name.to_string();
+    for part in rel.iter() {
+        path.push('/');
+        path.push_str(&part.to_string_lossy());
+    }
+    Ok(path)
+}
+
+/// The generated files with their contents.
+type Bindings = Vec<(PathBuf, Vec<u8>)>;
+
+/// Generates the bindings of `world`, returning the files to write with their
+/// contents, with the imports of an existing `moon.pkg.json` merged in.
+fn bindings(
+    wit: &Path,
+    world: Option<&str>,
+    opts: &wit_bindgen_mbt::Opts,
+    out_dir: Option<&Path>,
+) -> Result<Bindings> {
+    let mut files = Files::default();
+    let (resolve, pkg) = parse_wit(wit)?;
+    let world = resolve.select_world(pkg, world)?;
+    opts.clone()
+        .build()?
+        .generate(&resolve, world, &mut files)?;
+    let mut bindings = Vec::new();
+    for (name, contents) in files.iter() {
+        let dst = match out_dir {
+            Some(path) => path.join(name),
+            None => name.into(),
+        };
+        let contents = if dst.ends_with("moon.pkg.json") && dst.exists() {
+            merge_moon_pkg_json(&dst, contents)?.into_bytes()
+        } else {
+            contents.to_vec()
+        };
+        bindings.push((dst, contents));
+    }
+    Ok(bindings)
+}
+
+fn write_bindings(bindings: &[(PathBuf, Vec<u8>)]) -> Result<()> {
+    for (dst, contents) in bindings {
+        println!("Generating {:?}", dst);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        fs::write(dst, contents).with_context(|| format!("failed to write {:?}", dst))?;
+    }
+    Ok(())
+}
+
+/// The bindings of the project, if it has a `bindgen-out-dir` to build them
+/// into, with the options of the project.
+fn project_bindings(world: Option<&str>, project: &Project) -> Result<Option<Bindings>> {
+    let Some(out_dir) = &project.bindgen_out_dir else {
+        return Ok(None);
+    };
+    let mut opts = wit_bindgen_mbt::Opts::default();
+    project.bindgen_opts(&mut opts);
+    if opts.multi_package {
+        opts.package_path = Some(package_path(out_dir)?);
+    }
+    let bindings = bindings(&project.wit(None), world, &opts, Some(out_dir))
+        .context("failed to generate the bindings")?;
+    Ok(Some(bindings))
+}
+
+/// The generated files that are missing or differ from `bindings`.
+fn stale_bindings(bindings: Bindings) -> Bindings {
+    bindings
+        .into_iter()
+        .filter(|(dst, contents)| fs::read(dst).ok().as_ref() != Some(contents))
+        .collect()
+}
+
+/// Regenerates the bindings of the project before building, writing only the
+/// files that differ from the generated ones.
+fn update_bindings(world: Option<&str>, project: &Project) -> Result<()> {
+    let Some(bindings) = project_bindings(world, project)? else {
+        return Ok(());
+    };
+    write_bindings(&stale_bindings(bindings))
+}
+
+/// Fails if the bindings of the project are out of date, for `--check`.
+fn check_bindings(world: Option<&str>, project: &Project) -> Result<()> {
+    let Some(bindings) = project_bindings(world, project)? else {
+        anyhow::bail!(
+            "no `bindgen-out-dir` to check in the `componentize-mbt` section of \"moon.mod.json\""
+        );
+    };
+    let stale = stale_bindings(bindings)
+        .into_iter()
+        .map(|(dst, _)| dst)
+        .collect::<Vec<_>>();
+    if !stale.is_empty() {
+        anyhow::bail!(
+            "the bindings are out of date: {stale:?}; regenerate them with `componentize-mbt bindgen`"
+        );
+    }
+    println!("The bindings are up to date");
+    Ok(())
+}
+
+/// Adds the generated imports to an existing `moon.pkg.json`, keeping all
+/// the other settings and imports of the package.
+fn merge_moon_pkg_json(dst: &Path, generated: &[u8]) -> Result<String> {
+    fn import_path(import: &serde_json::Value) -> Option<&str> {
+        import
+            .as_str()
+            .or_else(|| import.get("path").and_then(|p| p.as_str()))
+    }
+
+    let generated: serde_json::Value = serde_json::from_slice(generated)?;
+    let json = fs::read_to_string(dst)?;
+    let mut json: serde_json::Value = serde_json::from_str(&json)?;
+    let imports = json
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("{dst:?} Format error!"))?
+        .entry("import")
+        .or_insert_with(|| serde_json::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| anyhow::anyhow!("{dst:?} Format error!"))?;
+    for import in generated["import"].as_array().into_iter().flatten() {
+        if !imports
+            .iter()
+            .any(|i| import_path(i) == import_path(import))
+        {
+            imports.push(import.clone());
+        }
     }
+    Ok(serde_json::to_string_pretty(&json)? + "\n")
+}
 
+/// Fills the metadata not given on the command line from `moon.mod.json`.
+fn module_metadata(metadata: &mut componentize_mbt::Metadata) -> Result<()> {
+    let json = fs::read_to_string("moon.mod.json")?;
+    let json: serde_json::Value = serde_json::from_str(&json)?;
+    let field = |name: &str| json.get(name).and_then(|v| v.as_str()).map(String::from);
+    metadata.name = metadata.name.take().or_else(|| field("name"));
+    metadata.description = metadata.description.take().or_else(|| field("description"));
+    metadata.source = metadata.source.take().or_else(|| field("repository"));
     Ok(())
 }
 
-fn build(world: Option<&str>) -> Result<()> {
+fn build(
+    world: Option<&str>,
+    project: &Project,
+    target_dir: Option<&Path>,
+    mut config: componentize_mbt::Config,
+) -> Result<()> {
     if !PathBuf::from("moon.mod.json").exists() {
         anyhow::bail!("You must execute componentize-mbt build in the project root directory!");
     }
-    let mut iter = fs::read_dir(".")?
-        .map(|r| -> Result<_> {
-            let r = r?;
-            let p = r.path();
-            let j = p.join("moon.pkg.json");
-            if !j.exists() {
-                return Ok(None);
+    module_metadata(&mut config.metadata)?;
+    let json = fs::read_to_string("moon.mod.json")?;
+    let json: serde_json::Value = serde_json::from_str(&json)?;
+    let source = Path::new(json.get("source").and_then(|s| s.as_str()).unwrap_or("."));
+    // The package at the root of the source directory is named after the module.
+    let module_name = json.get("name").and_then(|n| n.as_str()).unwrap_or("main");
+    let root_name = module_name.rsplit('/').next().unwrap();
+    let mut skipped = vec![Path::new("target")];
+    skipped.extend(target_dir);
+    let packages = main_packages(source, &skipped)?;
+    if packages.is_empty() {
+        anyhow::bail!("At least one MoonBit package must have is_main set to true.");
+    }
+
+    let mut cmd = moon_build(config.debug, target_dir);
+    println!("Execute: {cmd:?}");
+    let status = cmd.status()?;
+    if !status.success() {
+        anyhow::bail!("moon build failed");
+    }
+
+    let build_dir = build_dir(config.debug, target_dir);
+    componentize_packages(
+        world, project, &packages, source, root_name, &build_dir, &config,
+    )
+}
+
+/// Componentizes the modules of the main `packages` built in `build_dir`,
+/// each with its own `wit-world` or else `world`.
+fn componentize_packages(
+    world: Option<&str>,
+    project: &Project,
+    packages: &[MainPackage],
+    source: &Path,
+    root_name: &str,
+    build_dir: &Path,
+    config: &componentize_mbt::Config,
+) -> Result<()> {
+    let (resolve, pkg) = parse_wit(&project.wit(None))?;
+    for package in packages.iter() {
+        let world = package.world.as_deref().or(world);
+        let world = resolve
+            .select_world(pkg, world)
+            .with_context(|| format!("failed to select the world of {:?}", package.path))?;
+        let name = package
+            .path
+            .file_name()
+            .map_or(root_name.into(), |name| name.to_string_lossy());
+        let (wasm, target) =
+            build_package(package, &name, build_dir, source, &resolve, world, config)
+                .with_context(|| format!("failed to build the component of {:?}", package.path))?;
+        let target = match &project.out_dir {
+            Some(out_dir) => {
+                fs::create_dir_all(out_dir)?;
+                out_dir.join(format!("{name}.wasm"))
             }
+            None => target,
+        };
+        fs::write(&target, wasm)?;
+        println!("Successfully generated: {target:?}");
+    }
+    Ok(())
+}
+
+/// The `moon build` of the modules, with the debug or the release profile.
+fn moon_build(debug: bool, target_dir: Option<&Path>) -> Command {
+    let mut cmd = Command::new("moon");
+    cmd.arg("build").arg("--target=wasm").arg("--output-wat");
+    if debug {
+        cmd.arg("--debug");
+    }
+    if let Some(dir) = target_dir {
+        cmd.arg("--target-dir").arg(dir);
+    }
+    cmd
+}
+
+/// Where `moon build` puts the modules of the profile.
+fn build_dir(debug: bool, target_dir: Option<&Path>) -> PathBuf {
+    let profile = if debug { "debug" } else { "release" };
+    target_dir
+        .unwrap_or(Path::new("target"))
+        .join("wasm")
+        .join(profile)
+        .join("build")
+}
+
+/// A package with `is_main` set to true, built to a component.
+struct MainPackage {
+    /// The path of the package in the source directory of the module.
+    path: PathBuf,
+    /// The `wit-world` of its `moon.pkg.json`.
+    world: Option<String>,
+}
+
+/// Finds the main packages in the `source` directory of the module, skipping
+/// the target directories and the hidden directories like `.mooncakes`.
+fn main_packages(source: &Path, skipped: &[&Path]) -> Result<Vec<MainPackage>> {
+    let mut packages = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let j = source.join(&dir).join("moon.pkg.json");
+        if j.exists() {
             let json = fs::read_to_string(&j)?;
             let json: serde_json::Value = serde_json::from_str(&json)?;
             let json = json
                 .as_object()
                 .ok_or_else(|| anyhow::anyhow!("{j:?} Format error!"))?;
-            if let Some(is_main) = json.get("is_main") {
-                let is_main = is_main
+            let is_main = match json.get("is_main") {
+                Some(is_main) => is_main
                     .as_bool()
-                    .ok_or_else(|| anyhow::anyhow!("{j:?} Format error!"))?;
-                Ok(is_main.then(|| r.file_name()))
-            } else {
-                Ok(None)
+                    .ok_or_else(|| anyhow::anyhow!("{j:?} Format error!"))?,
+                None => false,
+            };
+            let world = match json.get("wit-world") {
+                Some(world) => Some(
+                    world
+                        .as_str()
+                        .ok_or_else(|| anyhow::anyhow!("{j:?} Format error!"))?
+                        .to_string(),
+                ),
+                None => None,
+            };
+            if is_main {
+                packages.push(MainPackage {
+                    path: dir.clone(),
+                    world,
+                });
             }
-        })
-        .flat_map(|r| r.transpose());
-    let pkg_name = iter.next().transpose()?.ok_or_else(|| {
-        anyhow::anyhow!("At least one MoonBit package must have is_main set to true.")
-    })?;
-    if iter.next().transpose()?.is_some() {
-        anyhow::bail!("Currently, only one MoonBit package can have is_main set to true.");
+        }
+        let mut entries = fs::read_dir(source.join(&dir))?
+            .map(|entry| Ok(entry?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        for path in entries.into_iter().rev() {
+            let name = path.file_name().unwrap_or_default();
+            let hidden = name.to_string_lossy().starts_with('.');
+            let is_skipped = skipped.iter().any(|dir| same_dir(&path, dir));
+            if path.is_dir() && !hidden && !is_skipped {
+                dirs.push(dir.join(name));
+            }
+        }
     }
+    Ok(packages)
+}
 
-    let mut cmd = Command::new("moon");
-    let cmd = cmd.arg("build").arg("--target=wasm").arg("--output-wat");
-    println!("Execute: {cmd:?}");
-    let status = cmd.status()?;
-    if !status.success() {
-        anyhow::bail!("moon build failed");
+fn same_dir(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
     }
+}
+
+/// Componentizes the module built for `package`, returning the component and
+/// the path it is written to by default.
+fn build_package(
+    package: &MainPackage,
+    name: &str,
+    build_dir: &Path,
+    source: &Path,
+    resolve: &Resolve,
+    world: WorldId,
+    config: &componentize_mbt::Config,
+) -> Result<(Vec<u8>, PathBuf)> {
+    let module = find_module(build_dir, &package.path, name)?
+        .ok_or_else(|| anyhow::anyhow!("{:?} was not built in {build_dir:?}", package.path))?;
+    let manifest_file = source
+        .join(&package.path)
+        .join(Manifest::file_name(&resolve.worlds[world].name));
+    let manifest = componentize_mbt::read_manifest(&manifest_file)?;
+
+    let resolve = resolve.clone();
+    let (wasm, target) = if module.extension() == Some("wat".as_ref()) {
+        let wat = fs::read_to_string(&module)?;
+        let wasm = componentize_mbt::componentize(&wat, resolve, world, &manifest, config)?;
+        (wasm, module.with_extension("wasm"))
+    } else {
+        let wasm = fs::read(&module)?;
+        let mut config = config.clone();
+        if config.debug {
+            config.source_map = componentize_mbt::read_source_map(&wasm, &module)?;
+        }
+        let wasm = componentize_mbt::componentize_wasm(&wasm, resolve, world, &manifest, &config)?;
+        (wasm, module.with_extension("component.wasm"))
+    };
+    Ok((wasm, target))
+}
 
-    let wat_file = PathBuf::from("target/wasm/release/build/")
-        .join(&pkg_name)
-        .join(&pkg_name)
-        .with_extension("wat");
-    if !wat_file.exists() {
-        anyhow::bail!("{wat_file:?} does not exist");
+/// Finds the module built for the package `name` at `path` in `build_dir`,
+/// usually as `<path>/<name>.wat`, preferring the text format to a binary
+/// module.
+fn find_module(build_dir: &Path, path: &Path, name: &str) -> Result<Option<PathBuf>> {
+    let expected = build_dir.join(path).join(name);
+    for ext in ["wat", "wasm"] {
+        if expected.with_extension(ext).exists() {
+            return Ok(Some(expected.with_extension(ext)));
+        }
+    }
+    // Other versions of `moon` may lay out the build directory differently.
+    let mut found = None;
+    let mut dirs = vec![build_dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.file_stem() == Some(name.as_ref())
+                && path.parent().unwrap().ends_with(name)
+            {
+                match path.extension().and_then(|ext| ext.to_str()) {
+                    Some("wat") => return Ok(Some(path)),
+                    Some("wasm") => found = Some(path),
+                    _ => {}
+                }
+            }
+        }
     }
+    Ok(found)
+}
 
-    let mut resolve = Resolve::default();
-    let pkg = resolve.push_dir(&PathBuf::from("wit"))?.0;
-    let world = resolve.select_world(pkg, world)?;
+#[derive(Clone, Copy, ValueEnum)]
+enum Template {
+    /// A world importing a host `println` and exporting `hello`.
+    Basic,
+    /// A `wasi:cli` command, exporting `wasi:cli/run`.
+    WasiCli,
+    /// A `wasi:http` proxy, exporting `wasi:http/incoming-handler`.
+    WasiHttp,
+    /// A world like `basic` for an Extism host, using the MoonBit PDK.
+    Extism,
+}
+
+impl Template {
+    fn world(self) -> &'static str {
+        match self {
+            Template::Basic => "basic",
+            Template::WasiCli => "cli",
+            Template::WasiHttp => "handler",
+            Template::Extism => "plugin",
+        }
+    }
+
+    /// The WIT of the world, in the package `namespace:name`.
+    fn wit(self, namespace: &str, name: &str) -> String {
+        let world = self.world();
+        match self {
+            Template::Basic | Template::Extism => {
+                let export = match self {
+                    Template::Extism => "greet",
+                    _ => "hello",
+                };
+                format!(
+                    "package {namespace}:{name};
+
+interface stdio {{
+  /// Print a line of text to standard output
+  println: func(line: string);
+}}
+
+world {world} {{
+  import stdio;
+
+  /// Say hello to a specified person
+  export {export}: func(name: string);
+}}
+"
+                )
+            }
+            Template::WasiCli => format!(
+                "package {namespace}:{name};
+
+world {world} {{
+  import wasi:cli/environment@0.2.0;
+
+  export wasi:cli/run@0.2.0;
+}}
+"
+            ),
+            Template::WasiHttp => format!(
+                "package {namespace}:{name};
+
+world {world} {{
+  export wasi:http/incoming-handler@0.2.0;
+}}
+"
+            ),
+        }
+    }
 
-    let wasm = componentize_mbt::componentize(&fs::read_to_string(&wat_file)?, resolve, world)?;
+    /// The `wit/deps.toml` of the WIT dependencies, fetched with `wit-deps`.
+    fn deps(self) -> Option<&'static str> {
+        match self {
+            Template::WasiCli => {
+                Some("cli = \"https://github.com/WebAssembly/wasi-cli/archive/v0.2.0.tar.gz\"\n")
+            }
+            Template::WasiHttp => {
+                Some("http = \"https://github.com/WebAssembly/wasi-http/archive/v0.2.0.tar.gz\"\n")
+            }
+            Template::Basic | Template::Extism => None,
+        }
+    }
+
+    /// The `main.mbt` implementing the exports, with the MoonBit path of the
+    /// WIT package.
+    fn main_mbt(self, package: &str) -> String {
+        match self {
+            Template::Basic => format!(
+                "struct Impl {{}}
+
+fn Guest::hello(self : Impl, name : String) -> Unit {{
+  {package}.stdio.println(\"Hello, \\(name)!\")
+}}
+
+fn init {{
+  init_guest(Impl::{{  }})
+}}
+
+fn main {{
+
+}}
+"
+            ),
+            Template::Extism => "struct Impl {}
+
+fn Guest::greet(self : Impl, name : String) -> Unit {
+  @host.output_string(\"Hello, \\(name)!\")
+}
+
+fn init {
+  init_guest(Impl::{  })
+}
+
+fn main {
+
+}
+"
+            .to_string(),
+            Template::WasiCli => "struct Impl {}
 
-    let target = wat_file.with_extension("wasm");
-    fs::write(&target, wasm)?;
-    println!("Successfully generated: {target:?}");
+fn ExportsWasiCliRun::run(self : Impl) -> Result[Unit, Unit] {
+  let arguments = wasi.cli.environment.get_arguments()
+  if arguments.length() > 0 {
     Ok(())
+  } else {
+    Err(())
+  }
+}
+
+fn init {
+  init_guest(Impl::{  })
+}
+
+fn main {
+
+}
+"
+            .to_string(),
+            Template::WasiHttp => "struct Impl {}
+
+fn ExportsWasiHttpIncomingHandler::handle(
+  self : Impl,
+  _request : IncomingRequest,
+  response_out : ResponseOutparam
+) -> Unit {
+  let response = OutgoingResponse::new(Fields::new())
+  let body = response.body().unwrap()
+  ResponseOutparam::set(response_out, Ok(response))
+  OutgoingBody::finish(body, None).unwrap()
+}
+
+fn init {
+  init_guest(Impl::{  })
+}
+
+fn main {
+
+}
+"
+            .to_string(),
+        }
+    }
+}
+
+/// The kebab-case WIT identifier of a name of the module.
+fn wit_identifier(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_ascii_lowercase())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Creates a project in `dir`, the current directory by default, with a main
+/// package building the world of `template`.
+fn init(template: Template, name: Option<String>, dir: Option<&Path>) -> Result<()> {
+    let root = dir.unwrap_or(Path::new(""));
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {:?}", dir))?;
+    }
+    if root.join("moon.mod.json").exists() {
+        anyhow::bail!("{:?} already exists", root.join("moon.mod.json"));
+    }
+    let name = match name {
+        Some(name) => name,
+        None => {
+            let dir = fs::canonicalize(std::env::current_dir()?.join(root))?;
+            let dir = dir.file_name().unwrap_or_default().to_string_lossy();
+            format!("username/{dir}")
+        }
+    };
+    let (user, module) = name.rsplit_once('/').unwrap_or(("username", &name));
+    let (namespace, package) = (wit_identifier(user), wit_identifier(module));
+    let world = template.world();
+
+    let mut moon_mod = serde_json::json!({
+        "name": name,
+        "version": "0.1.0",
+    });
+    let mut moon_pkg = serde_json::json!({ "is_main": true });
+    if let Template::Extism = template {
+        moon_mod["deps"] = serde_json::json!({ "gmlewis/moonbit-pdk": "0.12.0" });
+        moon_pkg["import"] = serde_json::json!(["gmlewis/moonbit-pdk/pdk/host"]);
+    }
+    moon_mod["componentize-mbt"] = serde_json::json!({
+        "world": world,
+        "bindgen-out-dir": "main",
+    });
+
+    let mut files = vec![
+        (
+            root.join("moon.mod.json"),
+            serde_json::to_string_pretty(&moon_mod)? + "\n",
+        ),
+        (
+            root.join("main/moon.pkg.json"),
+            serde_json::to_string_pretty(&moon_pkg)? + "\n",
+        ),
+        (
+            root.join("wit").join(format!("{world}.wit")),
+            template.wit(&namespace, &package),
+        ),
+        (
+            root.join("main/main.mbt"),
+            template.main_mbt(&format!(
+                "{}.{}",
+                namespace.replace('-', "_"),
+                package.replace('-', "_")
+            )),
+        ),
+    ];
+    if let Some(deps) = template.deps() {
+        files.push((root.join("wit/deps.toml"), deps.to_string()));
+    }
+    for (dst, _) in &files {
+        if dst.exists() {
+            anyhow::bail!("{dst:?} already exists");
+        }
+    }
+    for (dst, contents) in files {
+        println!("Creating {:?}", dst);
+        if let Some(parent) = dst.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("failed to create {:?}", parent))?;
+        }
+        fs::write(&dst, contents).with_context(|| format!("failed to write {:?}", dst))?;
+    }
+
+    if template.deps().is_some() {
+        let mut cmd = Command::new("wit-deps");
+        cmd.arg("lock");
+        if let Some(dir) = dir {
+            cmd.current_dir(dir);
+        }
+        println!("Execute: {:?}", cmd);
+        let fetched = match cmd.status() {
+            Ok(status) => status.success(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e).context("failed to run `wit-deps`"),
+        };
+        if !fetched {
+            println!(
+                "The WIT dependencies weren't fetched, run `wit-deps lock` \
+                 (`cargo install wit-deps-cli`); the bindings are generated when building, \
+                 add the exported functions of `main/{}` to `link.wasm.exports` in \
+                 `main/moon.pkg.json`",
+                Manifest::file_name(world)
+            );
+            return Ok(());
+        }
+    }
+    let project = Project::load(root)?;
+    if let Some(bindings) = project_bindings(Some(world), &project)? {
+        write_bindings(&bindings)?;
+    }
+    let main = root.join("main");
+    let manifest = componentize_mbt::read_manifest(&main.join(Manifest::file_name(world)))?;
+    let moon_pkg = main.join("moon.pkg.json");
+    let json = link_exports(&moon_pkg, &manifest)?;
+    fs::write(&moon_pkg, json).with_context(|| format!("failed to write {:?}", moon_pkg))?;
+    Ok(())
+}
+
+/// Sets the `link` section of the `moon.pkg.json` at `dst` for `moon build` to
+/// export the memory and the functions of the bindings in `manifest`.
+fn link_exports(dst: &Path, manifest: &Manifest) -> Result<String> {
+    let exports = manifest
+        .exports
+        .iter()
+        .flat_map(|f| {
+            let post_return = f.post_return.as_ref().map(|(function, _)| function);
+            std::iter::once(&f.function).chain(post_return)
+        })
+        .collect::<Vec<_>>();
+    let json = fs::read_to_string(dst)?;
+    let mut json: serde_json::Value = serde_json::from_str(&json)?;
+    json.as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("{dst:?} Format error!"))?
+        .insert(
+            "link".into(),
+            serde_json::json!({
+                "wasm": {
+                    "exports": exports,
+                    "export-memory-name": "memory",
+                },
+            }),
+        );
+    Ok(serde_json::to_string_pretty(&json)? + "\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use wasmparser::{Payload, Validator, WasmFeatures};
+    use wit_bindgen_mbt::ExportedFunction;
+
+    use super::*;
+
+    /// An empty directory for the test `name`.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("componentize-mbt-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Validates the component at `path` and returns its exports.
+    fn exports(path: &Path) -> Vec<String> {
+        let component = fs::read(path).unwrap();
+        let mut validator = Validator::new_with_features(WasmFeatures {
+            component_model: true,
+            ..Default::default()
+        });
+        validator.validate_all(&component).unwrap();
+        let mut exports = Vec::new();
+        for payload in wasmparser::Parser::new(0).parse_all(&component) {
+            if let Payload::ComponentExportSection(reader) = payload.unwrap() {
+                for export in reader {
+                    exports.push(export.unwrap().name.0.to_string());
+                }
+            }
+        }
+        exports
+    }
+
+    fn args(cmd: &Command) -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let cli = Cli::try_parse_from(["componentize-mbt"]).unwrap();
+        assert!(!cli.config.debug && cli.target_dir.is_none());
+        let cli = Cli::try_parse_from(["componentize-mbt", "--release"]).unwrap();
+        assert!(!cli.config.debug);
+        let cli =
+            Cli::try_parse_from(["componentize-mbt", "--debug", "--target-dir", "out"]).unwrap();
+        assert!(cli.config.debug);
+        assert_eq!(cli.target_dir.as_deref(), Some(Path::new("out")));
+        assert!(Cli::try_parse_from(["componentize-mbt", "--debug", "--release"]).is_err());
+    }
+
+    #[test]
+    fn builds_with_profiles() {
+        let cmd = moon_build(false, None);
+        assert_eq!(cmd.get_program(), "moon");
+        assert_eq!(args(&cmd), ["build", "--target=wasm", "--output-wat"]);
+        assert_eq!(
+            build_dir(false, None),
+            Path::new("target/wasm/release/build")
+        );
+
+        let cmd = moon_build(true, Some(Path::new("out")));
+        assert_eq!(
+            args(&cmd),
+            [
+                "build",
+                "--target=wasm",
+                "--output-wat",
+                "--debug",
+                "--target-dir",
+                "out"
+            ]
+        );
+        assert_eq!(
+            build_dir(true, Some(Path::new("out"))),
+            Path::new("out/wasm/debug/build")
+        );
+    }
+
+    #[test]
+    fn componentizes_every_main_package() {
+        let dir = temp_dir("packages");
+        write(
+            &dir.join("wit/app.wit"),
+            "package my:app;
+
+world greeter {
+  export hello: func() -> u32;
+}
+
+world farewell {
+  export bye: func() -> u32;
+}
+",
+        );
+        let source = dir.join("src");
+        let target = dir.join("target");
+        let main = r#"{ "is_main": true }"#;
+        write(
+            &source.join("greeter/moon.pkg.json"),
+            r#"{ "is_main": true, "wit-world": "greeter" }"#,
+        );
+        write(&source.join("farewell/moon.pkg.json"), main);
+        write(&source.join("lib/moon.pkg.json"), "{}");
+        write(&source.join(".mooncakes/dep/moon.pkg.json"), main);
+        write(&target.join("wasm/moon.pkg.json"), main);
+        write(&source.join("out/moon.pkg.json"), main);
+
+        let skipped = [target.as_path(), &source.join("out")];
+        let packages = main_packages(&source, &skipped).unwrap();
+        let found = packages
+            .iter()
+            .map(|package| (package.path.to_str().unwrap(), package.world.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(found, [("farewell", None), ("greeter", Some("greeter"))]);
+
+        // The modules as built by `moon build`, with the manifests of their
+        // worlds from the bindings.
+        let build_dir = build_dir(false, Some(&target));
+        let worlds = [
+            ("greeter", "hello", "wasmExportHello"),
+            ("farewell", "bye", "wasmExportBye"),
+        ];
+        for (name, export, function) in worlds {
+            let manifest = Manifest {
+                world: name.into(),
+                runtime: componentize_mbt::RUNTIME_VERSION,
+                exports: vec![ExportedFunction {
+                    function: function.into(),
+                    export: export.into(),
+                    has_rv: true,
+                    post_return: None,
+                }],
+            };
+            write(
+                &source.join(name).join(Manifest::file_name(name)),
+                &manifest.to_json(),
+            );
+            write(
+                &build_dir.join(name).join(format!("{name}.wat")),
+                &format!(
+                    r#"(module
+                      (memory $moonbit.memory (export "memory") 1)
+                      (func $rael.malloc (param i32) (result i32)
+                        i32.const 1024)
+                      (func $user/app/{name}::{function} (export "user/app/{name}::{function}")
+                        (result i32)
+                        i32.const 1)
+                      (func $*init*/0 (export "_start")))"#
+                ),
+            );
+        }
+
+        let project = Project {
+            wit: Some(dir.join("wit")),
+            out_dir: Some(dir.join("components")),
+            ..Default::default()
+        };
+        let config = componentize_mbt::Config::default();
+        componentize_packages(
+            Some("farewell"),
+            &project,
+            &packages,
+            &source,
+            "app",
+            &build_dir,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(exports(&dir.join("components/greeter.wasm")), ["hello"]);
+        assert_eq!(exports(&dir.join("components/farewell.wasm")), ["bye"]);
+
+        // Without a default world, the package without one can't be built.
+        let err = componentize_packages(
+            None, &project, &packages, &source, "app", &build_dir, &config,
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("\"farewell\""), "{err:#}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn loads_the_project() {
+        let dir = temp_dir("project");
+        write(
+            &dir.join("moon.mod.json"),
+            r#"{
+  "name": "user/app",
+  "componentize-mbt": {
+    "wit": "wit/app.wit",
+    "world": "app",
+    "bindgen-out-dir": "src/bindings",
+    "export-prefix": "__export_",
+    "derive": ["Show", "Eq"],
+    "multi-package": true,
+    "with": { "wasi:io/streams": "@wasi_io/streams" },
+    "runtime-package": "user/runtime",
+    "format": true,
+    "stubs": true,
+    "string-encoding": "utf16",
+    "adapters": { "wasi_snapshot_preview1": "adapter.wasm" },
+    "out-dir": "dist"
+  }
+}"#,
+        );
+        let project = Project::load(&dir).unwrap();
+        assert_eq!(project.wit(None), dir.join("wit/app.wit"));
+        assert_eq!(project.wit(Some(Path::new("other"))), Path::new("other"));
+        assert_eq!(project.world.as_deref(), Some("app"));
+        assert_eq!(project.bindgen_out_dir, Some(dir.join("src/bindings")));
+        assert_eq!(project.out_dir, Some(dir.join("dist")));
+        assert_eq!(
+            project.adapters,
+            [format!(
+                "wasi_snapshot_preview1={}",
+                dir.join("adapter.wasm").display()
+            )]
+        );
+
+        let mut opts = wit_bindgen_mbt::Opts::default();
+        project.bindgen_opts(&mut opts);
+        assert_eq!(opts.export_prefix.as_deref(), Some("__export_"));
+        assert_eq!(opts.derive, ["Show", "Eq"]);
+        assert!(opts.multi_package && opts.format && opts.stubs);
+        assert_eq!(opts.with["wasi:io/streams"], "@wasi_io/streams");
+        assert_eq!(opts.runtime_package.as_deref(), Some("user/runtime"));
+
+        // The command line options take precedence.
+        let mut opts = wit_bindgen_mbt::Opts::default();
+        opts.derive = vec!["Hash".into()];
+        opts.runtime_package = Some("user/alloc".into());
+        opts.with
+            .insert("wasi:io/streams".into(), "@io/streams".into());
+        project.bindgen_opts(&mut opts);
+        assert_eq!(opts.derive, ["Hash"]);
+        assert_eq!(opts.with["wasi:io/streams"], "@io/streams");
+        assert_eq!(opts.runtime_package.as_deref(), Some("user/alloc"));
+
+        // Without a section or even `moon.mod.json`, everything is a default.
+        fs::remove_file(dir.join("moon.mod.json")).unwrap();
+        let project = Project::load(&dir).unwrap();
+        assert_eq!(project.wit(None), dir.join("wit"));
+        assert!(project.derive.is_empty() && !project.stubs);
+
+        for (section, error) in [
+            (r#"{ "derive": "Show" }"#, "must be a list of traits"),
+            (r#"{ "format": "yes" }"#, "must be a boolean"),
+            (r#"{ "with": ["a=b"] }"#, "must map interfaces to packages"),
+            (r#"{ "skip": [] }"#, "unknown key `componentize-mbt.skip`"),
+        ] {
+            write(
+                &dir.join("moon.mod.json"),
+                &format!(r#"{{ "componentize-mbt": {section} }}"#),
+            );
+            let err = Project::load(&dir).err().unwrap();
+            assert!(err.to_string().contains(error), "{err}");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn updates_stale_bindings() {
+        let dir = temp_dir("bindings");
+        write(
+            &dir.join("moon.mod.json"),
+            r#"{
+  "name": "user/app",
+  "componentize-mbt": {
+    "bindgen-out-dir": "main",
+    "derive": ["Eq"]
+  }
+}"#,
+        );
+        write(
+            &dir.join("wit/app.wit"),
+            "package my:app;
+
+world app {
+  record point {
+    x: s32,
+    y: s32,
+  }
+
+  export origin: func() -> point;
+}
+",
+        );
+        write(
+            &dir.join("main/moon.pkg.json"),
+            r#"{ "is_main": true, "import": ["user/app/util"] }"#,
+        );
+        let project = Project::load(&dir).unwrap();
+        let err = check_bindings(None, &project).unwrap_err();
+        assert!(err.to_string().contains("app.mbt"), "{err}");
+
+        // The bindings are generated with the options of the project.
+        update_bindings(None, &project).unwrap();
+        let mbt = dir.join("main/app.mbt");
+        let generated = fs::read_to_string(&mbt).unwrap();
+        assert!(generated.contains("derive(Eq)"), "{generated}");
+        let pkg = fs::read_to_string(dir.join("main/moon.pkg.json")).unwrap();
+        assert!(pkg.contains("user/app/util"), "{pkg}");
+        check_bindings(None, &project).unwrap();
+
+        // Fresh bindings are left alone.
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1);
+        let set_modified = |path: &Path| {
+            fs::File::options()
+                .write(true)
+                .open(path)
+                .unwrap()
+                .set_modified(old)
+                .unwrap()
+        };
+        let modified = |path: &Path| fs::metadata(path).unwrap().modified().unwrap();
+        set_modified(&mbt);
+        update_bindings(None, &project).unwrap();
+        assert_eq!(modified(&mbt), old);
+
+        // Stale bindings are checked and regenerated.
+        fs::write(&mbt, "fn main {}").unwrap();
+        let err = check_bindings(None, &project).unwrap_err();
+        assert!(err.to_string().contains("app.mbt"), "{err}");
+        update_bindings(None, &project).unwrap();
+        assert_eq!(fs::read_to_string(&mbt).unwrap(), generated);
+        check_bindings(None, &project).unwrap();
+
+        // The bindings are out of date once the WIT changes.
+        let wit = fs::read_to_string(dir.join("wit/app.wit")).unwrap();
+        write(&dir.join("wit/app.wit"), &wit.replace("origin", "center"));
+        assert!(check_bindings(None, &project).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checks_without_a_profile() {
+        let cli = Cli::try_parse_from(["componentize-mbt", "--check", "--release"]).unwrap();
+        assert!(cli.check);
+    }
+
+    #[test]
+    fn initializes_a_project_to_componentize() {
+        let dir = temp_dir("init");
+        let root = dir.join("hello");
+        init(Template::Basic, None, Some(&root)).unwrap();
+        let moon_mod = fs::read_to_string(root.join("moon.mod.json")).unwrap();
+        assert!(
+            moon_mod.contains(r#""name": "username/hello""#),
+            "{moon_mod}"
+        );
+        assert!(root.join("main/main.mbt").exists());
+        assert!(init(Template::Basic, None, Some(&root)).is_err());
+
+        // The main package exports the functions of the bindings.
+        let moon_pkg = fs::read_to_string(root.join("main/moon.pkg.json")).unwrap();
+        let moon_pkg: serde_json::Value = serde_json::from_str(&moon_pkg).unwrap();
+        assert_eq!(moon_pkg["is_main"], true);
+        assert_eq!(
+            moon_pkg["link"],
+            serde_json::json!({
+                "wasm": {
+                    "exports": ["hello"],
+                    "export-memory-name": "memory",
+                },
+            })
+        );
+
+        // The module as built by `moon build` with this `link` section.
+        let target = root.join("target");
+        let build_dir = build_dir(false, Some(&target));
+        let exported = moon_pkg["link"]["wasm"]["exports"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|export| {
+                let export = export.as_str().unwrap();
+                format!(r#"(func (export "{export}") (param i32 i32))"#)
+            })
+            .collect::<String>();
+        write(
+            &build_dir.join("main/main.wat"),
+            &format!(
+                r#"(module
+                  (memory $moonbit.memory (export "memory") 1)
+                  (func $rael.malloc (param i32) (result i32)
+                    i32.const 1024)
+                  {exported}
+                  (func $*init*/0 (export "_start")))"#
+            ),
+        );
+
+        let mut project = Project::load(&root).unwrap();
+        project.out_dir = Some(dir.join("components"));
+        let packages = main_packages(&root, &[target.as_path()]).unwrap();
+        let config = componentize_mbt::Config::default();
+        componentize_packages(
+            project.world.as_deref(),
+            &project,
+            &packages,
+            &root,
+            "hello",
+            &build_dir,
+            &config,
+        )
+        .unwrap();
+        assert_eq!(exports(&dir.join("components/main.wasm")), ["hello"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
 }